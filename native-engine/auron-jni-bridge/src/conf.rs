@@ -49,7 +49,35 @@ define_conf!(IntConf, SPARK_IO_COMPRESSION_ZSTD_LEVEL);
 define_conf!(IntConf, TOKIO_WORKER_THREADS_PER_CPU);
 define_conf!(IntConf, SPARK_TASK_CPUS);
 define_conf!(IntConf, SHUFFLE_COMPRESSION_TARGET_BUF_SIZE);
+define_conf!(IntConf, SHUFFLE_MAX_IN_MEM_SPILL_SIZE);
+define_conf!(BooleanConf, SHUFFLE_VECTORIZED_HASHING_ENABLE);
+define_conf!(IntConf, SHUFFLE_PARTITION_LOG_SAMPLE_INTERVAL);
+define_conf!(BooleanConf, SHUFFLE_OUTPUT_EXCLUSIVE_CREATE);
+define_conf!(DoubleConf, SHUFFLE_NULL_KEY_FALLBACK_RATIO);
+define_conf!(BooleanConf, SHUFFLE_RANGE_BOUNDS_INDEX_ENABLE);
+define_conf!(StringConf, SHUFFLE_BATCH_FORMAT);
+define_conf!(LongConf, SHUFFLE_ACCURATE_BLOCK_THRESHOLD);
+define_conf!(IntConf, SHUFFLE_MIN_PARTITIONS_TO_HIGHLY_COMPRESS);
+define_conf!(IntConf, SHUFFLE_TINY_BATCH_COALESCE_ROWS);
+define_conf!(LongConf, SHUFFLE_ADAPTIVE_COALESCE_MAX_SIZE);
+define_conf!(IntConf, SHUFFLE_ADAPTIVE_COALESCE_TARGET_PARTITIONS);
+define_conf!(IntConf, SHUFFLE_MIN_PARTITION_FRAMES);
+define_conf!(IntConf, SHUFFLE_MIN_FRAME_ROWS);
+define_conf!(StringConf, SHUFFLE_HASH_COMBINE_ORDER);
+define_conf!(StringConf, SHUFFLE_HASH_COMBINE_METHOD);
+define_conf!(BooleanConf, SHUFFLE_MERGE_FILE_SPILLS_FIRST);
+define_conf!(IntConf, SHUFFLE_INDEX_CHECKSUM_BLOCK_SIZE);
+define_conf!(IntConf, SHUFFLE_MAX_INTERRUPT_RETRIES);
+define_conf!(DoubleConf, SHUFFLE_IN_MEM_SPILL_RATIO);
+define_conf!(BooleanConf, SHUFFLE_UNSAFE_ROW_OUTPUT);
+define_conf!(StringConf, SHUFFLE_RANGE_BOUNDARY_TIE_BREAK);
+define_conf!(IntConf, SHUFFLE_ZSTD_SEEKABLE_FRAME_SIZE);
+define_conf!(BooleanConf, SHUFFLE_COLUMN_SIZES_ENABLE);
+define_conf!(BooleanConf, SHUFFLE_VERIFY_IN_MEM_SPILL_OFFSETS);
+define_conf!(StringConf, SHUFFLE_OUTPUT_FILE_EXTENSION);
+define_conf!(StringConf, SHUFFLE_SPILL_TRACE_DIR);
 define_conf!(StringConf, SPILL_COMPRESSION_CODEC);
+define_conf!(StringConf, SPILL_FILE_EXTENSION);
 define_conf!(BooleanConf, SMJ_FALLBACK_ENABLE);
 define_conf!(IntConf, SMJ_FALLBACK_ROWS_THRESHOLD);
 define_conf!(IntConf, SMJ_FALLBACK_MEM_SIZE_THRESHOLD);
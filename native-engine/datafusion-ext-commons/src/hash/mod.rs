@@ -13,6 +13,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod crc32c;
 pub mod mur;
 pub mod xxhash;
 
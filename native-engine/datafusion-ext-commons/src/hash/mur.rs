@@ -29,6 +29,11 @@ pub fn spark_compatible_murmur3_hash<T: AsRef<[u8]>>(data: T, seed: i32) -> i32
     fmix(h1, len as i32)
 }
 
+#[inline]
+pub fn spark_compatible_murmur3_hash_int(value: i32, seed: i32) -> i32 {
+    hash_int(value, seed)
+}
+
 #[inline]
 pub fn spark_compatible_murmur3_hash_long(value: i64, seed: i32) -> i32 {
     hash_long(value, seed)
@@ -72,6 +77,13 @@ fn hash_bytes_by_int(data: &[u8], seed: i32) -> i32 {
     h1
 }
 
+#[inline]
+fn hash_int(input: i32, seed: i32) -> i32 {
+    let k1 = mix_k1(input);
+    let h1 = mix_h1(seed, k1);
+    fmix(h1, 4)
+}
+
 #[inline]
 fn hash_long(input: i64, seed: i32) -> i32 {
     let low = input as i32;
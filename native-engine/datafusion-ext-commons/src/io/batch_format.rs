@@ -0,0 +1,265 @@
+// Licensed to the Apache Software Foundation (ASF) under one or more
+// contributor license agreements.  See the NOTICE file distributed with
+// this work for additional information regarding copyright ownership.
+// The ASF licenses this file to You under the Apache License, Version 2.0
+// (the "License"); you may not use this file except in compliance with
+// the License.  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Write},
+    sync::Arc,
+};
+
+use arrow::{
+    array::{ArrayRef, RecordBatch, RecordBatchOptions},
+    buffer::Buffer,
+    datatypes::{DataType, Field, Schema, SchemaRef},
+    ipc::{
+        MetadataVersion,
+        reader::read_record_batch,
+        root_as_message,
+        writer::{DictionaryTracker, IpcDataGenerator, IpcWriteOptions, write_message},
+    },
+};
+use datafusion::common::Result;
+
+use crate::{
+    df_execution_err,
+    io::{batch_serde, read_bytes_slice, read_len, write_len},
+};
+
+/// format of batches serialized by [`write_one_batch_with_format`]. supported
+/// formats are:
+///
+/// - `native` (default): the compact auron batch format.
+/// - `arrow_ipc_v4`: a length-prefixed arrow IPC record batch message encoded
+///   with IPC metadata version V4, the metadata format used before arrow format
+///   1.0.
+/// - `arrow_ipc_v5`: same as above, encoded with IPC metadata version V5.
+///
+/// only the record batch message is written, the schema message is never
+/// repeated since readers always know the schema. the messages are stored
+/// inside auron's compressed block framing, so shuffle/spill files are not
+/// readable as plain arrow IPC streams by other tools.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BatchFormat {
+    #[default]
+    Native,
+    ArrowIpcV4,
+    ArrowIpcV5,
+}
+
+impl BatchFormat {
+    pub fn try_from_name(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "native" => Ok(Self::Native),
+            "arrow_ipc_v4" => Ok(Self::ArrowIpcV4),
+            "arrow_ipc_v5" => Ok(Self::ArrowIpcV5),
+            _ => df_execution_err!("unsupported batch format: {name}"),
+        }
+    }
+
+    pub fn ipc_metadata_version(&self) -> Option<MetadataVersion> {
+        match self {
+            Self::Native => None,
+            Self::ArrowIpcV4 => Some(MetadataVersion::V4),
+            Self::ArrowIpcV5 => Some(MetadataVersion::V5),
+        }
+    }
+}
+
+pub fn write_one_batch_with_format(
+    num_rows: usize,
+    cols: &[ArrayRef],
+    mut output: impl Write,
+    format: BatchFormat,
+) -> Result<()> {
+    let Some(metadata_version) = format.ipc_metadata_version() else {
+        return batch_serde::write_batch(num_rows, cols, &mut output);
+    };
+
+    // dictionary batches would need to be written along with every batch
+    if let Some(col) = cols
+        .iter()
+        .find(|col| matches!(col.data_type(), DataType::Dictionary(..)))
+    {
+        return df_execution_err!(
+            "arrow ipc batch format does not support dictionary type: {}",
+            col.data_type()
+        );
+    }
+
+    // column names are not serialized in native format, use placeholders
+    let schema = Arc::new(Schema::new(
+        cols.iter()
+            .enumerate()
+            .map(|(i, col)| Field::new(format!("c{i}"), col.data_type().clone(), true))
+            .collect::<Vec<_>>(),
+    ));
+    let batch = RecordBatch::try_new_with_options(
+        schema,
+        cols.to_vec(),
+        &RecordBatchOptions::new().with_row_count(Some(num_rows)),
+    )?;
+
+    let options = IpcWriteOptions::try_new(8, false, metadata_version)?;
+    let (_, encoded) = IpcDataGenerator::default().encoded_batch(
+        &batch,
+        &mut DictionaryTracker::new(false),
+        &options,
+    )?;
+    let mut buf = vec![];
+    write_message(&mut buf, encoded, &options)?;
+
+    write_len(buf.len(), &mut output)?;
+    output.write_all(&buf)?;
+    Ok(())
+}
+
+/// reads a batch written by [`write_one_batch_with_format`], fails if the
+/// batch is written with another arrow IPC metadata version
+pub fn read_one_batch_with_format(
+    mut input: impl Read,
+    schema: &SchemaRef,
+    format: BatchFormat,
+) -> Result<Option<(usize, Vec<ArrayRef>)>> {
+    let Some(metadata_version) = format.ipc_metadata_version() else {
+        return batch_serde::read_batch(&mut input, schema);
+    };
+
+    let len = match read_len(&mut input) {
+        Ok(len) => len,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let buf = read_bytes_slice(&mut input, len)?;
+    let metadata = ipc_message_metadata(&buf)?;
+    let message = match root_as_message(metadata) {
+        Ok(message) => message,
+        Err(e) => return df_execution_err!("invalid arrow ipc message: {e}"),
+    };
+    if message.version() != metadata_version {
+        return df_execution_err!(
+            "arrow ipc metadata version mismatched: expected {metadata_version:?}, got {:?}",
+            message.version()
+        );
+    }
+    let Some(ipc_batch) = message.header_as_record_batch() else {
+        return df_execution_err!("arrow ipc message is not a record batch");
+    };
+
+    let body_start = 8 + metadata.len();
+    let body_end = body_start + message.bodyLength() as usize;
+    let Some(body) = buf.get(body_start..body_end) else {
+        return df_execution_err!("truncated arrow ipc message body");
+    };
+    let batch = read_record_batch(
+        &Buffer::from(body),
+        ipc_batch,
+        schema.clone(),
+        &HashMap::new(),
+        None,
+        &metadata_version,
+    )?;
+    Ok(Some((batch.num_rows(), batch.columns().to_vec())))
+}
+
+// returns the metadata flatbuffer of an encapsulated arrow IPC message
+fn ipc_message_metadata(buf: &[u8]) -> Result<&[u8]> {
+    // every message starts with a continuation marker and the metadata length
+    if buf.len() < 8 || buf[0..4] != [0xff; 4] {
+        return df_execution_err!("invalid arrow ipc message");
+    }
+    let len = i32::from_le_bytes(buf[4..8].try_into().unwrap()) as usize;
+    match buf.get(8..8 + len) {
+        Some(metadata) => Ok(metadata),
+        None => df_execution_err!("truncated arrow ipc message"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use arrow::{
+        array::{DictionaryArray, Int32Array, StringArray},
+        datatypes::Int32Type,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_arrow_ipc_metadata_version() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, true),
+            Field::new("b", DataType::Utf8, true),
+        ]));
+        let cols: Vec<ArrayRef> = vec![
+            Arc::new(Int32Array::from(vec![Some(1), None, Some(3)])),
+            Arc::new(StringArray::from(vec![Some("x"), Some("y"), None])),
+        ];
+
+        for format in [
+            BatchFormat::Native,
+            BatchFormat::ArrowIpcV4,
+            BatchFormat::ArrowIpcV5,
+        ] {
+            let mut buf = vec![];
+            write_one_batch_with_format(3, &cols, &mut buf, format)?;
+            write_one_batch_with_format(3, &cols, &mut buf, format)?;
+
+            let mut cursor = Cursor::new(&buf);
+            for _ in 0..2 {
+                let (num_rows, decoded) =
+                    read_one_batch_with_format(&mut cursor, &schema, format)?.unwrap();
+                assert_eq!(num_rows, 3);
+                assert_eq!(decoded, cols);
+            }
+            assert!(read_one_batch_with_format(&mut cursor, &schema, format)?.is_none());
+        }
+
+        // a reader pinned to V5 rejects batches written with V4, and vice versa
+        let mut buf = vec![];
+        write_one_batch_with_format(3, &cols, &mut buf, BatchFormat::ArrowIpcV4)?;
+        let mut cursor = Cursor::new(&buf);
+        read_len(&mut cursor)?;
+        let message = &buf[cursor.position() as usize..];
+        let message = root_as_message(ipc_message_metadata(message)?).unwrap();
+        assert_eq!(message.version(), MetadataVersion::V4);
+
+        // only the record batch message is written, without schema
+        assert!(message.header_as_record_batch().is_some());
+        assert!(
+            read_one_batch_with_format(Cursor::new(&buf), &schema, BatchFormat::ArrowIpcV5)
+                .is_err()
+        );
+
+        let mut buf = vec![];
+        write_one_batch_with_format(3, &cols, &mut buf, BatchFormat::ArrowIpcV5)?;
+        assert!(
+            read_one_batch_with_format(Cursor::new(&buf), &schema, BatchFormat::ArrowIpcV4)
+                .is_err()
+        );
+
+        // dictionary columns are rejected
+        let dict_col: ArrayRef = Arc::new(DictionaryArray::<Int32Type>::from_iter(["x", "y"]));
+        assert!(
+            write_one_batch_with_format(2, &[dict_col], vec![], BatchFormat::ArrowIpcV5).is_err()
+        );
+        assert_eq!(
+            BatchFormat::try_from_name("ARROW_IPC_V4")?,
+            BatchFormat::ArrowIpcV4
+        );
+        assert!(BatchFormat::try_from_name("arrow_ipc_v3").is_err());
+        Ok(())
+    }
+}
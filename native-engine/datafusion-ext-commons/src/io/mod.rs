@@ -20,14 +20,18 @@ use arrow::{
     datatypes::SchemaRef,
     record_batch::RecordBatch,
 };
+pub use batch_format::{BatchFormat, read_one_batch_with_format, write_one_batch_with_format};
 pub use batch_serde::{read_array, write_array};
 use datafusion::common::Result;
 pub use scalar_serde::{read_scalar, write_scalar};
+pub use unsafe_row::{is_unsafe_row_supported, write_unsafe_row, write_unsafe_rows};
 
 use crate::{UninitializedInit, arrow::cast::cast};
 
+mod batch_format;
 mod batch_serde;
 mod scalar_serde;
+mod unsafe_row;
 
 pub fn write_one_batch(num_rows: usize, cols: &[ArrayRef], mut output: impl Write) -> Result<()> {
     batch_serde::write_batch(num_rows, cols, &mut output)
@@ -0,0 +1,368 @@
+// Licensed to the Apache Software Foundation (ASF) under one or more
+// contributor license agreements.  See the NOTICE file distributed with
+// this work for additional information regarding copyright ownership.
+// The ASF licenses this file to You under the Apache License, Version 2.0
+// (the "License"); you may not use this file except in compliance with
+// the License.  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Serializes rows in spark's `UnsafeRow` layout, as written by
+//! `UnsafeRowSerializer`, so that pure JVM reducers can consume them without a
+//! columnar-to-row conversion.
+//!
+//! Every record is a big-endian i32 row size followed by the row bytes:
+//!
+//! ```text
+//! [null bitset: 8 bytes per 64 fields][fixed region: 8 bytes per field][variable region]
+//! ```
+//!
+//! Fixed-width values are stored little-endian in their 8-byte slots (zeroed
+//! first). Variable-length values are 8-byte aligned in the variable region,
+//! their slots hold `(offset << 32) | size`, where offset is relative to the
+//! row start. Null fields have their bit set and a zeroed slot, except
+//! decimals with precision > 18 which always reserve 16 bytes.
+
+use std::io::Write;
+
+use arrow::{
+    array::{Array, ArrayRef, AsArray},
+    datatypes::{
+        DataType, Date32Type, Decimal128Type, Float32Type, Float64Type, Int8Type, Int16Type,
+        Int32Type, Int64Type, TimeUnit, TimestampMicrosecondType,
+    },
+};
+use datafusion::common::Result;
+
+use crate::df_unimplemented_err;
+
+/// max precision of decimals stored as unscaled longs in the fixed region
+const MAX_LONG_DIGITS: u8 = 18;
+
+/// returns whether columns of the data type can be written as UnsafeRow
+/// fields
+pub fn is_unsafe_row_supported(data_type: &DataType) -> bool {
+    matches!(
+        data_type,
+        DataType::Null
+            | DataType::Boolean
+            | DataType::Int8
+            | DataType::Int16
+            | DataType::Int32
+            | DataType::Int64
+            | DataType::Float32
+            | DataType::Float64
+            | DataType::Date32
+            | DataType::Timestamp(TimeUnit::Microsecond, _)
+            | DataType::Decimal128(..)
+            | DataType::Utf8
+            | DataType::Binary
+    )
+}
+
+/// writes rows as an `UnsafeRowSerializer` stream
+pub fn write_unsafe_rows<W: Write>(num_rows: usize, cols: &[ArrayRef], mut w: W) -> Result<()> {
+    for col in cols {
+        if !is_unsafe_row_supported(col.data_type()) {
+            return df_unimplemented_err!(
+                "writing {} as UnsafeRow field is not supported",
+                col.data_type()
+            );
+        }
+    }
+
+    let mut buf = vec![];
+    let mut row = vec![];
+    for row_idx in 0..num_rows {
+        row.clear();
+        write_unsafe_row(cols, row_idx, &mut row);
+        buf.extend_from_slice(&(row.len() as i32).to_be_bytes());
+        buf.extend_from_slice(&row);
+    }
+    w.write_all(&buf)?;
+    Ok(())
+}
+
+/// appends one row in UnsafeRow layout to `row`, all columns must be
+/// supported by [`is_unsafe_row_supported`]
+pub fn write_unsafe_row(cols: &[ArrayRef], row_idx: usize, row: &mut Vec<u8>) {
+    let num_fields = cols.len();
+    let null_bitset_width = num_fields.div_ceil(64) * 8;
+    let fixed_size = null_bitset_width + num_fields * 8;
+    let start = row.len();
+    row.resize(start + fixed_size, 0);
+
+    for (ordinal, col) in cols.iter().enumerate() {
+        let slot = start + null_bitset_width + ordinal * 8;
+        let is_null = col.data_type() == &DataType::Null || col.is_null(row_idx);
+        if is_null {
+            row[start + ordinal / 8] |= 1 << (ordinal % 8);
+        }
+
+        macro_rules! put {
+            ($bytes:expr) => {{
+                let bytes = $bytes;
+                row[slot..slot + bytes.len()].copy_from_slice(&bytes);
+            }};
+        }
+        macro_rules! put_var {
+            ($bytes:expr, $reserved:expr) => {{
+                let bytes: &[u8] = $bytes;
+                let offset = row.len() - start;
+                row.extend_from_slice(bytes);
+                row.resize(
+                    row.len() + $reserved.max(bytes.len()).next_multiple_of(8) - bytes.len(),
+                    0,
+                );
+                let offset_and_size = ((offset as u64) << 32) | bytes.len() as u64;
+                put!(offset_and_size.to_le_bytes());
+            }};
+        }
+
+        match col.data_type() {
+            DataType::Decimal128(precision, _) if *precision > MAX_LONG_DIGITS => {
+                // always reserves 16 bytes, null decimals keep the offset
+                if is_null {
+                    put_var!(&[], 16);
+                } else {
+                    let unscaled = col.as_primitive::<Decimal128Type>().value(row_idx);
+                    put_var!(&big_integer_bytes(unscaled), 16);
+                }
+            }
+            _ if is_null => {}
+            DataType::Null => {}
+            DataType::Boolean => put!([col.as_boolean().value(row_idx) as u8]),
+            DataType::Int8 => put!(col.as_primitive::<Int8Type>().value(row_idx).to_le_bytes()),
+            DataType::Int16 => put!(col.as_primitive::<Int16Type>().value(row_idx).to_le_bytes()),
+            DataType::Int32 => put!(col.as_primitive::<Int32Type>().value(row_idx).to_le_bytes()),
+            DataType::Int64 => put!(col.as_primitive::<Int64Type>().value(row_idx).to_le_bytes()),
+            DataType::Float32 => {
+                put!(
+                    col.as_primitive::<Float32Type>()
+                        .value(row_idx)
+                        .to_le_bytes()
+                )
+            }
+            DataType::Float64 => {
+                put!(
+                    col.as_primitive::<Float64Type>()
+                        .value(row_idx)
+                        .to_le_bytes()
+                )
+            }
+            DataType::Date32 => put!(
+                col.as_primitive::<Date32Type>()
+                    .value(row_idx)
+                    .to_le_bytes()
+            ),
+            DataType::Timestamp(TimeUnit::Microsecond, _) => put!(
+                col.as_primitive::<TimestampMicrosecondType>()
+                    .value(row_idx)
+                    .to_le_bytes()
+            ),
+            DataType::Decimal128(..) => {
+                put!((col.as_primitive::<Decimal128Type>().value(row_idx) as i64).to_le_bytes())
+            }
+            DataType::Utf8 => put_var!(col.as_string::<i32>().value(row_idx).as_bytes(), 0),
+            DataType::Binary => put_var!(col.as_binary::<i32>().value(row_idx), 0),
+            other => unreachable!("unsupported UnsafeRow field type: {other}"),
+        }
+    }
+}
+
+// same as java's BigInteger.toByteArray(): minimal big-endian two's complement
+fn big_integer_bytes(value: i128) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let mut start = 0;
+    while start < bytes.len() - 1 {
+        let redundant = (bytes[start] == 0x00 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0);
+        if !redundant {
+            break;
+        }
+        start += 1;
+    }
+    bytes[start..].to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arrow::array::{
+        BinaryArray, BooleanArray, Decimal128Array, Float64Array, Int32Array, Int64Array,
+        StringArray,
+    };
+    use datafusion::common::Result;
+
+    use super::*;
+
+    // reference reader of UnsafeRowSerializer streams, following spark's
+    // UnsafeRow accessors
+    struct RefUnsafeRow<'a> {
+        data: &'a [u8],
+        num_fields: usize,
+    }
+
+    impl<'a> RefUnsafeRow<'a> {
+        fn read_all(mut stream: &'a [u8], num_fields: usize) -> Vec<Self> {
+            let mut rows = vec![];
+            while !stream.is_empty() {
+                let size = i32::from_be_bytes(stream[..4].try_into().unwrap()) as usize;
+                rows.push(Self {
+                    data: &stream[4..4 + size],
+                    num_fields,
+                });
+                stream = &stream[4 + size..];
+            }
+            rows
+        }
+
+        fn field_offset(&self, ordinal: usize) -> usize {
+            self.num_fields.div_ceil(64) * 8 + ordinal * 8
+        }
+
+        fn is_null_at(&self, ordinal: usize) -> bool {
+            let word = u64::from_le_bytes(self.data[ordinal / 64 * 8..][..8].try_into().unwrap());
+            word & (1 << (ordinal % 64)) != 0
+        }
+
+        fn get_long(&self, ordinal: usize) -> i64 {
+            i64::from_le_bytes(
+                self.data[self.field_offset(ordinal)..][..8]
+                    .try_into()
+                    .unwrap(),
+            )
+        }
+
+        fn get_int(&self, ordinal: usize) -> i32 {
+            i32::from_le_bytes(
+                self.data[self.field_offset(ordinal)..][..4]
+                    .try_into()
+                    .unwrap(),
+            )
+        }
+
+        fn get_boolean(&self, ordinal: usize) -> bool {
+            self.data[self.field_offset(ordinal)] != 0
+        }
+
+        fn get_double(&self, ordinal: usize) -> f64 {
+            f64::from_bits(self.get_long(ordinal) as u64)
+        }
+
+        fn get_binary(&self, ordinal: usize) -> &[u8] {
+            let offset_and_size = self.get_long(ordinal) as u64;
+            let offset = (offset_and_size >> 32) as usize;
+            let size = offset_and_size as u32 as usize;
+            &self.data[offset..offset + size]
+        }
+
+        fn get_big_decimal_unscaled(&self, ordinal: usize) -> i128 {
+            let bytes = self.get_binary(ordinal);
+            let mut buf = [if bytes[0] & 0x80 != 0 { 0xff } else { 0 }; 16];
+            buf[16 - bytes.len()..].copy_from_slice(bytes);
+            i128::from_be_bytes(buf)
+        }
+    }
+
+    // the rows of test_unsafe_row_layout, as projected by spark's
+    // UnsafeProjection and serialized by UnsafeRowSerializer. the same bytes
+    // are checked against spark in AuronUnsafeRowSuite
+    const SPARK_UNSAFE_ROWS_HEX: &[&str] = &[
+        "0000007000000000000000000100000000000000feffffffffffffff05000000",
+        "480000000100000000000000000000000000f83f393000000000000001000000",
+        "500000000a0000006000000068656c6c6f000000ff0000000000000000000000",
+        "000000003031323334353637383900000000000000000058c100000000000000",
+        "0000000000000000000000000001000000000000480000000000000000000000",
+        "000000000000d0bfffffffffffffffff00000000480000000000000000000000",
+        "00000000000000000000000000000000000000602c00000000000000f9ffffff",
+        "000000000000000000000000000000000000000000000000000000007dc39425",
+        "ad49b25400000000000000000a00000048000000020000005800000003e7ffff",
+        "fffffffffc180000000000006162000000000000",
+    ];
+
+    #[test]
+    fn test_unsafe_row_layout() -> Result<()> {
+        let cols: Vec<ArrayRef> = vec![
+            Arc::new(Int32Array::from(vec![Some(1), None, Some(-7)])),
+            Arc::new(Int64Array::from(vec![-2, 1 << 40, 0])),
+            Arc::new(StringArray::from(vec![Some("hello"), Some(""), None])),
+            Arc::new(BooleanArray::from(vec![Some(true), Some(false), None])),
+            Arc::new(Float64Array::from(vec![1.5, -0.25, 1e100])),
+            Arc::new(
+                Decimal128Array::from(vec![Some(12345), Some(-1), None])
+                    .with_precision_and_scale(10, 2)?,
+            ),
+            Arc::new(
+                Decimal128Array::from(vec![Some(-1), None, Some(i128::from(u64::MAX) * 1000)])
+                    .with_precision_and_scale(38, 2)?,
+            ),
+            Arc::new(BinaryArray::from(vec![
+                Some(b"0123456789".as_ref()),
+                None,
+                Some(b"ab".as_ref()),
+            ])),
+        ];
+        let mut stream = vec![];
+        write_unsafe_rows(3, &cols, &mut stream)?;
+
+        // byte-exact layout of all rows
+        let golden = SPARK_UNSAFE_ROWS_HEX
+            .concat()
+            .as_bytes()
+            .chunks(2)
+            .map(|hex| u8::from_str_radix(std::str::from_utf8(hex).unwrap(), 16).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(stream, golden);
+
+        // decode all rows with the reference reader
+        let rows = RefUnsafeRow::read_all(&stream, 8);
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].data.len(), 112);
+        assert_eq!(rows[0].get_big_decimal_unscaled(6), -1);
+
+        assert!(rows[1].is_null_at(0));
+        assert_eq!(rows[1].get_long(0), 0);
+        assert_eq!(rows[1].get_long(1), 1 << 40);
+        assert_eq!(rows[1].get_binary(2), b"");
+        assert!(!rows[1].get_boolean(3));
+        assert_eq!(rows[1].get_double(4), -0.25);
+        assert_eq!(rows[1].get_long(5), -1);
+        assert!(rows[1].is_null_at(6));
+        assert!(rows[1].is_null_at(7));
+        assert_eq!(rows[1].get_long(7), 0);
+        // null decimal(38, 2) still reserves 16 bytes
+        assert_eq!(rows[1].get_long(6) as u64, 72 << 32);
+        assert_eq!(rows[1].data.len(), 72 + 16);
+
+        assert_eq!(rows[2].get_int(0), -7);
+        assert!(rows[2].is_null_at(2));
+        assert!(rows[2].is_null_at(3));
+        assert_eq!(rows[2].get_double(4), 1e100);
+        assert!(rows[2].is_null_at(5));
+        assert_eq!(
+            rows[2].get_big_decimal_unscaled(6),
+            i128::from(u64::MAX) * 1000
+        );
+        assert_eq!(rows[2].get_binary(7), b"ab");
+        Ok(())
+    }
+
+    #[test]
+    fn test_big_integer_bytes() {
+        assert_eq!(big_integer_bytes(0), vec![0x00]);
+        assert_eq!(big_integer_bytes(127), vec![0x7f]);
+        assert_eq!(big_integer_bytes(128), vec![0x00, 0x80]);
+        assert_eq!(big_integer_bytes(-1), vec![0xff]);
+        assert_eq!(big_integer_bytes(-128), vec![0x80]);
+        assert_eq!(big_integer_bytes(-129), vec![0xff, 0x7f]);
+        assert_eq!(big_integer_bytes(i128::MIN).len(), 16);
+    }
+}
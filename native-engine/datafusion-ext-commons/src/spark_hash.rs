@@ -18,12 +18,24 @@
 use arrow::{
     array::*,
     datatypes::{
-        ArrowDictionaryKeyType, ArrowNativeType, DataType, Int8Type, Int16Type, Int32Type,
-        Int64Type, TimeUnit,
+        ArrowDictionaryKeyType, ArrowNativeType, ArrowPrimitiveType, DataType, Date32Type,
+        Date64Type, Float32Type, Float64Type, Int8Type, Int16Type, Int32Type, Int64Type, TimeUnit,
+        TimestampMicrosecondType, TimestampMillisecondType, TimestampNanosecondType,
+        TimestampSecondType,
+    },
+};
+use datafusion::common::Result;
+
+use crate::{
+    df_execution_err,
+    hash::{
+        mur::{
+            spark_compatible_murmur3_hash, spark_compatible_murmur3_hash_int,
+            spark_compatible_murmur3_hash_long,
+        },
+        xxhash::spark_compatible_xxhash64_hash,
     },
 };
-
-use crate::hash::{mur::spark_compatible_murmur3_hash, xxhash::spark_compatible_xxhash64_hash};
 
 pub fn create_murmur3_hashes(len: usize, arrays: &[ArrayRef], seed: i32) -> Vec<i32> {
     create_hashes(len, arrays, seed, |data: &[u8], seed: i32| {
@@ -37,6 +49,93 @@ pub fn create_xxhash64_hashes(len: usize, arrays: &[ArrayRef], seed: i64) -> Vec
     })
 }
 
+/// Order in which the per-column hashes of a multi-column key are combined.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HashCombineOrder {
+    /// spark: columns are combined from left to right
+    #[default]
+    LeftToRight,
+    RightToLeft,
+}
+
+/// Method used to combine the per-column hashes of a multi-column key.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HashCombineMethod {
+    /// spark: hash of the previous columns is used as seed of the next column
+    #[default]
+    SeedChaining,
+    /// `combined = 31 * combined + murmur3(column)`, every column is hashed
+    /// with murmur3 and the initial seed. NOTE: this is not compatible with
+    /// hive bucketing, which uses different per-column hash functions
+    Multiply31,
+}
+
+/// Combines per-column murmur3 hashes of a multi-column key. The default
+/// combiner is identical to spark's hash partitioning.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HashCombiner {
+    pub order: HashCombineOrder,
+    pub method: HashCombineMethod,
+}
+
+impl HashCombiner {
+    pub fn try_from_names(order: &str, method: &str) -> Result<Self> {
+        let order = match order.to_ascii_lowercase().as_str() {
+            "left_to_right" => HashCombineOrder::LeftToRight,
+            "right_to_left" => HashCombineOrder::RightToLeft,
+            _ => df_execution_err!("unsupported hash combine order: {order}")?,
+        };
+        let method = match method.to_ascii_lowercase().as_str() {
+            "seed_chaining" => HashCombineMethod::SeedChaining,
+            "multiply31" => HashCombineMethod::Multiply31,
+            _ => df_execution_err!("unsupported hash combine method: {method}")?,
+        };
+        Ok(Self { order, method })
+    }
+
+    pub fn create_murmur3_hashes(
+        &self,
+        len: usize,
+        arrays: &[ArrayRef],
+        seed: i32,
+        vectorized: bool,
+    ) -> Vec<i32> {
+        let hash_column = |col: &ArrayRef, hashes: &mut [i32]| {
+            if !(vectorized && hash_array_murmur3_vectorized(col, hashes)) {
+                hash_array(col, hashes, |data: &[u8], seed: i32| {
+                    spark_compatible_murmur3_hash(data, seed)
+                });
+            }
+        };
+        let columns: Box<dyn Iterator<Item = &ArrayRef>> = match self.order {
+            HashCombineOrder::LeftToRight => Box::new(arrays.iter()),
+            HashCombineOrder::RightToLeft => Box::new(arrays.iter().rev()),
+        };
+
+        match self.method {
+            HashCombineMethod::SeedChaining => {
+                let mut hash_buffer = vec![seed; len];
+                for col in columns {
+                    hash_column(col, &mut hash_buffer);
+                }
+                hash_buffer
+            }
+            HashCombineMethod::Multiply31 => {
+                let mut hash_buffer = vec![0i32; len];
+                let mut col_hashes = vec![seed; len];
+                for col in columns {
+                    col_hashes.fill(seed);
+                    hash_column(col, &mut col_hashes);
+                    for (hash, &col_hash) in hash_buffer.iter_mut().zip(&col_hashes) {
+                        *hash = hash.wrapping_mul(31).wrapping_add(col_hash);
+                    }
+                }
+                hash_buffer
+            }
+        }
+    }
+}
+
 /// Creates hash values for every row, based on the values in the
 /// columns.
 ///
@@ -214,6 +313,87 @@ fn hash_array<T: num::PrimInt>(
     }
 }
 
+/// Hashes a fixed-width column with murmur3 directly on its native values in
+/// place, without going through byte slices. Null rows are handled without
+/// branching: every value is hashed and the previous hash is kept for nulls.
+/// Returns false if the data type is not supported, in which case the hashes
+/// buffer is left untouched.
+fn hash_array_murmur3_vectorized(array: &ArrayRef, hashes_buffer: &mut [i32]) -> bool {
+    assert_eq!(array.len(), hashes_buffer.len());
+
+    fn hash_values<T: ArrowPrimitiveType>(
+        array: &ArrayRef,
+        hashes_buffer: &mut [i32],
+        h: impl Fn(T::Native, i32) -> i32,
+    ) {
+        let array = array.as_primitive::<T>();
+        let values = array.values().iter();
+        match array.nulls().filter(|nulls| nulls.null_count() > 0) {
+            None => {
+                for (hash, &value) in hashes_buffer.iter_mut().zip(values) {
+                    *hash = h(value, *hash);
+                }
+            }
+            Some(nulls) => {
+                let valids = nulls.inner().iter();
+                for ((hash, &value), valid) in hashes_buffer.iter_mut().zip(values).zip(valids) {
+                    let hashed = h(value, *hash);
+                    *hash = if valid { hashed } else { *hash };
+                }
+            }
+        }
+    }
+
+    match array.data_type() {
+        DataType::Int8 => hash_values::<Int8Type>(array, hashes_buffer, |v, seed| {
+            spark_compatible_murmur3_hash_int(v as i32, seed)
+        }),
+        DataType::Int16 => hash_values::<Int16Type>(array, hashes_buffer, |v, seed| {
+            spark_compatible_murmur3_hash_int(v as i32, seed)
+        }),
+        DataType::Int32 => {
+            hash_values::<Int32Type>(array, hashes_buffer, spark_compatible_murmur3_hash_int)
+        }
+        DataType::Date32 => {
+            hash_values::<Date32Type>(array, hashes_buffer, spark_compatible_murmur3_hash_int)
+        }
+        DataType::Int64 => {
+            hash_values::<Int64Type>(array, hashes_buffer, spark_compatible_murmur3_hash_long)
+        }
+        DataType::Date64 => {
+            hash_values::<Date64Type>(array, hashes_buffer, spark_compatible_murmur3_hash_long)
+        }
+        DataType::Timestamp(TimeUnit::Second, _) => hash_values::<TimestampSecondType>(
+            array,
+            hashes_buffer,
+            spark_compatible_murmur3_hash_long,
+        ),
+        DataType::Timestamp(TimeUnit::Millisecond, _) => hash_values::<TimestampMillisecondType>(
+            array,
+            hashes_buffer,
+            spark_compatible_murmur3_hash_long,
+        ),
+        DataType::Timestamp(TimeUnit::Microsecond, _) => hash_values::<TimestampMicrosecondType>(
+            array,
+            hashes_buffer,
+            spark_compatible_murmur3_hash_long,
+        ),
+        DataType::Timestamp(TimeUnit::Nanosecond, _) => hash_values::<TimestampNanosecondType>(
+            array,
+            hashes_buffer,
+            spark_compatible_murmur3_hash_long,
+        ),
+        DataType::Float32 => hash_values::<Float32Type>(array, hashes_buffer, |v, seed| {
+            spark_compatible_murmur3_hash_int(v.to_bits() as i32, seed)
+        }),
+        DataType::Float64 => hash_values::<Float64Type>(array, hashes_buffer, |v, seed| {
+            spark_compatible_murmur3_hash_long(v.to_bits() as i64, seed)
+        }),
+        _ => return false,
+    }
+    true
+}
+
 /// Hash the values in a dictionary array
 #[inline]
 fn create_hashes_dictionary<K: ArrowDictionaryKeyType, T: num::PrimInt>(
@@ -357,12 +537,15 @@ fn hash_one<T: num::PrimInt>(
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::{sync::Arc, time::Instant};
 
     use arrow::{
         array::{
-            Array, ArrayData, ArrayRef, Int8Array, Int32Array, Int64Array, MapArray, StringArray,
-            StructArray, UInt32Array, make_array,
+            Array, ArrayData, ArrayRef, BinaryArray, BooleanArray, Date32Array, Date64Array,
+            Decimal128Array, Float32Array, Float64Array, Int8Array, Int16Array, Int32Array,
+            Int64Array, MapArray, StringArray, StructArray, TimestampMicrosecondArray,
+            TimestampMillisecondArray, TimestampNanosecondArray, TimestampSecondArray, UInt32Array,
+            make_array,
         },
         buffer::Buffer,
         datatypes::{DataType, Field, ToByteSlice},
@@ -627,4 +810,204 @@ mod tests {
                 .unwrap()
         );
     }
+
+    fn build_arrays_of_all_types(n: usize) -> Vec<ArrayRef> {
+        fn with_nulls<T>(n: usize, f: impl Fn() -> T) -> Vec<Option<T>> {
+            (0..n).map(|i| (i % 7 != 0).then(|| f())).collect()
+        }
+        vec![
+            Arc::new(BooleanArray::from(with_nulls(n, rand::random::<bool>))),
+            Arc::new(Int8Array::from(with_nulls(n, rand::random::<i8>))),
+            Arc::new(Int16Array::from(with_nulls(n, rand::random::<i16>))),
+            Arc::new(Int32Array::from(with_nulls(n, rand::random::<i32>))),
+            Arc::new(Int64Array::from(with_nulls(n, rand::random::<i64>))),
+            Arc::new(Float32Array::from(with_nulls(n, rand::random::<f32>))),
+            Arc::new(Float64Array::from(with_nulls(n, rand::random::<f64>))),
+            Arc::new(Date32Array::from(with_nulls(n, rand::random::<i32>))),
+            Arc::new(Date64Array::from(with_nulls(n, rand::random::<i64>))),
+            Arc::new(TimestampSecondArray::from(with_nulls(
+                n,
+                rand::random::<i64>,
+            ))),
+            Arc::new(TimestampMillisecondArray::from(with_nulls(
+                n,
+                rand::random::<i64>,
+            ))),
+            Arc::new(TimestampMicrosecondArray::from(with_nulls(
+                n,
+                rand::random::<i64>,
+            ))),
+            Arc::new(TimestampNanosecondArray::from(with_nulls(
+                n,
+                rand::random::<i64>,
+            ))),
+            Arc::new(Decimal128Array::from(with_nulls(n, rand::random::<i128>))),
+            Arc::new(StringArray::from(with_nulls(n, || {
+                format!("{}", rand::random::<u32>())
+            }))),
+            Arc::new(BinaryArray::from_iter(with_nulls(n, || {
+                rand::random::<u64>().to_le_bytes()
+            }))),
+        ]
+    }
+
+    // columns with special float values and mostly nulls
+    fn build_edge_case_arrays(n: usize) -> Vec<ArrayRef> {
+        let mostly_nulls = |i: usize| i % 10 == 9;
+        let f32_values = [0.0, -0.0, f32::NAN, -f32::NAN, f32::INFINITY, f32::MIN, 1.5];
+        let f64_values = [
+            0.0,
+            -0.0,
+            f64::NAN,
+            -f64::NAN,
+            f64::NEG_INFINITY,
+            f64::MAX,
+            1.5,
+        ];
+        vec![
+            Arc::new(Float32Array::from_iter_values(
+                (0..n).map(|i| f32_values[i % f32_values.len()]),
+            )),
+            Arc::new(Float64Array::from_iter_values(
+                (0..n).map(|i| f64_values[i % f64_values.len()]),
+            )),
+            Arc::new(Float32Array::from_iter((0..n).map(|i| {
+                mostly_nulls(i).then(|| f32_values[i % f32_values.len()])
+            }))),
+            Arc::new(Float64Array::from_iter((0..n).map(|i| {
+                mostly_nulls(i).then(|| f64_values[i % f64_values.len()])
+            }))),
+            Arc::new(Int32Array::from_iter(
+                (0..n).map(|i| mostly_nulls(i).then(|| i as i32)),
+            )),
+            Arc::new(Int64Array::from_iter((0..n).map(|_| None::<i64>))),
+        ]
+    }
+
+    #[test]
+    fn test_murmur3_vectorized() {
+        let n = 1000;
+        let vectorized_hashes = |len: usize, arrays: &[ArrayRef]| {
+            HashCombiner::default().create_murmur3_hashes(len, arrays, 42, true)
+        };
+        let all_arrays = build_arrays_of_all_types(n)
+            .into_iter()
+            .chain(build_edge_case_arrays(n))
+            .collect::<Vec<_>>();
+        for array in &all_arrays {
+            // check each column, sliced column and all columns combined
+            for array in [array.clone(), array.slice(3, n - 3)] {
+                let len = array.len();
+                assert_eq!(
+                    vectorized_hashes(len, &[array.clone()]),
+                    create_murmur3_hashes(len, &[array.clone()], 42),
+                    "hashes mismatched for {}",
+                    array.data_type(),
+                );
+            }
+        }
+        assert_eq!(
+            vectorized_hashes(n, &all_arrays),
+            create_murmur3_hashes(n, &all_arrays, 42),
+        );
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --ignored in release mode"]
+    fn bench_murmur3_vectorized() {
+        let n = 1000000;
+        let arrays: Vec<ArrayRef> = vec![
+            Arc::new(Int32Array::from_iter_values(
+                (0..n).map(|_| rand::random::<i32>()),
+            )),
+            Arc::new(Int64Array::from(
+                (0..n)
+                    .map(|i| (i % 10 != 0).then(|| rand::random::<i64>()))
+                    .collect::<Vec<_>>(),
+            )),
+        ];
+
+        let time_start = Instant::now();
+        let hashes = create_murmur3_hashes(n, &arrays, 42);
+        let scalar_time = time_start.elapsed();
+
+        let time_start = Instant::now();
+        let vectorized_hashes = HashCombiner::default().create_murmur3_hashes(n, &arrays, 42, true);
+        let vectorized_time = time_start.elapsed();
+        assert_eq!(hashes, vectorized_hashes);
+        assert!(
+            vectorized_time <= scalar_time,
+            "vectorized hashing is slower: {vectorized_time:?} vs {scalar_time:?}",
+        );
+    }
+
+    #[test]
+    fn test_hash_combiner() -> Result<()> {
+        let arrays: Vec<ArrayRef> = vec![
+            Arc::new(Int32Array::from(vec![1, 1, 2, 3])),
+            Arc::new(Int64Array::from(vec![2, 2, 3, 4])),
+            Arc::new(StringArray::from(vec!["hello", "a", "bc", "spark"])),
+        ];
+
+        // generated with Murmur3Hash(Seq(Literal(1), Literal(2L), Literal("hello")),
+        // 42) and pmod(hash, 200) as spark's hash partitioning does
+        let expected_hashes = vec![948072272, -1035358580, -493921853, 1755241370];
+        let expected_partitions = vec![72, 20, 147, 170];
+
+        let combiner = HashCombiner::default();
+        assert_eq!(
+            combiner,
+            HashCombiner::try_from_names("LEFT_TO_RIGHT", "seed_chaining")?
+        );
+        for vectorized in [false, true] {
+            let hashes = combiner.create_murmur3_hashes(4, &arrays, 42, vectorized);
+            assert_eq!(hashes, expected_hashes);
+            assert_eq!(hashes, create_murmur3_hashes(4, &arrays, 42));
+            let partitions = hashes.iter().map(|h| h.rem_euclid(200)).collect::<Vec<_>>();
+            assert_eq!(partitions, expected_partitions);
+        }
+
+        // reversed order equals to hashing reversed columns
+        let reversed = HashCombiner::try_from_names("right_to_left", "seed_chaining")?;
+        let reversed_arrays = arrays.iter().rev().cloned().collect::<Vec<_>>();
+        assert_eq!(
+            reversed.create_murmur3_hashes(4, &arrays, 42, false),
+            create_murmur3_hashes(4, &reversed_arrays, 42),
+        );
+        assert_eq!(
+            reversed.create_murmur3_hashes(
+                1,
+                &[
+                    arrays[0].slice(0, 1),
+                    arrays[1].slice(0, 1),
+                    arrays[2].slice(0, 1)
+                ],
+                42,
+                false
+            ),
+            vec![1737247936]
+        );
+
+        // multiply31 combines independently seeded column hashes
+        let multiply31 = HashCombiner::try_from_names("left_to_right", "multiply31")?;
+        let col_hashes = arrays
+            .iter()
+            .map(|col| create_murmur3_hashes(4, &[col.clone()], 42))
+            .collect::<Vec<_>>();
+        let expected = (0..4)
+            .map(|i| {
+                col_hashes
+                    .iter()
+                    .fold(0i32, |h, col| h.wrapping_mul(31).wrapping_add(col[i]))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            multiply31.create_murmur3_hashes(4, &arrays, 42, true),
+            expected
+        );
+
+        assert!(HashCombiner::try_from_names("middle_out", "seed_chaining").is_err());
+        assert!(HashCombiner::try_from_names("left_to_right", "xor").is_err());
+        Ok(())
+    }
 }
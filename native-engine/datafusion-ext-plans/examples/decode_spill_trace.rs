@@ -0,0 +1,33 @@
+// Licensed to the Apache Software Foundation (ASF) under one or more
+// contributor license agreements.  See the NOTICE file distributed with
+// this work for additional information regarding copyright ownership.
+// The ASF licenses this file to You under the Apache License, Version 2.0
+// (the "License"); you may not use this file except in compliance with
+// the License.  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Prints records of a spill trace file written by
+//! `SpillTrace::try_new_file`.
+//!
+//! Usage: cargo run --example decode_spill_trace -- <trace-file>
+
+use datafusion::common::Result;
+use datafusion_ext_plans::shuffle::spill_trace::decode_spill_trace;
+
+fn main() -> Result<()> {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("usage: decode_spill_trace <trace-file>");
+        std::process::exit(1);
+    };
+    for record in decode_spill_trace(&std::fs::read(path)?)? {
+        println!("{record}");
+    }
+    Ok(())
+}
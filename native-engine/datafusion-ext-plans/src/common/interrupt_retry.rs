@@ -0,0 +1,110 @@
+// Licensed to the Apache Software Foundation (ASF) under one or more
+// contributor license agreements.  See the NOTICE file distributed with
+// this work for additional information regarding copyright ownership.
+// The ASF licenses this file to You under the Apache License, Version 2.0
+// (the "License"); you may not use this file except in compliance with
+// the License.  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::{ErrorKind, Read, Result, Write};
+
+/// Wraps a reader/writer and retries calls interrupted by signals (EINTR),
+/// which are benign but surface as errors on some runtimes. each call is
+/// retried at most `max_retries` times.
+pub struct InterruptRetry<T> {
+    inner: T,
+    max_retries: usize,
+}
+
+impl<T> InterruptRetry<T> {
+    pub fn new(inner: T, max_retries: usize) -> Self {
+        Self { inner, max_retries }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn retry<R>(&mut self, mut f: impl FnMut(&mut T) -> Result<R>) -> Result<R> {
+        let mut num_retries = 0;
+        loop {
+            match f(&mut self.inner) {
+                Err(e) if e.kind() == ErrorKind::Interrupted && num_retries < self.max_retries => {
+                    num_retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for InterruptRetry<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.retry(|inner| inner.read(buf))
+    }
+}
+
+impl<W: Write> Write for InterruptRetry<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.retry(|inner| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.retry(|inner| inner.flush())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Error, ErrorKind, Result, Write};
+
+    use super::*;
+
+    // returns EINTR on the first call of write and flush
+    #[derive(Default)]
+    struct InterruptOnceWriter {
+        data: Vec<u8>,
+        write_interrupted: bool,
+        flush_interrupted: bool,
+    }
+
+    impl Write for InterruptOnceWriter {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            if !std::mem::replace(&mut self.write_interrupted, true) {
+                return Err(Error::from(ErrorKind::Interrupted));
+            }
+            self.data.write(buf)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            if !std::mem::replace(&mut self.flush_interrupted, true) {
+                return Err(Error::from(ErrorKind::Interrupted));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_interrupt_retry() -> Result<()> {
+        let mut w = InterruptRetry::new(InterruptOnceWriter::default(), 3);
+        assert_eq!(w.write(b"hello")?, 5);
+        w.flush()?;
+        assert_eq!(w.inner().data, b"hello");
+
+        // no retry
+        let mut w = InterruptRetry::new(InterruptOnceWriter::default(), 0);
+        assert_eq!(w.flush().unwrap_err().kind(), ErrorKind::Interrupted);
+        Ok(())
+    }
+}
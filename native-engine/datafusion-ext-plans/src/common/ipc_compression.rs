@@ -15,7 +15,7 @@
 // specific language governing permissions and limitations
 // under the License.
 
-use std::io::{BufReader, Read, Take, Write};
+use std::io::{BufReader, Chain, Cursor, Read, Take, Write};
 
 use arrow::{array::ArrayRef, datatypes::SchemaRef};
 use auron_jni_bridge::{
@@ -27,35 +27,40 @@ use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use datafusion::common::Result;
 use datafusion_ext_commons::{
     df_execution_err,
-    io::{read_one_batch, write_one_batch},
+    io::{BatchFormat, read_one_batch_with_format, write_one_batch_with_format},
 };
 use once_cell::sync::OnceCell;
 
+use crate::common::zstd_seekable::ZstdSeekableWriter;
+
 pub struct IpcCompressionWriter<W: Write> {
     output: W,
     shared_buf: VecBuffer,
-    block_writer: IoCompressionWriter<VecBufferWrite>,
-    block_empty: bool,
+    block_writer: Option<IoCompressionWriter<VecBufferWrite>>, // None if current block is empty
+    batch_format: BatchFormat,
 }
 unsafe impl<W: Write> Send for IpcCompressionWriter<W> {}
 
 impl<W: Write> IpcCompressionWriter<W> {
     pub fn new(output: W) -> Self {
-        let mut shared_buf = VecBuffer::default();
-        shared_buf.inner_mut().extend_from_slice(&[0u8; 4]);
-
-        let block_writer = IoCompressionWriter::new_with_configured_codec(shared_buf.writer());
         Self {
             output,
-            shared_buf,
-            block_writer,
-            block_empty: true,
+            shared_buf: VecBuffer::default(),
+            block_writer: None,
+            batch_format: configured_batch_format(),
         }
     }
 
+    /// overrides the configured format of serialized batches, readers must
+    /// use the same format
+    pub fn with_batch_format(mut self, batch_format: BatchFormat) -> Self {
+        self.batch_format = batch_format;
+        self
+    }
+
     pub fn set_output(&mut self, output: W) {
         assert!(
-            self.block_empty,
+            self.block_writer.is_none(),
             "IpcCompressionWriter must be empty while changing output"
         );
         self.output = output;
@@ -65,9 +70,49 @@ impl<W: Write> IpcCompressionWriter<W> {
         if num_rows == 0 {
             return Ok(());
         }
-        write_one_batch(num_rows, cols, &mut self.block_writer)?;
-        self.block_empty = false;
+        let batch_format = self.batch_format;
+        let block_writer = self.open_block_writer()?;
+        write_one_batch_with_format(num_rows, cols, block_writer, batch_format)?;
+        self.finish_current_buf_if_full()
+    }
+
+    /// serializes a batch into buf without writing it, so that callers can
+    /// inspect the serialized size before writing it with
+    /// [`Self::write_serialized_batch`]
+    pub fn serialize_batch(
+        &self,
+        num_rows: usize,
+        cols: &[ArrayRef],
+        buf: &mut Vec<u8>,
+    ) -> Result<()> {
+        if num_rows == 0 {
+            return Ok(());
+        }
+        write_one_batch_with_format(num_rows, cols, buf, self.batch_format)
+    }
+
+    pub fn write_serialized_batch(&mut self, serialized: &[u8]) -> Result<()> {
+        if serialized.is_empty() {
+            return Ok(());
+        }
+        self.open_block_writer()?.write_all(serialized)?;
+        self.finish_current_buf_if_full()
+    }
 
+    fn open_block_writer(&mut self) -> Result<&mut IoCompressionWriter<VecBufferWrite>> {
+        let block_writer = match self.block_writer.take() {
+            Some(block_writer) => block_writer,
+            None => {
+                // open next buf
+                self.shared_buf.inner_mut().clear();
+                self.shared_buf.inner_mut().extend_from_slice(&[0u8; 4]);
+                IoCompressionWriter::try_new(io_compression_codec(), self.shared_buf.writer())?
+            }
+        };
+        Ok(self.block_writer.insert(block_writer))
+    }
+
+    fn finish_current_buf_if_full(&mut self) -> Result<()> {
         let buf_len = self.shared_buf.inner().len();
         if buf_len as f64
             >= conf::SHUFFLE_COMPRESSION_TARGET_BUF_SIZE
@@ -81,9 +126,9 @@ impl<W: Write> IpcCompressionWriter<W> {
     }
 
     pub fn finish_current_buf(&mut self) -> Result<()> {
-        if !self.block_empty {
+        if let Some(mut block_writer) = self.block_writer.take() {
             // finish current buf
-            self.block_writer.finish_internal()?;
+            block_writer.finish_internal()?;
 
             // write
             let block_len = self.shared_buf.inner().len() - 4;
@@ -91,13 +136,6 @@ impl<W: Write> IpcCompressionWriter<W> {
                 .as_mut()
                 .write_u32::<LittleEndian>(block_len as u32)?;
             self.output.write_all(self.shared_buf.inner())?;
-
-            // open next buf
-            self.shared_buf.inner_mut().clear();
-            self.shared_buf.inner_mut().extend_from_slice(&[0u8; 4]);
-            self.block_writer =
-                IoCompressionWriter::new_with_configured_codec(self.shared_buf.writer());
-            self.block_empty = true;
         }
         Ok(())
     }
@@ -113,6 +151,7 @@ impl<W: Write> IpcCompressionWriter<W> {
 
 pub struct IpcCompressionReader<R: Read + 'static> {
     input: InputState<R>,
+    batch_format: BatchFormat,
 }
 unsafe impl<R: Read> Send for IpcCompressionReader<R> {}
 
@@ -122,15 +161,22 @@ enum InputState<R: Read + 'static> {
     Unreachable,
     BlockStart(R),
     BlockContent(IoCompressionReader<Take<R>>),
+    ZstdStream(Box<zstd::Decoder<'static, BufReader<Chain<Cursor<[u8; 4]>, R>>>>),
 }
 
 impl<R: Read> IpcCompressionReader<R> {
     pub fn new(input: R) -> Self {
         Self {
             input: InputState::BlockStart(input),
+            batch_format: configured_batch_format(),
         }
     }
 
+    pub fn with_batch_format(mut self, batch_format: BatchFormat) -> Self {
+        self.batch_format = batch_format;
+        self
+    }
+
     pub fn read_batch(&mut self, schema: &SchemaRef) -> Result<Option<(usize, Vec<ArrayRef>)>> {
         struct Reader<'a, R: Read + 'static>(&'a mut IpcCompressionReader<R>);
         impl<'a, R: Read> Read for Reader<'a, R> {
@@ -146,6 +192,13 @@ impl<R: Read> IpcCompressionReader<R> {
                                 return Err(err);
                             }
                         };
+                        if block_len == ZSTD_MAGIC_NUMBER {
+                            // unframed zstd stream, see transcode_to_zstd_seekable()
+                            let magic = Cursor::new(ZSTD_MAGIC_NUMBER.to_le_bytes());
+                            let decoder = zstd::Decoder::new(magic.chain(input))?;
+                            self.0.input = InputState::ZstdStream(Box::new(decoder));
+                            return self.read(buf);
+                        }
                         let taken = input.take(block_len as u64);
 
                         self.0.input = InputState::BlockContent(IoCompressionReader::try_new(
@@ -166,12 +219,51 @@ impl<R: Read> IpcCompressionReader<R> {
                         }
                         Err(err) => Err(err),
                     },
+                    InputState::ZstdStream(mut decoder) => {
+                        let read_result = decoder.read(buf);
+                        self.0.input = InputState::ZstdStream(decoder);
+                        read_result
+                    }
                     _ => unreachable!(),
                 }
             }
         }
-        read_one_batch(&mut Reader(self), schema)
+        let batch_format = self.batch_format;
+        read_one_batch_with_format(&mut Reader(self), schema, batch_format)
+    }
+}
+
+// zstd frames start with this magic number, which never collides with a block
+// length because blocks are limited to a few MBs
+const ZSTD_MAGIC_NUMBER: u32 = 0xFD2FB528;
+
+/// decompresses blocks written by [`IpcCompressionWriter`] and writes their
+/// content as a single zstd seekable stream without block framing, see
+/// [`crate::common::zstd_seekable`]. the output is readable by
+/// [`IpcCompressionReader`] as well as by any zstd decoder.
+pub fn transcode_to_zstd_seekable<R: Read, W: Write>(
+    mut input: R,
+    output: W,
+    max_frame_size: usize,
+) -> Result<W> {
+    let mut writer = ZstdSeekableWriter::try_new(
+        output,
+        conf::SPARK_IO_COMPRESSION_ZSTD_LEVEL.value().unwrap_or(1),
+        max_frame_size,
+    )?;
+    loop {
+        let block_len = match input.read_u32::<LittleEndian>() {
+            Ok(block_len) => block_len,
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        };
+        let mut block_reader =
+            IoCompressionReader::try_new(io_compression_codec(), input.take(block_len as u64))?;
+        std::io::copy(&mut block_reader, &mut writer)?;
+        input = block_reader.finish_into_inner()?.into_inner();
     }
+    writer.finish()?;
+    Ok(writer.into_inner())
 }
 
 pub enum IoCompressionWriter<W: Write> {
@@ -278,6 +370,19 @@ fn io_compression_codec() -> &'static str {
         .as_str()
 }
 
+fn configured_batch_format() -> BatchFormat {
+    static BATCH_FORMAT: OnceCell<BatchFormat> = OnceCell::new();
+    *BATCH_FORMAT
+        .get_or_try_init(|| {
+            if is_jni_bridge_inited() {
+                BatchFormat::try_from_name(&conf::SHUFFLE_BATCH_FORMAT.value()?)
+            } else {
+                Ok(BatchFormat::Native) // for testing
+            }
+        })
+        .expect("error reading spark.auron.shuffle.batchFormat")
+}
+
 #[derive(Default)]
 struct VecBuffer {
     vec: Box<Vec<u8>>,
@@ -349,4 +454,69 @@ mod tests {
         assert!(reader.read_batch(&schema)?.is_none());
         Ok(())
     }
+    #[test]
+    fn test_ipc_compression_with_batch_format() -> Result<(), Box<dyn Error>> {
+        let test_array: ArrayRef = Arc::new(StringArray::from(vec![Some("hello"), None]));
+        let schema = Arc::new(Schema::new(vec![Field::new("", DataType::Utf8, true)]));
+
+        let mut buf = vec![];
+        let mut writer =
+            IpcCompressionWriter::new(&mut buf).with_batch_format(BatchFormat::ArrowIpcV4);
+        writer.write_batch(2, &[test_array.clone()])?;
+        writer.finish_current_buf()?;
+
+        let mut reader = IpcCompressionReader::new(Cursor::new(buf.clone()))
+            .with_batch_format(BatchFormat::ArrowIpcV4);
+        let (num_rows, arrays) = reader.read_batch(&schema)?.unwrap();
+        assert_eq!(num_rows, 2);
+        assert_eq!(arrays, &[test_array]);
+        assert!(reader.read_batch(&schema)?.is_none());
+
+        // readers pinned to another version fail
+        let mut reader =
+            IpcCompressionReader::new(Cursor::new(buf)).with_batch_format(BatchFormat::ArrowIpcV5);
+        assert!(reader.read_batch(&schema).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_transcode_to_zstd_seekable() -> Result<(), Box<dyn Error>> {
+        let test_array1: ArrayRef = Arc::new(StringArray::from(vec![Some("hello"), Some("world")]));
+        let test_array2: ArrayRef = Arc::new(StringArray::from(vec![Some("foo"), Some("bar")]));
+        let schema = Arc::new(Schema::new(vec![Field::new("", DataType::Utf8, false)]));
+
+        let mut buf = vec![];
+        let mut writer = IpcCompressionWriter::new(&mut buf);
+        writer.write_batch(2, &[test_array1.clone()])?;
+        writer.finish_current_buf()?;
+        writer.write_batch(2, &[test_array2.clone()])?;
+        writer.finish_current_buf()?;
+
+        let transcoded = transcode_to_zstd_seekable(Cursor::new(&buf), vec![], 16)?;
+        assert_eq!(&transcoded[0..4], &ZSTD_MAGIC_NUMBER.to_le_bytes());
+
+        // unframed zstd streams are detected by the reader
+        let mut reader = IpcCompressionReader::new(Cursor::new(transcoded.clone()));
+        let (num_rows1, arrays1) = reader.read_batch(&schema)?.unwrap();
+        assert_eq!(num_rows1, 2);
+        assert_eq!(arrays1, &[test_array1]);
+        let (num_rows2, arrays2) = reader.read_batch(&schema)?.unwrap();
+        assert_eq!(num_rows2, 2);
+        assert_eq!(arrays2, &[test_array2]);
+        assert!(reader.read_batch(&schema)?.is_none());
+
+        // and by the plain zstd decoder
+        let mut expected = vec![];
+        let mut reader = Cursor::new(&buf);
+        while let Ok(block_len) = reader.read_u32::<LittleEndian>() {
+            let mut block_reader =
+                IoCompressionReader::try_new("lz4", (&mut reader).take(block_len as u64))?;
+            block_reader.read_to_end(&mut expected)?;
+        }
+        assert_eq!(
+            zstd::stream::decode_all(Cursor::new(&transcoded))?,
+            expected
+        );
+        Ok(())
+    }
 }
@@ -16,9 +16,11 @@
 pub mod cached_exprs_evaluator;
 pub mod column_pruning;
 pub mod execution_context;
+pub mod interrupt_retry;
 pub mod ipc_compression;
 pub mod key_rows_output;
 pub mod offsetted;
 pub mod row_null_checker;
 pub mod stream_exec;
 pub mod timer_helper;
+pub mod zstd_seekable;
@@ -125,6 +125,15 @@ impl<'a, O: PrimInt + 'a, T: 'a> OffsettedMergeIterator<'a, O, T> {
         self.cursors.peek().cur
     }
 
+    /// number of cursors which still have partitions to merge
+    pub fn num_active_cursors(&self) -> usize {
+        self.cursors
+            .values()
+            .iter()
+            .filter(|cursor| cursor.cur < self.num_partitions)
+            .count()
+    }
+
     pub fn merged_offsets(&self) -> &[O] {
         &self.merged_offsets
     }
@@ -0,0 +1,284 @@
+// Licensed to the Apache Software Foundation (ASF) under one or more
+// contributor license agreements.  See the NOTICE file distributed with
+// this work for additional information regarding copyright ownership.
+// The ASF licenses this file to You under the Apache License, Version 2.0
+// (the "License"); you may not use this file except in compliance with
+// the License.  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Zstd seekable format, see zstd/contrib/seekable_format in the zstd repo.
+//! Data is compressed into independent zstd frames of a fixed decompressed
+//! size, followed by a seek table in a skippable frame. The output is still a
+//! valid zstd stream, so ordinary zstd decoders read it sequentially, while
+//! [`ZstdSeekableReader`] decompresses only the frames covering a range.
+
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+const SKIPPABLE_MAGIC_NUMBER: u32 = 0x184D2A5E;
+const SEEKABLE_MAGIC_NUMBER: u32 = 0x8F92EAB1;
+const SEEK_TABLE_FOOTER_SIZE: u64 = 9;
+const SEEK_TABLE_CHECKSUM_FLAG: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeekTableEntry {
+    pub compressed_size: u32,
+    pub decompressed_size: u32,
+}
+
+pub struct ZstdSeekableWriter<W: Write> {
+    inner: W,
+    compressor: zstd::bulk::Compressor<'static>,
+    max_frame_size: usize,
+    frame_buf: Vec<u8>,
+    seek_table: Vec<SeekTableEntry>,
+}
+
+impl<W: Write> ZstdSeekableWriter<W> {
+    pub fn try_new(inner: W, level: i32, max_frame_size: usize) -> Result<Self> {
+        Ok(Self {
+            inner,
+            compressor: zstd::bulk::Compressor::new(level)?,
+            max_frame_size: max_frame_size.max(1),
+            frame_buf: vec![],
+            seek_table: vec![],
+        })
+    }
+
+    /// compresses pending data and writes the seek table, no more data can be
+    /// written after finishing
+    pub fn finish(&mut self) -> Result<()> {
+        self.write_frame()?;
+
+        let num_frames = self.seek_table.len();
+        let seek_table_size = num_frames as u64 * 8 + SEEK_TABLE_FOOTER_SIZE;
+        self.inner
+            .write_u32::<LittleEndian>(SKIPPABLE_MAGIC_NUMBER)?;
+        self.inner
+            .write_u32::<LittleEndian>(seek_table_size as u32)?;
+        for entry in std::mem::take(&mut self.seek_table) {
+            self.inner
+                .write_u32::<LittleEndian>(entry.compressed_size)?;
+            self.inner
+                .write_u32::<LittleEndian>(entry.decompressed_size)?;
+        }
+        self.inner.write_u32::<LittleEndian>(num_frames as u32)?;
+        self.inner.write_u8(0)?; // no checksums
+        self.inner
+            .write_u32::<LittleEndian>(SEEKABLE_MAGIC_NUMBER)?;
+        self.inner.flush()
+    }
+
+    pub fn inner(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn write_frame(&mut self) -> Result<()> {
+        if self.frame_buf.is_empty() {
+            return Ok(());
+        }
+        let frame = self.compressor.compress(&self.frame_buf)?;
+        self.inner.write_all(&frame)?;
+        self.seek_table.push(SeekTableEntry {
+            compressed_size: frame.len() as u32,
+            decompressed_size: self.frame_buf.len() as u32,
+        });
+        self.frame_buf.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ZstdSeekableWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = buf.len().min(self.max_frame_size - self.frame_buf.len());
+        self.frame_buf.extend_from_slice(&buf[..len]);
+        if self.frame_buf.len() == self.max_frame_size {
+            self.write_frame()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Random access reader of zstd seekable format data.
+pub struct ZstdSeekableReader<R: Read + Seek> {
+    input: R,
+    decompressor: zstd::bulk::Decompressor<'static>,
+    seek_table: Vec<SeekTableEntry>,
+    compressed_offsets: Vec<u64>,
+    decompressed_offsets: Vec<u64>,
+    num_decoded_frames: usize,
+}
+
+impl<R: Read + Seek> ZstdSeekableReader<R> {
+    pub fn try_new(mut input: R) -> Result<Self> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_owned());
+
+        // read footer
+        let len = input.seek(SeekFrom::End(0))?;
+        if len < SEEK_TABLE_FOOTER_SIZE + 8 {
+            return Err(invalid("zstd seekable data too short"));
+        }
+        input.seek(SeekFrom::Start(len - SEEK_TABLE_FOOTER_SIZE))?;
+        let num_frames = input.read_u32::<LittleEndian>()? as u64;
+        let descriptor = input.read_u8()?;
+        if input.read_u32::<LittleEndian>()? != SEEKABLE_MAGIC_NUMBER {
+            return Err(invalid("missing zstd seekable magic number"));
+        }
+        let entry_size = match descriptor & SEEK_TABLE_CHECKSUM_FLAG {
+            0 => 8,
+            _ => 12,
+        };
+
+        // read seek table
+        let seek_table_size = num_frames * entry_size + SEEK_TABLE_FOOTER_SIZE;
+        if len < seek_table_size + 8 {
+            return Err(invalid("zstd seek table exceeds data"));
+        }
+        let seek_table_start = len - seek_table_size - 8;
+        input.seek(SeekFrom::Start(seek_table_start))?;
+        if input.read_u32::<LittleEndian>()? != SKIPPABLE_MAGIC_NUMBER
+            || input.read_u32::<LittleEndian>()? as u64 != seek_table_size
+        {
+            return Err(invalid("invalid zstd seek table frame header"));
+        }
+        let mut seek_table = Vec::with_capacity(num_frames as usize);
+        let mut compressed_offsets = vec![0];
+        let mut decompressed_offsets = vec![0];
+        for _ in 0..num_frames {
+            let entry = SeekTableEntry {
+                compressed_size: input.read_u32::<LittleEndian>()?,
+                decompressed_size: input.read_u32::<LittleEndian>()?,
+            };
+            if entry_size == 12 {
+                input.read_u32::<LittleEndian>()?; // checksums are not verified
+            }
+            compressed_offsets
+                .push(compressed_offsets.last().unwrap() + entry.compressed_size as u64);
+            decompressed_offsets
+                .push(decompressed_offsets.last().unwrap() + entry.decompressed_size as u64);
+            seek_table.push(entry);
+        }
+        if *compressed_offsets.last().unwrap() != seek_table_start {
+            return Err(invalid("zstd seek table mismatches frames"));
+        }
+
+        Ok(Self {
+            input,
+            decompressor: zstd::bulk::Decompressor::new()?,
+            seek_table,
+            compressed_offsets,
+            decompressed_offsets,
+            num_decoded_frames: 0,
+        })
+    }
+
+    pub fn seek_table(&self) -> &[SeekTableEntry] {
+        &self.seek_table
+    }
+
+    pub fn decompressed_size(&self) -> u64 {
+        *self.decompressed_offsets.last().unwrap()
+    }
+
+    /// number of frames decompressed since creation
+    pub fn num_decoded_frames(&self) -> usize {
+        self.num_decoded_frames
+    }
+
+    /// decompresses `len` bytes starting at decompressed offset `offset`,
+    /// only frames overlapping the range are decompressed
+    pub fn read_range(&mut self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let end = offset + len as u64;
+        if end > self.decompressed_size() {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!(
+                    "range {offset}..{end} exceeds decompressed size {}",
+                    self.decompressed_size()
+                ),
+            ));
+        }
+
+        let mut output = Vec::with_capacity(len);
+        let mut frame_idx = self.decompressed_offsets.partition_point(|&o| o <= offset) - 1;
+        while output.len() < len {
+            let entry = self.seek_table[frame_idx];
+            let mut frame = vec![0; entry.compressed_size as usize];
+            self.input
+                .seek(SeekFrom::Start(self.compressed_offsets[frame_idx]))?;
+            self.input.read_exact(&mut frame)?;
+            let decompressed = self
+                .decompressor
+                .decompress(&frame, entry.decompressed_size as usize)?;
+            self.num_decoded_frames += 1;
+
+            let frame_start = self.decompressed_offsets[frame_idx];
+            let from = (offset.max(frame_start) - frame_start) as usize;
+            let to = decompressed.len().min((end - frame_start) as usize);
+            output.extend_from_slice(&decompressed[from..to]);
+            frame_idx += 1;
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| format!("{:08}", i / 8 * 31 % 1000).as_bytes()[i % 8])
+            .collect()
+    }
+
+    #[test]
+    fn test_zstd_seekable() -> Result<()> {
+        let data = test_data(1000000);
+        let mut writer = ZstdSeekableWriter::try_new(vec![], 1, 65536)?;
+        for chunk in data.chunks(10000) {
+            writer.write_all(chunk)?;
+        }
+        writer.finish()?;
+        let compressed = writer.into_inner();
+
+        // ordinary zstd decoders read the whole stream, skipping the seek table
+        assert_eq!(zstd::stream::decode_all(&compressed[..])?, data);
+
+        // decompress a middle portion, only frames 7..=9 are decoded
+        let mut reader = ZstdSeekableReader::try_new(Cursor::new(&compressed))?;
+        assert_eq!(reader.seek_table().len(), 16);
+        assert_eq!(reader.decompressed_size(), data.len() as u64);
+        assert_eq!(reader.read_range(500000, 100000)?, &data[500000..600000]);
+        assert_eq!(reader.num_decoded_frames(), 3);
+
+        // ranges within a single frame and at the end
+        assert_eq!(reader.read_range(65536, 10)?, &data[65536..65546]);
+        assert_eq!(reader.read_range(999990, 10)?, &data[999990..]);
+        assert_eq!(reader.read_range(0, 0)?, Vec::<u8>::new());
+        assert_eq!(reader.num_decoded_frames(), 5);
+        assert!(reader.read_range(999990, 11).is_err());
+
+        // truncated data is rejected
+        assert!(ZstdSeekableReader::try_new(Cursor::new(&compressed[..100])).is_err());
+        Ok(())
+    }
+}
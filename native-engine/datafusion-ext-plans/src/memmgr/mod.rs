@@ -27,17 +27,23 @@ use bytesize::ByteSize;
 use datafusion::common::Result;
 use once_cell::sync::OnceCell;
 use parking_lot::{Condvar, Mutex};
+use tokio::sync::{Semaphore, SemaphorePermit};
 
 static MEM_MANAGER: OnceCell<Arc<MemManager>> = OnceCell::new();
 
 // never triggers waiting/spilling for consumers which use very little memory
 const MIN_TRIGGER_SIZE: usize = 1 << 24; // 16MB
 
+// number of permits that the memory budget is divided into for bounding
+// in-flight memory growing
+const NUM_GROW_PERMITS: usize = 1024;
+
 pub struct MemManager {
     total: usize,
     consumers: Mutex<Vec<Arc<MemConsumerInfo>>>,
     status: Mutex<MemManagerStatus>,
     cv: Condvar,
+    grow_permits: GrowPermits,
 }
 
 impl MemManager {
@@ -53,6 +59,7 @@ impl MemManager {
                 consumers: Mutex::default(),
                 status: Mutex::default(),
                 cv: Condvar::default(),
+                grow_permits: GrowPermits::new(total),
             })
         });
     }
@@ -69,6 +76,13 @@ impl MemManager {
         self.consumers.lock().len()
     }
 
+    /// acquires permits for growing memory usage by the given size. concurrent
+    /// callers holding permits never sum up to more than the total memory, so
+    /// that they cannot over-commit before the memory manager reacts.
+    pub async fn acquire_grow_permit(&self, size: usize) -> SemaphorePermit<'_> {
+        self.grow_permits.acquire(size).await
+    }
+
     pub fn total_used(&self) -> usize {
         self.status.lock().total_used
     }
@@ -196,6 +210,33 @@ struct MemConsumerStatus {
     spillable: bool,
 }
 
+struct GrowPermits {
+    semaphore: Semaphore,
+    num_permits: usize,
+    permit_size: usize,
+}
+
+impl GrowPermits {
+    fn new(total: usize) -> Self {
+        let permit_size = (total / NUM_GROW_PERMITS).max(1);
+        let num_permits = (total / permit_size).max(1);
+        Self {
+            semaphore: Semaphore::new(num_permits),
+            num_permits,
+            permit_size,
+        }
+    }
+
+    async fn acquire(&self, size: usize) -> SemaphorePermit<'_> {
+        // requests larger than the total memory take all permits
+        let permits = size.div_ceil(self.permit_size).clamp(1, self.num_permits);
+        self.semaphore
+            .acquire_many(permits as u32)
+            .await
+            .expect("grow permits semaphore closed")
+    }
+}
+
 #[async_trait]
 pub trait MemConsumer: Send + Sync {
     fn name(&self) -> &str;
@@ -455,3 +496,53 @@ fn get_proc_memory_used() -> usize {
     }
     get_vmrss_used()
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering::SeqCst},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_grow_permits_bound_concurrent_inserts() {
+        let total = 100 << 20;
+        let insert_size = 30 << 20;
+        let grow_permits = Arc::new(GrowPermits::new(total));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak_in_flight = Arc::new(AtomicUsize::new(0));
+
+        let handles = (0..64)
+            .map(|_| {
+                let grow_permits = grow_permits.clone();
+                let in_flight = in_flight.clone();
+                let peak_in_flight = peak_in_flight.clone();
+                tokio::spawn(async move {
+                    let _permit = grow_permits.acquire(insert_size).await;
+                    let cur = in_flight.fetch_add(insert_size, SeqCst) + insert_size;
+                    peak_in_flight.fetch_max(cur, SeqCst);
+                    tokio::task::yield_now().await;
+                    in_flight.fetch_sub(insert_size, SeqCst);
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        let peak = peak_in_flight.load(SeqCst);
+        assert!(peak >= insert_size);
+        assert!(
+            peak <= total,
+            "peak in-flight grow {peak} exceeds total {total}"
+        );
+        assert_eq!(grow_permits.semaphore.available_permits(), NUM_GROW_PERMITS);
+
+        // oversized requests are capped to the whole budget rather than blocking
+        // forever
+        let _permit = grow_permits.acquire(total * 2).await;
+        assert_eq!(grow_permits.semaphore.available_permits(), 0);
+    }
+}
@@ -18,8 +18,9 @@ use std::{
     fs,
     fs::{File, OpenOptions},
     io::{BufReader, BufWriter, Cursor, Read, Seek, Write},
+    path::Path,
     sync::Arc,
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
 use auron_jni_bridge::{
@@ -88,6 +89,33 @@ fn spill_compression_codec() -> &'static str {
         .as_str()
 }
 
+/// Extension of spill file names, e.g. `.blaze-spill`, so that lifecycle
+/// policies and tooling can classify them. empty by default.
+fn spill_file_extension() -> &'static str {
+    static EXTENSION: OnceCell<String> = OnceCell::new();
+    EXTENSION
+        .get_or_try_init(|| {
+            if is_jni_bridge_inited() {
+                conf::SPILL_FILE_EXTENSION
+                    .value()
+                    .map(|ext| normalize_file_extension(&ext))
+            } else {
+                Ok(String::new()) // for testing
+            }
+        })
+        .expect("error reading spark.auron.spill.fileExtension")
+        .as_str()
+}
+
+pub(crate) fn normalize_file_extension(ext: &str) -> String {
+    let ext = ext.trim();
+    if ext.is_empty() || ext.starts_with('.') {
+        ext.to_string()
+    } else {
+        format!(".{ext}")
+    }
+}
+
 pub fn try_new_spill(spill_metrics: &SpillMetrics) -> Result<Box<dyn Spill>> {
     if !is_jni_bridge_inited() || jni_call_static!(JniBridge.isDriverSide() -> bool)? {
         Ok(Box::new(FileSpill::try_new(spill_metrics)?))
@@ -102,17 +130,95 @@ pub fn try_new_spill(spill_metrics: &SpillMetrics) -> Result<Box<dyn Spill>> {
     }
 }
 
+/// Prefix of spill file names. spill files are created in the directories of
+/// spark's DiskBlockManager, but renamed from spark's `temp_local_` prefix so
+/// that they can be told apart from spark's own temp files.
+pub const SPILL_FILE_PREFIX: &str = "blaze-spill-";
+
+const SPARK_TEMP_LOCAL_PREFIX: &str = "temp_local_";
+
+/// returns spill file path for a temp local block path allocated by spark
+fn spill_file_path(spark_temp_local_file: &str, extension: &str) -> String {
+    let path = Path::new(spark_temp_local_file);
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let id = file_name
+        .strip_prefix(SPARK_TEMP_LOCAL_PREFIX)
+        .unwrap_or(&file_name);
+    path.with_file_name(format!("{SPILL_FILE_PREFIX}{id}{extension}"))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Removes spill files older than `max_age` in `dir` and its sub-directories,
+/// returns the number of removed files. should be called by the host at
+/// executor startup, to clean up spill files left by crashed executors.
+/// only files named with [`SPILL_FILE_PREFIX`] are touched. unreadable
+/// sub-directories are logged and skipped.
+pub fn cleanup_orphaned_spills<P: AsRef<Path>>(dir: P, max_age: Duration) -> Result<usize> {
+    let now = SystemTime::now();
+    let mut num_removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            // the sub-directory may be unreadable or removed by others, skip it
+            let sub_dir = entry.path();
+            match cleanup_orphaned_spills(&sub_dir, max_age) {
+                Ok(num_removed_in_sub_dir) => num_removed += num_removed_in_sub_dir,
+                Err(e) => warn!(
+                    "Was unable to clean up orphaned spill files in: {}. error: {}",
+                    sub_dir.display(),
+                    e
+                ),
+            }
+            continue;
+        }
+        let is_spill_file = entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(SPILL_FILE_PREFIX);
+        if !file_type.is_file() || !is_spill_file {
+            continue;
+        }
+
+        // the file may have been removed by others, skip it
+        let Ok(modified) = entry.metadata().and_then(|metadata| metadata.modified()) else {
+            continue;
+        };
+        if now.duration_since(modified).unwrap_or_default() > max_age {
+            let file_path = entry.path();
+            match fs::remove_file(&file_path) {
+                Ok(()) => num_removed += 1,
+                Err(e) => warn!(
+                    "Was unable to delete orphaned spill file: {}. error: {}",
+                    file_path.display(),
+                    e
+                ),
+            }
+        }
+    }
+    Ok(num_removed)
+}
+
 /// A spill structure which write data to temporary files
 /// used in driver side or executor side with on-heap memory is full
 struct FileSpill(File, SpillMetrics, Option<String>);
 impl FileSpill {
     fn try_new(spill_metrics: &SpillMetrics) -> Result<Self> {
+        Self::try_new_with_extension(spill_metrics, spill_file_extension())
+    }
+
+    fn try_new_with_extension(spill_metrics: &SpillMetrics, extension: &str) -> Result<Self> {
         if is_jni_bridge_inited() {
-            let file_name = jni_get_string!(
+            let spark_file_name = jni_get_string!(
                 jni_call_static!(JniBridge.getDirectWriteSpillToDiskFile() -> JObject)?
                     .as_obj()
                     .into()
             )?;
+            let file_name = spill_file_path(&spark_file_name, extension);
             let file = OpenOptions::new() // create file and open under rw mode
                 .create(true)
                 .truncate(true)
@@ -120,9 +226,19 @@ impl FileSpill {
                 .read(true)
                 .open(&file_name)?;
             Ok(Self(file, spill_metrics.clone(), Some(file_name)))
-        } else {
+        } else if extension.is_empty() {
             let file = tempfile::tempfile()?;
             Ok(Self(file, spill_metrics.clone(), None))
+        } else {
+            // named temp file, removed on drop
+            let (file, file_path) = tempfile::Builder::new()
+                .prefix(SPILL_FILE_PREFIX)
+                .suffix(extension)
+                .tempfile()?
+                .keep()
+                .map_err(|e| e.error)?;
+            let file_name = file_path.to_string_lossy().to_string();
+            Ok(Self(file, spill_metrics.clone(), Some(file_name)))
         }
     }
 }
@@ -327,3 +443,96 @@ impl<'a> OwnedSpillBufReader<'a> {
         &mut self.buf_reader
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs::File,
+        time::{Duration, SystemTime},
+    };
+
+    use datafusion::{common::Result, physical_plan::metrics::ExecutionPlanMetricsSet};
+
+    use super::*;
+
+    #[test]
+    fn test_cleanup_orphaned_spills() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sub_dir = dir.path().join("0c");
+        fs::create_dir(&sub_dir)?;
+
+        let old_time = SystemTime::now() - Duration::from_secs(7200);
+        let create_file = |path: &Path, old: bool| -> Result<()> {
+            let file = File::create(path)?;
+            if old {
+                file.set_modified(old_time)?;
+            }
+            Ok(())
+        };
+        let old_spill = dir.path().join("blaze-spill-old");
+        let old_spill_in_sub_dir = sub_dir.join("blaze-spill-old");
+        let new_spill = dir.path().join("blaze-spill-new");
+        let old_other = dir.path().join("shuffle_0_0_0.data");
+        let new_other = sub_dir.join("shuffle_0_1_0.data");
+        let old_spark_temp = sub_dir.join("temp_local_0f1e2d3c");
+        create_file(&old_spill, true)?;
+        create_file(&old_spill_in_sub_dir, true)?;
+        create_file(&new_spill, false)?;
+        create_file(&old_other, true)?;
+        create_file(&new_other, false)?;
+        create_file(&old_spark_temp, true)?;
+
+        let num_removed = cleanup_orphaned_spills(dir.path(), Duration::from_secs(3600))?;
+        assert_eq!(num_removed, 2);
+        assert!(!old_spill.exists());
+        assert!(!old_spill_in_sub_dir.exists());
+        assert!(new_spill.exists());
+        assert!(old_other.exists());
+        assert!(new_other.exists());
+        assert!(old_spark_temp.exists()); // spark's own temp files are never touched
+        Ok(())
+    }
+
+    #[test]
+    fn test_spill_file_path() {
+        assert_eq!(
+            spill_file_path("/tmp/blockmgr-1/0c/temp_local_0f1e2d3c", ""),
+            "/tmp/blockmgr-1/0c/blaze-spill-0f1e2d3c",
+        );
+        assert_eq!(
+            spill_file_path("/tmp/blockmgr-1/0c/temp_local_0f1e2d3c", ".spill"),
+            "/tmp/blockmgr-1/0c/blaze-spill-0f1e2d3c.spill",
+        );
+    }
+
+    #[test]
+    fn test_spill_file_extension() -> Result<()> {
+        assert_eq!(normalize_file_extension("blaze-spill"), ".blaze-spill");
+        assert_eq!(normalize_file_extension(".blaze-spill"), ".blaze-spill");
+        assert_eq!(normalize_file_extension(""), "");
+
+        let spill_metrics = SpillMetrics::new(&ExecutionPlanMetricsSet::new(), 0);
+        let mut spill = FileSpill::try_new_with_extension(&spill_metrics, ".blaze-spill")?;
+        let file_path = spill.2.clone().expect("named spill file");
+        let file_name = Path::new(&file_path)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        assert!(file_name.starts_with(SPILL_FILE_PREFIX));
+        assert!(file_name.ends_with(".blaze-spill"));
+
+        // spilled data is readable and the file is removed on drop
+        let mut writer = spill.get_buf_writer();
+        writer.write_all(b"hello")?;
+        writer.flush()?;
+        drop(writer);
+        let mut data = vec![];
+        spill.get_buf_reader().read_to_end(&mut data)?;
+        assert_eq!(data, b"hello");
+        assert!(Path::new(&file_path).exists());
+        drop(spill);
+        assert!(!Path::new(&file_path).exists());
+        Ok(())
+    }
+}
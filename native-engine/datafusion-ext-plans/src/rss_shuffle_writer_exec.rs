@@ -133,12 +133,12 @@ impl ExecutionPlan for RssShuffleWriterExec {
                 output_io_time,
             )),
             Partitioning::HashPartitioning(..) | Partitioning::RangePartitioning(..) => {
-                let partitioner = Arc::new(RssSortShuffleRepartitioner::new(
+                let partitioner = Arc::new(RssSortShuffleRepartitioner::try_new(
                     partition,
                     rss_partition_writer,
                     self.partitioning.clone(),
                     output_io_time,
-                ));
+                )?);
                 MemManager::register_consumer(partitioner.clone(), true);
                 partitioner
             }
@@ -159,12 +159,12 @@ impl ExecutionPlan for RssShuffleWriterExec {
                     false, // do not record output metric
                 );
 
-                let partitioner = Arc::new(RssSortShuffleRepartitioner::new(
+                let partitioner = Arc::new(RssSortShuffleRepartitioner::try_new(
                     partition,
                     rss_partition_writer,
                     self.partitioning.clone(),
                     output_io_time,
-                ));
+                )?);
                 MemManager::register_consumer(partitioner.clone(), true);
                 partitioner
             }
@@ -15,11 +15,19 @@
 
 use std::io::Write;
 
-use arrow::record_batch::RecordBatch;
-use auron_jni_bridge::{is_task_running, jni_call};
+use arrow::{
+    array::{Array, ArrayRef},
+    compute::concat_batches,
+    record_batch::RecordBatch,
+    util::display::array_value_to_string,
+};
+use auron_jni_bridge::{conf, conf::IntConf, is_jni_bridge_inited, is_task_running, jni_call};
 use bytesize::ByteSize;
 use count_write::CountWrite;
-use datafusion::{common::Result, physical_plan::metrics::Time};
+use datafusion::{
+    common::{DataFusionError, Result},
+    physical_plan::metrics::{Count, Time},
+};
 use datafusion_ext_commons::{
     algorithm::rdx_sort::radix_sort_by_key,
     arrow::{
@@ -27,9 +35,11 @@ use datafusion_ext_commons::{
         selection::{BatchInterleaver, create_batch_interleaver},
     },
     compute_suggested_batch_size_for_output, df_execution_err,
+    io::write_unsafe_rows,
 };
 use itertools::Itertools;
 use jni::objects::GlobalRef;
+use once_cell::sync::OnceCell;
 #[cfg(test)]
 use parking_lot::Mutex;
 
@@ -40,58 +50,210 @@ use crate::{
         timer_helper::TimerHelper,
     },
     shuffle::{
-        Partitioning, evaluate_hashes, evaluate_partition_ids, evaluate_range_partition_ids,
-        evaluate_robin_partition_ids, rss::RssWriter,
+        Partitioning, configured_range_boundary_tie_break, evaluate_hash_keys,
+        evaluate_partition_ids, evaluate_range_partition_ids, evaluate_robin_partition_ids,
+        hash_keys, rss::RssWriter,
     },
 };
 
+/// routes rows whose hash partitioning keys are all null in a round-robin
+/// manner, if the ratio of such rows in a batch reaches `min_null_ratio`.
+///
+/// NOTE: this deviates from spark, which always routes null keys to the same
+/// partition. it mitigates extreme skew caused by null keys, but must not be
+/// used when the consumer relies on co-partitioning (e.g. joins).
+#[derive(Clone)]
+pub struct NullKeyFallback {
+    pub min_null_ratio: f64,
+    pub fallback_rows: Count,
+}
+
+/// configurations used by [`BufferedData::try_new`], read only once since
+/// buffered data is recreated on every spill
+struct BufferedDataConf {
+    partition_log_sample_interval: usize,
+    tiny_batch_rows: usize,
+    min_partition_frames: usize,
+    min_frame_rows: usize,
+    verify_in_mem_spill_offsets: bool,
+}
+
+fn buffered_data_conf() -> Result<&'static BufferedDataConf> {
+    static CONF: OnceCell<BufferedDataConf> = OnceCell::new();
+    CONF.get_or_try_init(|| {
+        if is_jni_bridge_inited() {
+            Ok::<_, DataFusionError>(BufferedDataConf {
+                partition_log_sample_interval: conf::SHUFFLE_PARTITION_LOG_SAMPLE_INTERVAL
+                    .value()?
+                    .max(0) as usize,
+                tiny_batch_rows: conf::SHUFFLE_TINY_BATCH_COALESCE_ROWS.value()?.max(0) as usize,
+                min_partition_frames: conf::SHUFFLE_MIN_PARTITION_FRAMES.value()?.max(1) as usize,
+                min_frame_rows: conf::SHUFFLE_MIN_FRAME_ROWS.value()?.max(1) as usize,
+                verify_in_mem_spill_offsets: conf::SHUFFLE_VERIFY_IN_MEM_SPILL_OFFSETS.value()?,
+            })
+        } else {
+            // for testing
+            Ok(BufferedDataConf {
+                partition_log_sample_interval: 0,
+                tiny_batch_rows: 0,
+                min_partition_frames: 1,
+                min_frame_rows: 10000,
+                verify_in_mem_spill_offsets: false,
+            })
+        }
+    })
+}
+
 pub struct BufferedData {
     partition_id: usize,
     partitioning: Partitioning,
     staging_batches: Vec<RecordBatch>,
     staging_num_rows: usize,
     staging_mem_used: usize,
+    staging_tiny_start: usize, // index of the first pending tiny staging batch
+    staging_tiny_rows: usize,
     sorted_batches: Vec<RecordBatch>,
     sorted_offsets: Vec<Vec<u32>>,
     num_rows: usize,
     sorted_mem_used: usize,
     output_io_time: Time,
+    partition_log_sample_interval: usize,
+    null_key_fallback: Option<NullKeyFallback>,
+    tiny_batch_rows: usize,
+    min_partition_frames: usize,
+    min_frame_rows: usize,
+    unsafe_row_output: bool,
+    verify_in_mem_spill_offsets: bool,
 }
 
 impl BufferedData {
-    pub fn new(partitioning: Partitioning, partition_id: usize, output_io_time: Time) -> Self {
+    pub fn try_new(
+        partitioning: Partitioning,
+        partition_id: usize,
+        output_io_time: Time,
+    ) -> Result<Self> {
+        Ok(Self::new_with_conf(
+            partitioning,
+            partition_id,
+            output_io_time,
+            buffered_data_conf()?,
+        ))
+    }
+
+    fn new_with_conf(
+        partitioning: Partitioning,
+        partition_id: usize,
+        output_io_time: Time,
+        conf: &BufferedDataConf,
+    ) -> Self {
         Self {
             partition_id,
             partitioning,
             staging_batches: vec![],
             staging_num_rows: 0,
             staging_mem_used: 0,
+            staging_tiny_start: 0,
+            staging_tiny_rows: 0,
             sorted_batches: vec![],
             sorted_offsets: vec![],
             num_rows: 0,
             sorted_mem_used: 0,
             output_io_time,
+            partition_log_sample_interval: conf.partition_log_sample_interval,
+            null_key_fallback: None,
+            tiny_batch_rows: conf.tiny_batch_rows,
+            min_partition_frames: conf.min_partition_frames,
+            min_frame_rows: conf.min_frame_rows,
+            unsafe_row_output: false,
+            verify_in_mem_spill_offsets: conf.verify_in_mem_spill_offsets,
         }
     }
 
+    /// logs one in every `interval` key-to-partition assignments at debug
+    /// level, 0 to disable
+    pub fn with_partition_log_sample_interval(mut self, interval: usize) -> Self {
+        self.partition_log_sample_interval = interval;
+        self
+    }
+
+    pub fn with_null_key_fallback(mut self, null_key_fallback: NullKeyFallback) -> Self {
+        self.null_key_fallback = Some(null_key_fallback);
+        self
+    }
+
+    /// coalesces consecutive input batches with fewer rows than
+    /// `tiny_batch_rows` into one staging batch, 0 to disable
+    pub fn with_tiny_batch_coalescing(mut self, tiny_batch_rows: usize) -> Self {
+        self.tiny_batch_rows = tiny_batch_rows;
+        self
+    }
+
+    /// splits each partition with at least `min_partition_frames *
+    /// min_frame_rows` rows into at least `min_partition_frames` compressed
+    /// frames, so that readers can decode them in parallel
+    pub fn with_min_partition_frames(
+        mut self,
+        min_partition_frames: usize,
+        min_frame_rows: usize,
+    ) -> Self {
+        self.min_partition_frames = min_partition_frames.max(1);
+        self.min_frame_rows = min_frame_rows.max(1);
+        self
+    }
+
+    /// writes partitions as uncompressed spark `UnsafeRowSerializer` streams
+    /// instead of compressed arrow frames, for pure JVM reducers
+    pub fn with_unsafe_row_output(mut self, unsafe_row_output: bool) -> Self {
+        self.unsafe_row_output = unsafe_row_output;
+        self
+    }
+
+    /// checks offsets of every in-memory spill against its data before
+    /// returning it, costs one pass over the offsets of each spill
+    pub fn with_in_mem_spill_offsets_verification(mut self, enabled: bool) -> Self {
+        self.verify_in_mem_spill_offsets = enabled;
+        self
+    }
+
     pub fn drain(&mut self) -> Self {
-        std::mem::replace(
-            self,
-            Self::new(
+        let conf = BufferedDataConf {
+            partition_log_sample_interval: self.partition_log_sample_interval,
+            tiny_batch_rows: self.tiny_batch_rows,
+            min_partition_frames: self.min_partition_frames,
+            min_frame_rows: self.min_frame_rows,
+            verify_in_mem_spill_offsets: self.verify_in_mem_spill_offsets,
+        };
+        let empty = Self {
+            unsafe_row_output: self.unsafe_row_output,
+            null_key_fallback: self.null_key_fallback.clone(),
+            ..Self::new_with_conf(
                 self.partitioning.clone(),
                 self.partition_id,
                 self.output_io_time.clone(),
-            ),
-        )
+                &conf,
+            )
+        };
+        std::mem::replace(self, empty)
     }
 
     pub fn add_batch(&mut self, batch: RecordBatch) -> Result<()> {
         // first add to staging, mem used is doubled for later sorting
-        self.num_rows += batch.num_rows();
-        self.staging_num_rows += batch.num_rows();
+        let num_rows = batch.num_rows();
+        self.num_rows += num_rows;
+        self.staging_num_rows += num_rows;
         self.staging_mem_used += batch.get_batch_mem_size() * 2;
         self.staging_batches.push(batch);
 
+        if num_rows < self.tiny_batch_rows {
+            self.staging_tiny_rows += num_rows;
+            if self.staging_tiny_rows >= self.tiny_batch_rows {
+                self.coalesce_tiny_batches()?;
+            }
+        } else {
+            self.staging_tiny_start = self.staging_batches.len();
+            self.staging_tiny_rows = 0;
+        }
+
         let suggested_batch_size =
             compute_suggested_batch_size_for_output(self.staging_mem_used, self.staging_num_rows);
         if self.staging_mem_used > suggested_batch_size {
@@ -100,6 +262,21 @@ impl BufferedData {
         Ok(())
     }
 
+    // concatenates consecutive tiny batches pending at the end of staging
+    // batches into one batch at once, rows are kept in input order
+    fn coalesce_tiny_batches(&mut self) -> Result<()> {
+        let tiny_batches = self.staging_batches.split_off(self.staging_tiny_start);
+        let coalesced = concat_batches(&tiny_batches[0].schema(), &tiny_batches)?;
+        for batch in &tiny_batches {
+            self.staging_mem_used -= batch.get_batch_mem_size() * 2;
+        }
+        self.staging_mem_used += coalesced.get_batch_mem_size() * 2;
+        self.staging_batches.push(coalesced);
+        self.staging_tiny_start = self.staging_batches.len();
+        self.staging_tiny_rows = 0;
+        Ok(())
+    }
+
     fn flush_staging(&mut self) -> Result<()> {
         let sorted_num_rows = self.num_rows - self.staging_num_rows;
         let staging_batches = std::mem::take(&mut self.staging_batches);
@@ -108,9 +285,13 @@ impl BufferedData {
             &self.partitioning,
             sorted_num_rows,
             self.partition_id,
+            self.partition_log_sample_interval,
+            self.null_key_fallback.as_ref(),
         )?;
         self.staging_num_rows = 0;
         self.staging_mem_used = 0;
+        self.staging_tiny_start = 0;
+        self.staging_tiny_rows = 0;
 
         self.sorted_mem_used += sorted_batch.get_batch_mem_size() + offsets.len() * 4;
         self.sorted_batches.push(sorted_batch);
@@ -131,22 +312,29 @@ impl BufferedData {
         if !self.staging_batches.is_empty() {
             self.flush_staging()?;
         }
+        if self.unsafe_row_output {
+            return self.write_unsafe_rows(w);
+        }
 
         let output_io_time = self.output_io_time.clone();
         let num_partitions = self.partitioning.partition_count();
+        let max_frame_rows = self.max_frame_rows();
         let mut writer = IpcCompressionWriter::new(CountWrite::from(&mut w));
         let mut offsets = vec![];
         let mut iter = self.into_sorted_batches()?;
 
-        while let Some((partition_id, batch_iter)) = iter.next_partition_chunk() {
+        while let Some((partition_id, batch_iter)) = iter.next_partition_chunk()? {
             if !is_task_running() {
                 df_execution_err!("task completed/killed")?;
             }
 
             offsets.resize(partition_id + 1, writer.inner().count());
-            for batch in batch_iter {
+            for (batch, frame_end) in split_frames(batch_iter, max_frame_rows[partition_id]) {
                 output_io_time
                     .with_timer(|| writer.write_batch(batch.num_rows(), batch.columns()))?;
+                if frame_end {
+                    output_io_time.with_timer(|| writer.finish_current_buf())?;
+                }
             }
             output_io_time.with_timer(|| writer.finish_current_buf())?;
         }
@@ -157,6 +345,95 @@ impl BufferedData {
         Ok(offsets)
     }
 
+    // write buffered data to in-memory spills, returns offsets to each partition
+    // of every spill.
+    // a new spill is started before the serialized (uncompressed) data of the
+    // current one exceeds max_spill_size, so partitions may span multiple
+    // spills. a single batch larger than max_spill_size still gets its own
+    // spill.
+    pub fn write_in_mem_spills(
+        mut self,
+        max_spill_size: usize,
+    ) -> Result<Vec<Offsetted<u64, Vec<u8>>>> {
+        if self.num_rows == 0 {
+            return Ok(vec![]);
+        }
+
+        let mem_used = ByteSize(self.mem_used() as u64);
+        log::info!("draining all buffered data to in-mem spills, total_mem={mem_used}");
+
+        if !self.staging_batches.is_empty() {
+            self.flush_staging()?;
+        }
+        if self.unsafe_row_output {
+            let mut spill = vec![];
+            let offsets = self.write_unsafe_rows(&mut spill)?;
+            return Ok(vec![Offsetted::new(offsets, spill)]);
+        }
+
+        let output_io_time = self.output_io_time.clone();
+        let num_partitions = self.partitioning.partition_count();
+        let verify_offsets = self.verify_in_mem_spill_offsets;
+        let spill_capacity = max_spill_size.min(self.mem_used());
+        let mut writer = IpcCompressionWriter::new(Vec::with_capacity(spill_capacity));
+        let mut spills = vec![];
+        let mut offsets = vec![];
+        let mut cur_spill_size = 0;
+        let mut serialized = vec![];
+        let max_frame_rows = self.max_frame_rows();
+        let mut iter = self.into_sorted_batches()?;
+
+        while let Some((partition_id, batch_iter)) = iter.next_partition_chunk()? {
+            if !is_task_running() {
+                df_execution_err!("task completed/killed")?;
+            }
+
+            offsets.resize(partition_id + 1, writer.inner().len() as u64);
+            for (batch, frame_end) in split_frames(batch_iter, max_frame_rows[partition_id]) {
+                serialized.clear();
+                output_io_time.with_timer(|| {
+                    writer.serialize_batch(batch.num_rows(), batch.columns(), &mut serialized)
+                })?;
+                if serialized.len() > max_spill_size {
+                    log::warn!(
+                        "serialized batch size {} exceeds max in-mem spill size {}",
+                        ByteSize(serialized.len() as u64),
+                        ByteSize(max_spill_size as u64),
+                    );
+                }
+                if cur_spill_size > 0 && cur_spill_size + serialized.len() > max_spill_size {
+                    output_io_time.with_timer(|| writer.finish_current_buf())?;
+                    offsets.resize(num_partitions + 1, writer.inner().len() as u64);
+                    spills.push(new_in_mem_spill(
+                        std::mem::take(&mut offsets),
+                        std::mem::replace(writer.inner_mut(), Vec::with_capacity(spill_capacity)),
+                        verify_offsets,
+                    )?);
+                    offsets.resize(partition_id + 1, 0);
+                    cur_spill_size = 0;
+                }
+                cur_spill_size += serialized.len();
+                output_io_time.with_timer(|| writer.write_serialized_batch(&serialized))?;
+                if frame_end {
+                    output_io_time.with_timer(|| writer.finish_current_buf())?;
+                }
+            }
+            output_io_time.with_timer(|| writer.finish_current_buf())?;
+        }
+        offsets.resize(num_partitions + 1, writer.inner().len() as u64);
+        spills.push(new_in_mem_spill(
+            offsets,
+            std::mem::take(writer.inner_mut()),
+            verify_offsets,
+        )?);
+
+        log::info!(
+            "all buffered data drained to {} in-mem spills",
+            spills.len()
+        );
+        Ok(spills)
+    }
+
     // write buffered data to rss, returns uncompressed size
     pub fn write_rss(mut self, rss_partition_writer: GlobalRef) -> Result<()> {
         if self.num_rows == 0 {
@@ -171,19 +448,23 @@ impl BufferedData {
         }
 
         let output_io_time = self.output_io_time.clone();
+        let max_frame_rows = self.max_frame_rows();
         let mut iter = self.into_sorted_batches()?;
         let mut writer = IpcCompressionWriter::new(RssWriter::new(rss_partition_writer.clone(), 0));
 
-        while let Some((partition_id, batch_iter)) = iter.next_partition_chunk() {
+        while let Some((partition_id, batch_iter)) = iter.next_partition_chunk()? {
             if !is_task_running() {
                 df_execution_err!("task completed/killed")?;
             }
 
             // write all batches with this part id
             writer.set_output(RssWriter::new(rss_partition_writer.clone(), partition_id));
-            for batch in batch_iter {
+            for (batch, frame_end) in split_frames(batch_iter, max_frame_rows[partition_id]) {
                 output_io_time
                     .with_timer(|| writer.write_batch(batch.num_rows(), batch.columns()))?;
+                if frame_end {
+                    output_io_time.with_timer(|| writer.finish_current_buf())?;
+                }
             }
             output_io_time.with_timer(|| writer.finish_current_buf())?;
         }
@@ -195,6 +476,53 @@ impl BufferedData {
         Ok(())
     }
 
+    // writes each partition as an UnsafeRowSerializer stream, streams of the
+    // same partition can be simply concatenated when merging spills
+    fn write_unsafe_rows<W: Write>(self, mut w: W) -> Result<Vec<u64>> {
+        let output_io_time = self.output_io_time.clone();
+        let num_partitions = self.partitioning.partition_count();
+        let mut w = CountWrite::from(&mut w);
+        let mut offsets = vec![];
+        let mut iter = self.into_sorted_batches()?;
+
+        while let Some((partition_id, batch_iter)) = iter.next_partition_chunk()? {
+            if !is_task_running() {
+                df_execution_err!("task completed/killed")?;
+            }
+
+            offsets.resize(partition_id + 1, w.count());
+            for batch in batch_iter {
+                output_io_time
+                    .with_timer(|| write_unsafe_rows(batch.num_rows(), batch.columns(), &mut w))?;
+            }
+        }
+        offsets.resize(num_partitions + 1, w.count());
+        Ok(offsets)
+    }
+
+    // returns max number of rows in a frame of each partition, large
+    // partitions are split into at least min_partition_frames frames
+    fn max_frame_rows(&self) -> Vec<usize> {
+        let num_partitions = self.partitioning.partition_count();
+        let mut max_frame_rows = vec![usize::MAX; num_partitions];
+        if self.min_partition_frames <= 1 {
+            return max_frame_rows;
+        }
+
+        let mut partition_num_rows = vec![0; num_partitions];
+        for offsets in &self.sorted_offsets {
+            for (num_rows, w) in partition_num_rows.iter_mut().zip(offsets.windows(2)) {
+                *num_rows += (w[1] - w[0]) as usize;
+            }
+        }
+        for (max_rows, &num_rows) in max_frame_rows.iter_mut().zip(&partition_num_rows) {
+            if num_rows >= self.min_partition_frames * self.min_frame_rows {
+                *max_rows = num_rows.div_ceil(self.min_partition_frames);
+            }
+        }
+        max_frame_rows
+    }
+
     fn into_sorted_batches(self) -> Result<PartitionedBatchesIterator<'static>> {
         let num_rows = self.num_rows;
         let sub_batch_size = compute_suggested_batch_size_for_output(self.mem_used(), num_rows);
@@ -214,9 +542,75 @@ impl BufferedData {
     pub fn is_empty(&self) -> bool {
         self.sorted_batches.is_empty() && self.staging_batches.is_empty()
     }
+
+    pub fn is_unsafe_row_output(&self) -> bool {
+        self.unsafe_row_output
+    }
+}
+
+// splits batches of a partition so that every frame holds at most
+// max_frame_rows rows, yields each piece and whether it ends a frame
+fn split_frames(
+    batches: impl Iterator<Item = RecordBatch>,
+    max_frame_rows: usize,
+) -> impl Iterator<Item = (RecordBatch, bool)> {
+    let mut frame_rows = 0;
+    batches.flat_map(move |batch| {
+        if max_frame_rows == usize::MAX {
+            return vec![(batch, false)];
+        }
+        let mut pieces = vec![];
+        let mut offset = 0;
+        while offset < batch.num_rows() {
+            let len = (batch.num_rows() - offset).min(max_frame_rows - frame_rows);
+            frame_rows += len;
+            let frame_end = frame_rows == max_frame_rows;
+            if frame_end {
+                frame_rows = 0;
+            }
+            pieces.push((batch.slice(offset, len), frame_end));
+            offset += len;
+        }
+        pieces
+    })
+}
+
+fn new_in_mem_spill(
+    offsets: Vec<u64>,
+    data: Vec<u8>,
+    verify_offsets: bool,
+) -> Result<Offsetted<u64, Vec<u8>>> {
+    if verify_offsets {
+        verify_in_mem_spill_offsets(&offsets, data.len())?;
+    }
+    Ok(Offsetted::new(offsets, data))
+}
+
+// checks offsets of an in-memory spill are monotonic and cover exactly the
+// written data, guarding against inconsistent spills caused by serialization
+// bugs
+fn verify_in_mem_spill_offsets(offsets: &[u64], data_len: usize) -> Result<()> {
+    if offsets.first() != Some(&0) {
+        return df_execution_err!("in-mem spill offsets not starting from 0: {offsets:?}");
+    }
+    if offsets.windows(2).any(|w| w[0] > w[1]) {
+        return df_execution_err!("in-mem spill offsets not monotonic: {offsets:?}");
+    }
+    let last_offset = offsets[offsets.len() - 1];
+    if last_offset != data_len as u64 {
+        return df_execution_err!(
+            "in-mem spill offsets mismatched with data length: last_offset={last_offset}, data_len={data_len}"
+        );
+    }
+    Ok(())
 }
 
 struct PartitionedBatchesIterator<'a> {
+    batches: Vec<RecordBatch>,
+    batch_last_partition_ids: Vec<usize>,
+    release_order: Vec<usize>, // batch indices ordered by last partition ids
+    num_release_checked: usize, // watermark of release_order
+    num_released_batches: usize,
     batch_interleaver: BatchInterleaver,
     merge_iter: OffsettedMergeIterator<'a, u32, usize>,
     batch_size: usize,
@@ -230,8 +624,28 @@ impl<'a> PartitionedBatchesIterator<'a> {
         sub_batch_size: usize,
         num_partitions: usize,
     ) -> Result<Self> {
+        // batches are sorted by partition id, so a batch is no longer needed once
+        // its last non-empty partition has been consumed
+        let batch_last_partition_ids: Vec<usize> = batch_offsets
+            .iter()
+            .map(|offsets| {
+                (0..num_partitions)
+                    .rev()
+                    .find(|&p| offsets[p + 1] > offsets[p])
+                    .unwrap_or(0)
+            })
+            .collect();
+        let release_order = (0..batches.len())
+            .sorted_by_key(|&batch_idx| batch_last_partition_ids[batch_idx])
+            .collect();
+
         Ok(Self {
             batch_interleaver: create_batch_interleaver(&batches, true)?,
+            batches,
+            batch_last_partition_ids,
+            release_order,
+            num_release_checked: 0,
+            num_released_batches: 0,
             merge_iter: OffsettedMergeIterator::new(
                 num_partitions,
                 batch_offsets
@@ -248,12 +662,16 @@ impl<'a> PartitionedBatchesIterator<'a> {
     /// all iterators returned should have been fully consumed
     pub fn next_partition_chunk(
         &mut self,
-    ) -> Option<(usize, impl Iterator<Item = RecordBatch> + 'a)> {
+    ) -> Result<Option<(usize, impl Iterator<Item = RecordBatch> + 'a)>> {
         // safety: bypass lifetime checker
         let batches_iter =
             unsafe { std::mem::transmute::<_, &mut PartitionedBatchesIterator<'a>>(self) };
 
-        let (chunk_partition_id, chunk) = batches_iter.merge_iter.next_partition_chunk()?;
+        batches_iter.release_consumed_batches(batches_iter.merge_iter.peek_next_partition_id())?;
+        let Some((chunk_partition_id, chunk)) = batches_iter.merge_iter.next_partition_chunk()
+        else {
+            return Ok(None);
+        };
 
         // last chunk must be fully consumed
         if batches_iter.last_chunk_partition_id == Some(chunk_partition_id) {
@@ -277,7 +695,31 @@ impl<'a> PartitionedBatchesIterator<'a> {
             let output_batch = batch_interleaver(&indices).expect("error interleaving batches");
             return Some(output_batch);
         });
-        Some((chunk_partition_id, batch_iter))
+        Ok(Some((chunk_partition_id, batch_iter)))
+    }
+
+    // drops batches whose partitions are all consumed, reducing peak memory
+    // while the remaining partitions are being serialized.
+    // released batches are replaced with empty ones to keep batch indices.
+    // batches are checked in order of their last partition ids from the
+    // watermark, so each batch is checked only once over all partitions.
+    fn release_consumed_batches(&mut self, next_partition_id: usize) -> Result<()> {
+        let mut released = false;
+        while let Some(&batch_idx) = self.release_order.get(self.num_release_checked)
+            && self.batch_last_partition_ids[batch_idx] < next_partition_id
+        {
+            let batch = &mut self.batches[batch_idx];
+            if batch.num_rows() > 0 {
+                *batch = RecordBatch::new_empty(batch.schema());
+                self.num_released_batches += 1;
+                released = true;
+            }
+            self.num_release_checked += 1;
+        }
+        if released {
+            self.batch_interleaver = create_batch_interleaver(&self.batches, true)?;
+        }
+        Ok(())
     }
 }
 
@@ -286,41 +728,71 @@ fn sort_batches_by_partition_id(
     partitioning: &Partitioning,
     current_num_rows: usize,
     partition_id: usize,
+    log_sample_interval: usize,
+    null_key_fallback: Option<&NullKeyFallback>,
 ) -> Result<(Vec<u32>, RecordBatch)> {
     let num_partitions = partitioning.partition_count();
     let mut round_robin_start_rows =
         (partition_id * 1000193 + current_num_rows) % partitioning.partition_count();
+    let log_sample_enabled = log_sample_interval > 0 && log::log_enabled!(log::Level::Debug);
+    let mut log_sample_start_rows = current_num_rows;
+    let mut null_key_start_rows = round_robin_start_rows;
 
     // compute partition indices
-    let mut partition_indices = batches
-        .iter()
-        .enumerate()
-        .flat_map(|(batch_idx, batch)| {
-            let part_ids = match partitioning {
-                Partitioning::HashPartitioning(..) => {
-                    // compute partition indices
-                    let hashes = evaluate_hashes(partitioning, &batch)
-                        .expect(&format!("error evaluating hashes with {partitioning}"));
-                    evaluate_partition_ids(hashes, partitioning.partition_count())
-                }
-                Partitioning::RoundRobinPartitioning(..) => {
-                    let part_ids =
-                        evaluate_robin_partition_ids(partitioning, &batch, round_robin_start_rows);
-                    round_robin_start_rows += batch.num_rows();
-                    round_robin_start_rows %= partitioning.partition_count();
-                    part_ids
-                }
-                Partitioning::RangePartitioning(sort_expr, _, bounds) => {
-                    evaluate_range_partition_ids(&batch, sort_expr, bounds).unwrap()
+    let mut partition_indices = Vec::with_capacity(batches.iter().map(|b| b.num_rows()).sum());
+    for (batch_idx, batch) in batches.iter().enumerate() {
+        let part_ids = match partitioning {
+            Partitioning::HashPartitioning(..) => {
+                // compute partition indices
+                let keys = evaluate_hash_keys(partitioning, batch)?;
+                let hashes = hash_keys(&keys)?;
+                let mut part_ids = evaluate_partition_ids(hashes, partitioning.partition_count());
+                if let Some(null_key_fallback) = null_key_fallback {
+                    apply_null_key_fallback(
+                        &keys,
+                        num_partitions,
+                        &mut part_ids,
+                        &mut null_key_start_rows,
+                        null_key_fallback,
+                    );
                 }
-                _ => unreachable!("unsupported partitioning: {:?}", partitioning),
-            };
+                part_ids
+            }
+            Partitioning::RoundRobinPartitioning(..) => {
+                let part_ids =
+                    evaluate_robin_partition_ids(partitioning, batch, round_robin_start_rows);
+                round_robin_start_rows += batch.num_rows();
+                round_robin_start_rows %= partitioning.partition_count();
+                part_ids
+            }
+            Partitioning::RangePartitioning(sort_expr, _, bounds) => evaluate_range_partition_ids(
+                batch,
+                sort_expr,
+                bounds,
+                configured_range_boundary_tie_break()?,
+            )?,
+            _ => unreachable!("unsupported partitioning: {:?}", partitioning),
+        };
+        if log_sample_enabled {
+            match sample_partition_ids(
+                partitioning,
+                batch,
+                &part_ids,
+                log_sample_start_rows,
+                log_sample_interval,
+            ) {
+                Ok(samples) => samples.iter().for_each(|sample| log::debug!("{sample}")),
+                Err(e) => log::warn!("error sampling partition ids: {e}"),
+            }
+            log_sample_start_rows += batch.num_rows();
+        }
+        partition_indices.extend(
             part_ids
                 .into_iter()
                 .enumerate()
-                .map(move |(row_idx, part_id)| (part_id, batch_idx as u32, row_idx as u32))
-        })
-        .collect::<Vec<_>>();
+                .map(|(row_idx, part_id)| (part_id, batch_idx as u32, row_idx as u32)),
+        );
+    }
 
     // sort
     let mut part_counts = vec![0; num_partitions];
@@ -350,13 +822,86 @@ fn sort_batches_by_partition_id(
     return Ok((partition_offsets, sorted_batch));
 }
 
+// reassigns partition ids of null-key rows in a round-robin manner, see
+// NullKeyFallback. key_cols are the evaluated hash partitioning keys
+fn apply_null_key_fallback(
+    key_cols: &[ArrayRef],
+    num_partitions: usize,
+    part_ids: &mut [u32],
+    start_rows: &mut usize,
+    null_key_fallback: &NullKeyFallback,
+) {
+    if key_cols.iter().any(|col| col.null_count() == 0) {
+        return; // fast path: some key column has no nulls
+    }
+
+    let num_rows = part_ids.len();
+    let null_key_rows = (0..num_rows)
+        .filter(|&row_idx| key_cols.iter().all(|col| col.is_null(row_idx)))
+        .collect::<Vec<_>>();
+    let null_ratio = null_key_rows.len() as f64 / num_rows.max(1) as f64;
+    if null_key_rows.is_empty() || null_ratio < null_key_fallback.min_null_ratio {
+        return;
+    }
+
+    for row_idx in &null_key_rows {
+        part_ids[*row_idx] = (*start_rows % num_partitions) as u32;
+        *start_rows += 1;
+    }
+    null_key_fallback.fallback_rows.add(null_key_rows.len());
+}
+
+// formats key values and partition id of every row whose global index is a
+// multiple of interval, only the first few key columns are printed
+fn sample_partition_ids(
+    partitioning: &Partitioning,
+    batch: &RecordBatch,
+    part_ids: &[u32],
+    start_row: usize,
+    interval: usize,
+) -> Result<Vec<String>> {
+    const MAX_LOGGED_KEYS: usize = 3;
+
+    let key_exprs = match partitioning {
+        Partitioning::HashPartitioning(exprs, _) => exprs.clone(),
+        Partitioning::RangePartitioning(sort_exprs, ..) => {
+            sort_exprs.iter().map(|e| e.expr.clone()).collect()
+        }
+        _ => vec![],
+    };
+    let key_cols: Vec<ArrayRef> = key_exprs
+        .iter()
+        .take(MAX_LOGGED_KEYS)
+        .map(|expr| {
+            expr.evaluate(batch)
+                .and_then(|cv| cv.into_array(batch.num_rows()))
+        })
+        .collect::<Result<_>>()?;
+
+    let first_row = (interval - start_row % interval) % interval;
+    let mut samples = vec![];
+    for row_idx in (first_row..batch.num_rows()).step_by(interval) {
+        let keys = key_cols
+            .iter()
+            .map(|col| array_value_to_string(col, row_idx))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        samples.push(format!(
+            "sampled partition assignment: row={}, keys=[{}], partition_id={}",
+            start_row + row_idx,
+            keys.join(", "),
+            part_ids[row_idx],
+        ));
+    }
+    Ok(samples)
+}
+
 #[cfg(test)]
 mod test {
-    use std::sync::Arc;
+    use std::{io::Cursor, sync::Arc};
 
     use arrow::{
-        array::{ArrayRef, Int32Array},
-        datatypes::{DataType, Field, Schema},
+        array::{ArrayRef, Int32Array, as_primitive_array},
+        datatypes::{DataType, Field, Int32Type, Schema, SchemaRef},
         record_batch::RecordBatch,
         row::{RowConverter, Rows, SortField},
     };
@@ -368,6 +913,7 @@ mod test {
     };
 
     use super::*;
+    use crate::{common::ipc_compression::IpcCompressionReader, shuffle::RangeBoundaryTieBreak};
 
     fn build_table_i32(
         a: (&str, &Vec<i32>),
@@ -400,8 +946,14 @@ mod test {
         );
 
         let round_robin_partitioning = Partitioning::RoundRobinPartitioning(4);
-        let (_parts, sorted_batch) =
-            sort_batches_by_partition_id(vec![record_batch], &round_robin_partitioning, 3, 0)?;
+        let (_parts, sorted_batch) = sort_batches_by_partition_id(
+            vec![record_batch],
+            &round_robin_partitioning,
+            3,
+            0,
+            0,
+            None,
+        )?;
 
         let expected = vec![
             "+----+---+---+",
@@ -455,7 +1007,7 @@ mod test {
         let range_repartitioning =
             Partitioning::RangePartitioning(sort_exprs, partition_num, Arc::from(rows));
         let (_parts, sorted_batch) =
-            sort_batches_by_partition_id(vec![record_batch], &range_repartitioning, 0, 0)?;
+            sort_batches_by_partition_id(vec![record_batch], &range_repartitioning, 0, 0, 0, None)?;
 
         let expected = vec![
             "+----+---+---+",
@@ -517,7 +1069,7 @@ mod test {
         let range_repartitioning =
             Partitioning::RangePartitioning(sort_exprs, partition_num, Arc::from(rows));
         let (_parts, sorted_batch) =
-            sort_batches_by_partition_id(vec![record_batch], &range_repartitioning, 0, 0)?;
+            sort_batches_by_partition_id(vec![record_batch], &range_repartitioning, 0, 0, 0, None)?;
 
         let expected = vec![
             "+----+---+---+",
@@ -538,4 +1090,441 @@ mod test {
         assert_batches_eq!(expected, &vec![sorted_batch]);
         Ok(())
     }
+
+    #[test]
+    fn test_range_boundary_tie_break() -> Result<()> {
+        let sort_exprs = vec![PhysicalSortExpr {
+            expr: Arc::new(Column::new("a", 0)),
+            options: SortOptions::default(),
+        }];
+        let row_converter = RowConverter::new(vec![SortField::new(DataType::Int32)])?;
+
+        // 3 bounds are searched linearly, 200 bounds are binary searched
+        for (bound_values, tie_bound_idx) in [
+            (vec![11, 14, 17], 1),
+            ((0..200).map(|i| i * 10).collect::<Vec<_>>(), 100),
+        ] {
+            let tie_value = bound_values[tie_bound_idx];
+            let bounds: ArrayRef = Arc::new(Int32Array::from(bound_values));
+            let bound_rows = Arc::new(row_converter.convert_columns(&[bounds])?);
+
+            let values = vec![tie_value; 1000];
+            let batch = build_table_i32(("a", &values), ("b", &values), ("c", &values));
+            for (tie_break, expected_partition) in [
+                (RangeBoundaryTieBreak::Lower, tie_bound_idx),
+                (RangeBoundaryTieBreak::Upper, tie_bound_idx + 1),
+            ] {
+                let part_ids =
+                    evaluate_range_partition_ids(&batch, &sort_exprs, &bound_rows, tie_break)?;
+                assert!(
+                    part_ids.iter().all(|&p| p as usize == expected_partition),
+                    "rows equal to bound must all go to partition {expected_partition} \
+                     with {tie_break:?}",
+                );
+            }
+        }
+        assert_eq!(
+            RangeBoundaryTieBreak::default(),
+            RangeBoundaryTieBreak::Lower
+        );
+        assert_eq!(
+            RangeBoundaryTieBreak::try_from_name("UPPER")?,
+            RangeBoundaryTieBreak::Upper
+        );
+        assert!(RangeBoundaryTieBreak::try_from_name("middle").is_err());
+        Ok(())
+    }
+
+    fn build_buffered_data(num_partitions: usize, num_batches: i32) -> Result<BufferedData> {
+        let partitioning =
+            Partitioning::HashPartitioning(vec![Arc::new(Column::new("a", 0))], num_partitions);
+        let mut data = BufferedData::try_new(partitioning, 0, Time::new())?;
+        for i in 0..num_batches {
+            let values = (i * 10000..(i + 1) * 10000).collect::<Vec<_>>();
+            data.add_batch(build_table_i32(
+                ("a", &values),
+                ("b", &values),
+                ("c", &values),
+            ))?;
+        }
+        Ok(data)
+    }
+
+    // reads all values of column `a` from the spills, grouped by partition
+    fn read_partitioned_values(
+        spills: &[Offsetted<u64, Vec<u8>>],
+        num_partitions: usize,
+        schema: &SchemaRef,
+    ) -> Result<Vec<Vec<i32>>> {
+        let mut partitions = vec![vec![]; num_partitions];
+        for spill in spills {
+            for (partition_id, values) in partitions.iter_mut().enumerate() {
+                let range = spill.offset(partition_id);
+                let buf = spill.data()[range.start as usize..range.end as usize].to_vec();
+                let mut reader = IpcCompressionReader::new(Cursor::new(buf));
+                while let Some((_, cols)) = reader.read_batch(schema)? {
+                    values.extend(as_primitive_array::<Int32Type>(&cols[0]).values());
+                }
+            }
+        }
+        for values in &mut partitions {
+            values.sort_unstable();
+        }
+        Ok(partitions)
+    }
+
+    #[test]
+    fn test_write_in_mem_spills_with_max_size() -> Result<()> {
+        let num_partitions = 16;
+        let schema = build_table_i32(("a", &vec![]), ("b", &vec![]), ("c", &vec![])).schema();
+        let max_spill_size = 262144;
+
+        let spills =
+            build_buffered_data(num_partitions, 20)?.write_in_mem_spills(max_spill_size)?;
+        assert!(spills.len() > 1);
+        for spill in &spills {
+            assert_eq!(spill.offsets().len(), num_partitions + 1);
+            assert!(spill.data().len() <= max_spill_size);
+        }
+
+        let unsplit = build_buffered_data(num_partitions, 20)?.write_in_mem_spills(usize::MAX)?;
+        assert_eq!(unsplit.len(), 1);
+
+        // every oversized batch gets its own spill
+        let oversized = build_buffered_data(num_partitions, 20)?.write_in_mem_spills(1)?;
+        assert!(oversized.len() > spills.len());
+        assert_eq!(
+            read_partitioned_values(&oversized, num_partitions, &schema)?,
+            read_partitioned_values(&unsplit, num_partitions, &schema)?
+        );
+
+        let values = read_partitioned_values(&spills, num_partitions, &schema)?;
+        assert_eq!(values.iter().map(|v| v.len()).sum::<usize>(), 200000);
+        assert_eq!(
+            values,
+            read_partitioned_values(&unsplit, num_partitions, &schema)?
+        );
+        Ok(())
+    }
+
+    // captures log messages of the current thread, so that messages logged by
+    // other tests running concurrently are not mixed in
+    struct CapturingLogger {
+        records: Mutex<Vec<(std::thread::ThreadId, String)>>,
+    }
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.records
+                .lock()
+                .push((std::thread::current().id(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn captured_logs(f: impl FnOnce() -> Result<()>) -> Result<Vec<String>> {
+        static LOGGER: OnceCell<&'static CapturingLogger> = OnceCell::new();
+        let logger = LOGGER.get_or_init(|| {
+            let logger = Box::leak(Box::new(CapturingLogger {
+                records: Mutex::new(vec![]),
+            }));
+            log::set_logger(logger).expect("error setting logger");
+            log::set_max_level(log::LevelFilter::Debug);
+            logger
+        });
+
+        let thread_id = std::thread::current().id();
+        f()?;
+        let mut records = logger.records.lock();
+        let (captured, others) = std::mem::take(&mut *records)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(id, _)| *id == thread_id);
+        *records = others;
+        Ok(captured.into_iter().map(|(_, message)| message).collect())
+    }
+
+    #[test]
+    fn test_partition_log_sampling() -> Result<()> {
+        let num_partitions = 16;
+        let partitioning =
+            Partitioning::HashPartitioning(vec![Arc::new(Column::new("a", 0))], num_partitions);
+
+        // batches of uneven sizes, sorted in two rounds. one row in every 1000
+        // rows is sampled and the sampling continues across rounds
+        let mut rounds = vec![];
+        let mut start_row = 0;
+        for round_num_rows in [vec![10000, 1, 999], vec![1500, 7500]] {
+            let mut batches = vec![];
+            let round_start_row = start_row;
+            for num_rows in round_num_rows {
+                let values = (start_row as i32..(start_row + num_rows) as i32).collect::<Vec<_>>();
+                batches.push(build_table_i32(
+                    ("a", &values),
+                    ("b", &values),
+                    ("c", &values),
+                ));
+                start_row += num_rows;
+            }
+            rounds.push((round_start_row, batches));
+        }
+
+        let logs = captured_logs(|| {
+            for (round_start_row, batches) in rounds {
+                sort_batches_by_partition_id(
+                    batches,
+                    &partitioning,
+                    round_start_row,
+                    0,
+                    1000,
+                    None,
+                )?;
+            }
+            Ok(())
+        })?;
+        let samples = logs
+            .iter()
+            .filter(|log| log.starts_with("sampled partition assignment: "))
+            .collect::<Vec<_>>();
+        assert_eq!(samples.len(), start_row / 1000);
+        for (i, sample) in samples.iter().enumerate() {
+            let row = i * 1000;
+            assert!(
+                sample.starts_with(&format!(
+                    "sampled partition assignment: row={row}, keys=[{row}], partition_id="
+                )),
+                "unexpected sample: {sample}",
+            );
+        }
+
+        // nothing is logged when sampling is disabled
+        let batch = build_table_i32(
+            ("a", &vec![0; 5000]),
+            ("b", &vec![0; 5000]),
+            ("c", &vec![0; 5000]),
+        );
+        let logs = captured_logs(|| {
+            sort_batches_by_partition_id(vec![batch], &partitioning, 0, 0, 0, None)?;
+            Ok(())
+        })?;
+        assert!(logs.is_empty(), "unexpected logs: {logs:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_null_key_fallback() -> Result<()> {
+        let num_partitions = 4;
+        let partitioning =
+            Partitioning::HashPartitioning(vec![Arc::new(Column::new("a", 0))], num_partitions);
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, true),
+            Field::new("b", DataType::Int32, false),
+        ]));
+        let build_batch = |keys: Vec<Option<i32>>| {
+            let values = (0..keys.len() as i32).collect::<Vec<_>>();
+            RecordBatch::try_new(
+                schema.clone(),
+                vec![
+                    Arc::new(Int32Array::from(keys)),
+                    Arc::new(Int32Array::from(values)),
+                ],
+            )
+        };
+        let fallback = NullKeyFallback {
+            min_null_ratio: 0.9,
+            fallback_rows: Count::new(),
+        };
+
+        // without fallback, all null keys go to the same partition
+        let all_null = build_batch(vec![None; 100])?;
+        let (offsets, _) =
+            sort_batches_by_partition_id(vec![all_null.clone()], &partitioning, 0, 0, 0, None)?;
+        let counts = offsets.windows(2).map(|w| w[1] - w[0]).collect::<Vec<_>>();
+        assert_eq!(counts.iter().filter(|&&c| c > 0).count(), 1);
+
+        // with fallback, null keys are evenly spread
+        let (offsets, _) =
+            sort_batches_by_partition_id(vec![all_null], &partitioning, 0, 0, 0, Some(&fallback))?;
+        let counts = offsets.windows(2).map(|w| w[1] - w[0]).collect::<Vec<_>>();
+        assert_eq!(counts, vec![25; num_partitions]);
+        assert_eq!(fallback.fallback_rows.value(), 100);
+
+        // non-null keys (and null keys below the ratio) are routed normally
+        let mixed = build_batch((0..100).map(|i| (i % 10 != 0).then_some(i)).collect())?;
+        let (expected_offsets, expected) =
+            sort_batches_by_partition_id(vec![mixed.clone()], &partitioning, 0, 0, 0, None)?;
+        let (offsets, sorted) =
+            sort_batches_by_partition_id(vec![mixed], &partitioning, 0, 0, 0, Some(&fallback))?;
+        assert_eq!(offsets, expected_offsets);
+        assert_eq!(sorted, expected);
+        assert_eq!(fallback.fallback_rows.value(), 100);
+        Ok(())
+    }
+
+    #[test]
+    fn test_release_consumed_batches() -> Result<()> {
+        // every batch holds rows of two adjacent partitions
+        let num_partitions = 8;
+        let mut batches = vec![];
+        let mut batch_offsets = vec![];
+        for i in 0..4 {
+            let values = (i * 100..i * 100 + 100).collect::<Vec<_>>();
+            batches.push(build_table_i32(
+                ("a", &values),
+                ("b", &values),
+                ("c", &values),
+            ));
+            let mut offsets = vec![0u32; num_partitions + 1];
+            for p in 0..num_partitions {
+                offsets[p + 1] = match p {
+                    p if p < i as usize * 2 => 0,
+                    p if p == i as usize * 2 => 50,
+                    _ => 100,
+                };
+            }
+            batch_offsets.push(offsets);
+        }
+        let first_col = batches[0].column(0).clone();
+        let last_col = batches[3].column(0).clone();
+
+        let mut iter =
+            PartitionedBatchesIterator::try_new(batches, batch_offsets, 1000, num_partitions)?;
+        let mut num_rows = 0;
+        while let Some((partition_id, batch_iter)) = iter.next_partition_chunk()? {
+            num_rows += batch_iter.map(|batch| batch.num_rows()).sum::<usize>();
+
+            // batch 0 is dropped as soon as its partitions (0, 1) are consumed
+            if partition_id >= 2 {
+                assert_eq!(Arc::strong_count(&first_col), 1);
+            } else {
+                assert!(Arc::strong_count(&first_col) > 1);
+            }
+            assert!(Arc::strong_count(&last_col) > 1);
+            assert_eq!(iter.num_released_batches, partition_id / 2);
+
+            // released batches are not checked again
+            assert_eq!(iter.num_release_checked, iter.num_released_batches);
+        }
+        assert_eq!(num_rows, 400);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tiny_batch_coalescing() -> Result<()> {
+        let partitioning = Partitioning::HashPartitioning(vec![Arc::new(Column::new("a", 0))], 1);
+        let mut data =
+            BufferedData::try_new(partitioning, 0, Time::new())?.with_tiny_batch_coalescing(100);
+        for i in 0..1000 {
+            data.add_batch(build_table_i32(
+                ("a", &vec![i]),
+                ("b", &vec![i]),
+                ("c", &vec![i]),
+            ))?;
+
+            // tiny batches are pending until they are concatenated into one batch
+            if data.sorted_batches.is_empty() {
+                let num_pending = data.staging_batches.len() - data.staging_tiny_start;
+                assert_eq!(num_pending, (i as usize + 1) % 100);
+                assert!(
+                    data.staging_batches[..data.staging_tiny_start]
+                        .iter()
+                        .all(|batch| batch.num_rows() == 100)
+                );
+            }
+        }
+        let num_entries = data.staging_batches.len() + data.sorted_batches.len();
+        assert!(
+            num_entries <= 20,
+            "too many buffered entries: {num_entries}"
+        );
+        assert_eq!(data.num_rows, 1000);
+
+        // rows are kept in input order within the partition
+        let schema = build_table_i32(("a", &vec![]), ("b", &vec![]), ("c", &vec![])).schema();
+        let spills = data.write_in_mem_spills(usize::MAX)?;
+        let mut values = vec![];
+        let mut reader = IpcCompressionReader::new(Cursor::new(spills[0].data().clone()));
+        while let Some((_, cols)) = reader.read_batch(&schema)? {
+            values.extend(as_primitive_array::<Int32Type>(&cols[2]).values());
+        }
+        assert_eq!(values, (0..1000).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_in_mem_spill_offsets() -> Result<()> {
+        // consistent spills pass the check, with the same output whether enabled or not
+        let verified = build_buffered_data(16, 5)?
+            .with_in_mem_spill_offsets_verification(true)
+            .write_in_mem_spills(262144)?;
+        let unverified = build_buffered_data(16, 5)?
+            .with_in_mem_spill_offsets_verification(false)
+            .write_in_mem_spills(262144)?;
+        assert_eq!(verified.len(), unverified.len());
+        for (a, b) in verified.iter().zip(&unverified) {
+            verify_in_mem_spill_offsets(a.offsets(), a.data().len())?;
+            assert_eq!(a.offsets(), b.offsets());
+            assert_eq!(a.data(), b.data());
+        }
+
+        // inconsistent spills are only rejected when enabled
+        assert!(new_in_mem_spill(vec![0, 10, 20, 30], vec![0; 25], true).is_err());
+        assert!(new_in_mem_spill(vec![0, 10, 20, 30], vec![0; 25], false).is_ok());
+
+        // inject faults into offsets and data length
+        assert!(verify_in_mem_spill_offsets(&[0, 10, 20, 30], 30).is_ok());
+        assert!(verify_in_mem_spill_offsets(&[0, 10, 20, 30], 25).is_err());
+        assert!(verify_in_mem_spill_offsets(&[0, 10, 20, 30], 35).is_err());
+        assert!(verify_in_mem_spill_offsets(&[0, 20, 10, 30], 30).is_err());
+        assert!(verify_in_mem_spill_offsets(&[5, 10, 20, 30], 30).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_min_partition_frames() -> Result<()> {
+        // partition 0 holds 10000 rows, partition 1 holds 10 rows
+        let values = (0..10010).collect::<Vec<_>>();
+        let batch = build_table_i32(("a", &values), ("b", &values), ("c", &values));
+        let sort_exprs = vec![PhysicalSortExpr {
+            expr: Arc::new(Column::new("a", 0)),
+            options: SortOptions::default(),
+        }];
+        let bounds = RowConverter::new(vec![SortField::new(DataType::Int32)])?
+            .convert_columns(&[Arc::new(Int32Array::from(vec![9999])) as ArrayRef])?;
+        let partitioning = Partitioning::RangePartitioning(sort_exprs, 2, Arc::new(bounds));
+
+        let mut data =
+            BufferedData::try_new(partitioning, 0, Time::new())?.with_min_partition_frames(4, 1000);
+        data.add_batch(batch.clone())?;
+        let mut output = vec![];
+        let offsets = data.write(&mut output)?;
+
+        // counts frames, each frame is a 4-byte length followed by the compressed block
+        let count_frames = |mut buf: &[u8]| {
+            let mut num_frames = 0;
+            while !buf.is_empty() {
+                let len = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+                buf = &buf[4 + len..];
+                num_frames += 1;
+            }
+            num_frames
+        };
+        let partition0 = &output[offsets[0] as usize..offsets[1] as usize];
+        let partition1 = &output[offsets[1] as usize..offsets[2] as usize];
+        assert!(count_frames(partition0) >= 4);
+        assert_eq!(count_frames(partition1), 1);
+
+        // all rows are preserved
+        let mut num_rows = 0;
+        let mut reader = IpcCompressionReader::new(Cursor::new(output));
+        while let Some((batch_num_rows, _)) = reader.read_batch(&batch.schema())? {
+            num_rows += batch_num_rows;
+        }
+        assert_eq!(num_rows, 10010);
+        Ok(())
+    }
 }
@@ -0,0 +1,317 @@
+// Licensed to the Apache Software Foundation (ASF) under one or more
+// contributor license agreements.  See the NOTICE file distributed with
+// this work for additional information regarding copyright ownership.
+// The ASF licenses this file to You under the Apache License, Version 2.0
+// (the "License"); you may not use this file except in compliance with
+// the License.  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Map-side adaptive coalescing of shuffle partitions.
+//!
+//! When the total output size is small, adjacent logical partitions are
+//! merged into fewer physical partitions to avoid tiny blocks. The index file
+//! then holds offsets of the physical partitions, and a sidecar mapping file
+//! holds the range of logical partitions covered by each physical partition:
+//! number of physical partitions as u32, followed by the first logical
+//! partition id of each physical partition and the number of logical
+//! partitions as u32, then offsets of all logical partitions in the data file
+//! as u64, all in little-endian.
+//!
+//! Outputs with coalesced partitions are only readable by consumers aware of
+//! the mapping file, such as [`crate::shuffle::reader::ShuffleReader`].
+
+use std::{
+    io::{BufReader, Read, Write},
+    ops::Range,
+    path::Path,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use datafusion::common::Result;
+use datafusion_ext_commons::df_execution_err;
+
+use crate::shuffle::open_shuffle_file;
+
+/// returns path of the coalesced partitions mapping file of a shuffle index
+/// file
+pub fn coalesced_partitions_file(output_index_file: &str) -> String {
+    format!("{output_index_file}.mapping")
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptiveCoalesce {
+    /// only coalesces outputs whose total size is below this value
+    pub max_total_size: u64,
+    pub target_num_partitions: usize,
+}
+
+impl AdaptiveCoalesce {
+    /// coalesces partitions if the output is small enough, returns offsets of
+    /// the physical partitions to be written into the index file. the mapping
+    /// file is written only if partitions are coalesced.
+    pub fn apply(
+        &self,
+        offsets: &[u64],
+        output_index_file: &str,
+        exclusive_create: bool,
+    ) -> Result<Vec<u64>> {
+        let mapping_file = coalesced_partitions_file(output_index_file);
+        let num_partitions = offsets.len() - 1;
+        let total_size = offsets[num_partitions] - offsets[0];
+
+        if total_size >= self.max_total_size || self.target_num_partitions >= num_partitions {
+            // remove stale mapping file left by previous attempts
+            match std::fs::remove_file(&mapping_file) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            return Ok(offsets.to_vec());
+        }
+
+        let coalesced = CoalescedPartitions::new(offsets, self.target_num_partitions);
+        log::info!(
+            "coalescing {num_partitions} shuffle partitions into {} (total_size={total_size})",
+            coalesced.num_physical_partitions(),
+        );
+        coalesced.write(open_shuffle_file(&mapping_file, exclusive_create)?)?;
+        Ok(coalesced.physical_offsets())
+    }
+}
+
+/// mapping of logical partitions to coalesced physical partitions
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoalescedPartitions {
+    // first logical partition of each physical partition, followed by the
+    // number of logical partitions
+    logical_starts: Vec<usize>,
+    logical_offsets: Vec<u64>,
+}
+
+impl CoalescedPartitions {
+    /// groups adjacent logical partitions into at most target_num_partitions
+    /// physical partitions with similar sizes
+    pub fn new(offsets: &[u64], target_num_partitions: usize) -> Self {
+        let num_partitions = offsets.len() - 1;
+        let target = target_num_partitions.clamp(1, num_partitions.max(1));
+        let total_size = offsets[num_partitions] - offsets[0];
+
+        let mut logical_starts = vec![0];
+        for k in 1..target {
+            let threshold = offsets[0] + total_size * k as u64 / target as u64;
+            let prev = logical_starts[k - 1];
+            let start = offsets[..num_partitions]
+                .partition_point(|&offset| offset < threshold)
+                .clamp(prev + 1, num_partitions - (target - k));
+            logical_starts.push(start);
+        }
+        logical_starts.push(num_partitions);
+        Self {
+            logical_starts,
+            logical_offsets: offsets.to_vec(),
+        }
+    }
+
+    pub fn try_read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut r = BufReader::new(std::fs::File::open(path)?);
+        let num_physical_partitions = r.read_u32::<LittleEndian>()? as usize;
+        let mut logical_starts = Vec::with_capacity(num_physical_partitions + 1);
+        for _ in 0..num_physical_partitions + 1 {
+            logical_starts.push(r.read_u32::<LittleEndian>()? as usize);
+        }
+        if logical_starts.windows(2).any(|w| w[0] >= w[1]) || logical_starts[0] != 0 {
+            return df_execution_err!("invalid coalesced partitions mapping: {logical_starts:?}");
+        }
+        let num_logical_partitions = logical_starts[num_physical_partitions];
+        let mut logical_offsets = Vec::with_capacity(num_logical_partitions + 1);
+        for _ in 0..num_logical_partitions + 1 {
+            logical_offsets.push(r.read_u64::<LittleEndian>()?);
+        }
+        if logical_offsets.windows(2).any(|w| w[0] > w[1]) {
+            return df_execution_err!("invalid coalesced partitions offsets: {logical_offsets:?}");
+        }
+        Ok(Self {
+            logical_starts,
+            logical_offsets,
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
+        w.write_u32::<LittleEndian>(self.num_physical_partitions() as u32)?;
+        for &start in &self.logical_starts {
+            w.write_u32::<LittleEndian>(start as u32)?;
+        }
+        for &offset in &self.logical_offsets {
+            w.write_u64::<LittleEndian>(offset)?;
+        }
+        Ok(())
+    }
+
+    pub fn num_logical_partitions(&self) -> usize {
+        self.logical_starts[self.logical_starts.len() - 1]
+    }
+
+    pub fn num_physical_partitions(&self) -> usize {
+        self.logical_starts.len() - 1
+    }
+
+    /// returns the physical partition containing the logical partition
+    pub fn physical_partition(&self, logical_partition_id: usize) -> usize {
+        assert!(logical_partition_id < self.num_logical_partitions());
+        self.logical_starts
+            .partition_point(|&start| start <= logical_partition_id)
+            - 1
+    }
+
+    pub fn logical_partitions(&self, physical_partition_id: usize) -> Range<usize> {
+        self.logical_starts[physical_partition_id]..self.logical_starts[physical_partition_id + 1]
+    }
+
+    /// returns the byte range of the logical partition in the data file
+    pub fn logical_partition_range(&self, logical_partition_id: usize) -> Range<u64> {
+        self.logical_offsets[logical_partition_id]..self.logical_offsets[logical_partition_id + 1]
+    }
+
+    /// returns offsets of the physical partitions, as written into the index
+    /// file
+    pub fn physical_offsets(&self) -> Vec<u64> {
+        self.logical_starts
+            .iter()
+            .map(|&start| self.logical_offsets[start])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashSet, sync::Arc};
+
+    use arrow::{
+        array::{ArrayRef, Int32Array, as_primitive_array},
+        datatypes::{DataType, Field, Int32Type, Schema},
+        record_batch::RecordBatch,
+    };
+    use datafusion::{
+        physical_expr::expressions::Column,
+        physical_plan::metrics::{ExecutionPlanMetricsSet, Time},
+        prelude::SessionContext,
+    };
+    use datafusion_ext_commons::spark_hash::create_murmur3_hashes;
+
+    use super::*;
+    use crate::{
+        common::execution_context::ExecutionContext,
+        memmgr::MemManager,
+        shuffle::{
+            Partitioning, ShuffleRepartitioner,
+            reader::{ShuffleReader, read_index_offsets},
+            sort_repartitioner::SortShuffleRepartitioner,
+        },
+    };
+
+    #[test]
+    fn test_coalesced_partitions() {
+        let offsets = vec![0, 10, 10, 50, 60, 100, 100, 130, 200];
+        let coalesced = CoalescedPartitions::new(&offsets, 3);
+        assert_eq!(coalesced.num_logical_partitions(), 8);
+        assert_eq!(coalesced.num_physical_partitions(), 3);
+        assert_eq!(coalesced.logical_starts, vec![0, 5, 7, 8]);
+        assert_eq!(coalesced.physical_partition(0), 0);
+        assert_eq!(coalesced.physical_partition(4), 0);
+        assert_eq!(coalesced.physical_partition(5), 1);
+        assert_eq!(coalesced.physical_partition(7), 2);
+        assert_eq!(coalesced.logical_partitions(1), 5..7);
+        assert_eq!(coalesced.logical_partition_range(6), 100..130);
+        assert_eq!(coalesced.physical_offsets(), vec![0, 100, 130, 200]);
+
+        // never produces empty physical partitions
+        let coalesced = CoalescedPartitions::new(&[0, 0, 0, 0, 100], 4);
+        assert_eq!(coalesced.logical_starts, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_adaptive_coalesce() -> Result<()> {
+        MemManager::init(100);
+        let num_partitions = 16;
+        let dir = tempfile::tempdir()?;
+        let data_file = dir
+            .path()
+            .join("shuffle.data")
+            .to_string_lossy()
+            .to_string();
+        let index_file = dir
+            .path()
+            .join("shuffle.index")
+            .to_string_lossy()
+            .to_string();
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+
+        let exec_ctx = ExecutionContext::new(
+            SessionContext::new().task_ctx(),
+            0,
+            schema.clone(),
+            &ExecutionPlanMetricsSet::new(),
+        );
+        let repartitioner = Arc::new(
+            SortShuffleRepartitioner::try_new(
+                exec_ctx,
+                data_file.clone(),
+                index_file.clone(),
+                Partitioning::HashPartitioning(vec![Arc::new(Column::new("a", 0))], num_partitions),
+                Time::new(),
+            )?
+            .with_adaptive_coalesce(AdaptiveCoalesce {
+                max_total_size: 1 << 20,
+                target_num_partitions: 4,
+            }),
+        );
+        MemManager::register_consumer(repartitioner.clone(), true);
+        let values = (0..1000).collect::<Vec<i32>>();
+        let values_array: ArrayRef = Arc::new(Int32Array::from(values.clone()));
+        repartitioner
+            .insert_batch(RecordBatch::try_new(
+                schema.clone(),
+                vec![values_array.clone()],
+            )?)
+            .await?;
+        repartitioner.shuffle_write().await?;
+
+        // small output is coalesced into 4 physical partitions
+        let coalesced = CoalescedPartitions::try_read(coalesced_partitions_file(&index_file))?;
+        assert_eq!(coalesced.num_logical_partitions(), num_partitions);
+        assert_eq!(coalesced.num_physical_partitions(), 4);
+        let offsets = read_index_offsets(&index_file)?;
+        assert_eq!(offsets.len(), 5);
+
+        // the reader resolves logical partitions through the mapping file, every row
+        // is read from its logical partition
+        let reader = ShuffleReader::try_new(data_file, &index_file, schema)?;
+        assert_eq!(reader.num_partitions(), num_partitions);
+        let logical_values = (0..num_partitions)
+            .map(|logical_partition_id| {
+                Ok(reader
+                    .read_partition(logical_partition_id)?
+                    .iter()
+                    .flat_map(|batch| {
+                        as_primitive_array::<Int32Type>(batch.column(0))
+                            .values()
+                            .to_vec()
+                    })
+                    .collect::<HashSet<_>>())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let hashes = create_murmur3_hashes(values.len(), &[values_array], 42);
+        for (value, hash) in values.into_iter().zip(hashes) {
+            let logical_partition_id = hash.rem_euclid(num_partitions as i32) as usize;
+            assert!(logical_values[logical_partition_id].contains(&value));
+        }
+        assert_eq!(logical_values.iter().map(|v| v.len()).sum::<usize>(), 1000);
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+// Licensed to the Apache Software Foundation (ASF) under one or more
+// contributor license agreements.  See the NOTICE file distributed with
+// this work for additional information regarding copyright ownership.
+// The ASF licenses this file to You under the Apache License, Version 2.0
+// (the "License"); you may not use this file except in compliance with
+// the License.  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Shuffle index file layouts.
+//!
+//! The plain layout is spark's: `num_partitions + 1` i64 little-endian offsets.
+//! The checksummed layout splits offsets into fixed-size blocks, each followed
+//! by the CRC32C of the block, so that readers can validate the index
+//! block-by-block:
+//!
+//! ```text
+//! [version: u8][offsets_per_block: u32][num_offsets: u32]
+//! ([offset: i64] * offsets_per_block, [crc32c: u32])*
+//! ```
+//!
+//! The first offset of a plain index is always zero, so a non-zero leading
+//! version byte identifies the checksummed layout. Spark's shuffle block
+//! resolver only understands the plain layout, the checksummed layout is for
+//! native readers.
+
+use std::io::Write;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use datafusion::common::Result;
+use datafusion_ext_commons::{df_execution_err, hash::crc32c::crc32c};
+
+pub const CHECKSUMMED_INDEX_VERSION: u8 = 1;
+const CHECKSUMMED_INDEX_HEADER_SIZE: usize = 9;
+
+/// writes partition offsets as a shuffle index, in the plain layout if
+/// `checksum_block_size` (number of offsets in a block) is zero, otherwise in
+/// the checksummed layout
+pub fn write_index<W: Write>(mut w: W, offsets: &[u64], checksum_block_size: usize) -> Result<()> {
+    if checksum_block_size == 0 {
+        let mut index_data = Vec::with_capacity(offsets.len() * 8);
+        for &offset in offsets {
+            index_data.extend_from_slice(&(offset as i64).to_le_bytes()[..]);
+        }
+        w.write_all(&index_data)?;
+        return Ok(());
+    }
+
+    let mut index_data = Vec::with_capacity(
+        CHECKSUMMED_INDEX_HEADER_SIZE
+            + offsets.len() * 8
+            + offsets.len() / checksum_block_size * 4
+            + 4,
+    );
+    index_data.write_u8(CHECKSUMMED_INDEX_VERSION)?;
+    index_data.write_u32::<LittleEndian>(checksum_block_size as u32)?;
+    index_data.write_u32::<LittleEndian>(offsets.len() as u32)?;
+    for block in offsets.chunks(checksum_block_size) {
+        let block_start = index_data.len();
+        for &offset in block {
+            index_data.write_i64::<LittleEndian>(offset as i64)?;
+        }
+        let checksum = crc32c(&index_data[block_start..]);
+        index_data.write_u32::<LittleEndian>(checksum)?;
+    }
+    w.write_all(&index_data)?;
+    Ok(())
+}
+
+/// decodes partition offsets from a shuffle index in either layout, blocks of
+/// a checksummed index are validated against their CRC32C
+pub fn decode_index(index: &[u8]) -> Result<Vec<u64>> {
+    match index.first() {
+        Some(0) => decode_plain_index(index),
+        Some(&CHECKSUMMED_INDEX_VERSION) => decode_checksummed_index(index),
+        Some(version) => df_execution_err!("unsupported shuffle index version: {version}"),
+        None => df_execution_err!("invalid shuffle index file size: 0"),
+    }
+}
+
+fn decode_plain_index(index: &[u8]) -> Result<Vec<u64>> {
+    if index.len() < 8 || index.len() % 8 != 0 {
+        return df_execution_err!("invalid shuffle index file size: {}", index.len());
+    }
+    let mut offsets = Vec::with_capacity(index.len() / 8);
+    let mut cursor = index;
+    while !cursor.is_empty() {
+        offsets.push(cursor.read_i64::<LittleEndian>()? as u64);
+    }
+    Ok(offsets)
+}
+
+fn decode_checksummed_index(index: &[u8]) -> Result<Vec<u64>> {
+    if index.len() < CHECKSUMMED_INDEX_HEADER_SIZE {
+        return df_execution_err!("invalid shuffle index file size: {}", index.len());
+    }
+    let mut header = &index[1..CHECKSUMMED_INDEX_HEADER_SIZE];
+    let offsets_per_block = header.read_u32::<LittleEndian>()? as usize;
+    let num_offsets = header.read_u32::<LittleEndian>()? as usize;
+    let num_blocks = num_offsets.div_ceil(offsets_per_block.max(1));
+    let expected_len = CHECKSUMMED_INDEX_HEADER_SIZE + num_offsets * 8 + num_blocks * 4;
+    if offsets_per_block == 0 || num_offsets == 0 || index.len() != expected_len {
+        return df_execution_err!(
+            "invalid checksummed shuffle index: offsets_per_block={offsets_per_block}, \
+             num_offsets={num_offsets}, file size={}",
+            index.len()
+        );
+    }
+
+    let mut offsets = Vec::with_capacity(num_offsets);
+    let mut cursor = &index[CHECKSUMMED_INDEX_HEADER_SIZE..];
+    for block_id in 0..num_blocks {
+        let block_len = offsets_per_block.min(num_offsets - offsets.len()) * 8;
+        let (block, rest) = cursor.split_at(block_len);
+        let (mut checksum, rest) = rest.split_at(4);
+        let checksum = checksum.read_u32::<LittleEndian>()?;
+        let actual_checksum = crc32c(block);
+        if actual_checksum != checksum {
+            return df_execution_err!(
+                "shuffle index block {block_id} checksum mismatch: \
+                 expected={checksum:08x}, actual={actual_checksum:08x}"
+            );
+        }
+        let mut block = block;
+        while !block.is_empty() {
+            offsets.push(block.read_i64::<LittleEndian>()? as u64);
+        }
+        cursor = rest;
+    }
+    Ok(offsets)
+}
+
+#[cfg(test)]
+mod test {
+    use datafusion::common::Result;
+
+    use super::*;
+
+    #[test]
+    fn test_checksummed_index() -> Result<()> {
+        let offsets = (0..=100).map(|i| i * 1000).collect::<Vec<u64>>();
+
+        // plain layout is spark's
+        let mut plain = vec![];
+        write_index(&mut plain, &offsets, 0)?;
+        assert_eq!(plain.len(), 101 * 8);
+        assert_eq!(decode_index(&plain)?, offsets);
+
+        // 101 offsets in 7 blocks of 16 offsets
+        let mut index = vec![];
+        write_index(&mut index, &offsets, 16)?;
+        assert_eq!(index[0], CHECKSUMMED_INDEX_VERSION);
+        assert_eq!(index.len(), CHECKSUMMED_INDEX_HEADER_SIZE + 101 * 8 + 7 * 4);
+        assert_eq!(decode_index(&index)?, offsets);
+
+        // flipping a bit of an offset in the third block is detected by its crc
+        let mut corrupted = index.clone();
+        let pos = CHECKSUMMED_INDEX_HEADER_SIZE + 2 * (16 * 8 + 4) + 5 * 8 + 1;
+        corrupted[pos] ^= 0x01;
+        let err = decode_index(&corrupted).unwrap_err().to_string();
+        assert!(
+            err.contains("shuffle index block 2 checksum mismatch"),
+            "{err}"
+        );
+
+        // corrupting a checksum is detected as well
+        let mut corrupted = index.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0x80;
+        let err = decode_index(&corrupted).unwrap_err().to_string();
+        assert!(
+            err.contains("shuffle index block 6 checksum mismatch"),
+            "{err}"
+        );
+
+        // truncated index
+        assert!(decode_index(&index[..index.len() - 4]).is_err());
+        assert!(decode_index(&[2, 0, 0]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+// Licensed to the Apache Software Foundation (ASF) under one or more
+// contributor license agreements.  See the NOTICE file distributed with
+// this work for additional information regarding copyright ownership.
+// The ASF licenses this file to You under the Apache License, Version 2.0
+// (the "License"); you may not use this file except in compliance with
+// the License.  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Partition sizes reported in spark's MapStatus, so that native shuffle
+//! outputs are reported exactly as spark's own shuffle writers would do.
+//!
+//! Like spark, sizes are log-encoded into a single byte. With many partitions
+//! (HighlyCompressedMapStatus), only partitions not smaller than the accurate
+//! block threshold keep their own sizes, other non-empty partitions report the
+//! average size of them.
+
+use std::{collections::HashMap, path::Path};
+
+use auron_jni_bridge::{
+    conf,
+    conf::{IntConf, LongConf},
+    is_jni_bridge_inited,
+};
+use datafusion::common::{DataFusionError, Result};
+use once_cell::sync::OnceCell;
+
+use crate::shuffle::reader::read_index_offsets;
+
+const LOG_BASE: f64 = 1.1;
+
+/// same as spark's MapStatus.compressSize()
+pub fn compress_size(size: u64) -> u8 {
+    if size == 0 {
+        0
+    } else if size <= 1 {
+        1
+    } else {
+        ((size as f64).ln() / LOG_BASE.ln()).ceil().min(255.0) as u8
+    }
+}
+
+/// same as spark's MapStatus.decompressSize()
+pub fn decompress_size(compressed_size: u8) -> u64 {
+    if compressed_size == 0 {
+        0
+    } else {
+        LOG_BASE.powi(compressed_size as i32) as u64
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MapStatusOptions {
+    /// same as spark.shuffle.accurateBlockThreshold
+    pub accurate_block_threshold: u64,
+    /// same as spark.shuffle.minNumPartitionsToHighlyCompress
+    pub min_partitions_to_highly_compress: usize,
+}
+
+impl Default for MapStatusOptions {
+    fn default() -> Self {
+        Self {
+            accurate_block_threshold: 100 * 1024 * 1024,
+            min_partitions_to_highly_compress: 2000,
+        }
+    }
+}
+
+impl MapStatusOptions {
+    pub fn configured() -> Result<Self> {
+        static OPTIONS: OnceCell<MapStatusOptions> = OnceCell::new();
+        OPTIONS
+            .get_or_try_init(|| {
+                if is_jni_bridge_inited() {
+                    Ok::<_, DataFusionError>(Self {
+                        accurate_block_threshold: conf::SHUFFLE_ACCURATE_BLOCK_THRESHOLD.value()?
+                            as u64,
+                        min_partitions_to_highly_compress:
+                            conf::SHUFFLE_MIN_PARTITIONS_TO_HIGHLY_COMPRESS.value()? as usize,
+                    })
+                } else {
+                    Ok(Self::default()) // for testing
+                }
+            })
+            .copied()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MapStatusSizes {
+    /// CompressedMapStatus: every partition size is log-encoded
+    Compressed(Vec<u8>),
+    /// HighlyCompressedMapStatus: exact sizes for huge partitions only
+    HighlyCompressed {
+        num_partitions: usize,
+        empty_partitions: Vec<bool>,
+        avg_size: u64,
+        huge_partition_sizes: HashMap<usize, u8>,
+    },
+}
+
+impl MapStatusSizes {
+    pub fn new(partition_sizes: &[u64], options: &MapStatusOptions) -> Self {
+        if partition_sizes.len() <= options.min_partitions_to_highly_compress {
+            return Self::Compressed(partition_sizes.iter().map(|&s| compress_size(s)).collect());
+        }
+
+        let mut num_small_partitions = 0;
+        let mut total_small_size = 0;
+        let mut huge_partition_sizes = HashMap::new();
+        for (partition_id, &size) in partition_sizes.iter().enumerate() {
+            if size >= options.accurate_block_threshold {
+                huge_partition_sizes.insert(partition_id, compress_size(size));
+            } else if size > 0 {
+                num_small_partitions += 1;
+                total_small_size += size;
+            }
+        }
+        Self::HighlyCompressed {
+            num_partitions: partition_sizes.len(),
+            empty_partitions: partition_sizes.iter().map(|&s| s == 0).collect(),
+            avg_size: match num_small_partitions {
+                0 => 0,
+                n => total_small_size / n,
+            },
+            huge_partition_sizes,
+        }
+    }
+
+    /// computes sizes from partition offsets in a shuffle index file
+    pub fn try_from_index_file<P: AsRef<Path>>(
+        index_file: P,
+        options: &MapStatusOptions,
+    ) -> Result<Self> {
+        let offsets = read_index_offsets(index_file)?;
+        let partition_sizes = offsets.windows(2).map(|w| w[1] - w[0]).collect::<Vec<_>>();
+        Ok(Self::new(&partition_sizes, options))
+    }
+
+    pub fn num_partitions(&self) -> usize {
+        match self {
+            Self::Compressed(sizes) => sizes.len(),
+            Self::HighlyCompressed { num_partitions, .. } => *num_partitions,
+        }
+    }
+
+    /// returns the size reported to the driver, same as spark's
+    /// MapStatus.getSizeForBlock()
+    pub fn size_for_partition(&self, partition_id: usize) -> u64 {
+        match self {
+            Self::Compressed(sizes) => decompress_size(sizes[partition_id]),
+            Self::HighlyCompressed {
+                empty_partitions,
+                avg_size,
+                huge_partition_sizes,
+                ..
+            } => {
+                if empty_partitions[partition_id] {
+                    return 0;
+                }
+                match huge_partition_sizes.get(&partition_id) {
+                    Some(&compressed_size) => decompress_size(compressed_size),
+                    None => *avg_size,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compress_size() {
+        // values computed by spark's MapStatus
+        assert_eq!(compress_size(0), 0);
+        assert_eq!(compress_size(1), 1);
+        assert_eq!(compress_size(2), 8);
+        assert_eq!(compress_size(1000), 73);
+        assert_eq!(compress_size(1000000), 145);
+        assert_eq!(compress_size(u64::MAX), 255);
+        assert_eq!(decompress_size(0), 0);
+        assert_eq!(decompress_size(1), 1);
+        assert_eq!(decompress_size(73), 1051);
+        for size in [2, 10, 1000, 123456, 1 << 30] {
+            let decompressed = decompress_size(compress_size(size));
+            assert!(decompressed >= size * 9 / 10 && decompressed <= size * 11 / 10);
+        }
+    }
+
+    #[test]
+    fn test_map_status_sizes() {
+        let options = MapStatusOptions {
+            accurate_block_threshold: 1000,
+            min_partitions_to_highly_compress: 4,
+        };
+        let partition_sizes = vec![0, 100, 200, 300, 5000, 1000, 0];
+        let sizes = MapStatusSizes::new(&partition_sizes, &options);
+        assert!(matches!(sizes, MapStatusSizes::HighlyCompressed { .. }));
+        assert_eq!(sizes.num_partitions(), 7);
+
+        // empty partitions report 0, small ones report the average
+        for partition_id in [0, 6] {
+            assert_eq!(sizes.size_for_partition(partition_id), 0);
+        }
+        for partition_id in [1, 2, 3] {
+            assert_eq!(sizes.size_for_partition(partition_id), 200);
+        }
+
+        // partitions not smaller than the threshold report their own sizes
+        for partition_id in [4, 5] {
+            assert_eq!(
+                sizes.size_for_partition(partition_id),
+                decompress_size(compress_size(partition_sizes[partition_id])),
+            );
+        }
+
+        // all sizes are encoded with few partitions
+        let options = MapStatusOptions {
+            min_partitions_to_highly_compress: 2000,
+            ..options
+        };
+        let sizes = MapStatusSizes::new(&partition_sizes, &options);
+        assert!(matches!(sizes, MapStatusSizes::Compressed(..)));
+        for (partition_id, &size) in partition_sizes.iter().enumerate() {
+            assert_eq!(
+                sizes.size_for_partition(partition_id),
+                decompress_size(compress_size(size)),
+            );
+        }
+    }
+}
@@ -26,11 +26,15 @@ use std::{
 
 use arrow::{
     array::ArrayRef,
-    error::Result as ArrowResult,
     record_batch::RecordBatch,
     row::{Row, RowConverter, Rows, SortField},
 };
 use async_trait::async_trait;
+use auron_jni_bridge::{
+    conf,
+    conf::{BooleanConf, StringConf},
+    is_jni_bridge_inited,
+};
 use bytesize::ByteSize;
 use datafusion::{
     common::Result,
@@ -38,8 +42,11 @@ use datafusion::{
     physical_expr::{PhysicalExprRef, PhysicalSortExpr},
     physical_plan::SendableRecordBatchStream,
 };
-use datafusion_ext_commons::{arrow::array_size::BatchSize, spark_hash::create_murmur3_hashes};
+use datafusion_ext_commons::{
+    arrow::array_size::BatchSize, df_execution_err, spark_hash::HashCombiner,
+};
 use futures::StreamExt;
+use once_cell::sync::OnceCell;
 use parking_lot::Mutex as SyncMutex;
 
 use crate::common::execution_context::ExecutionContext;
@@ -48,9 +55,17 @@ pub mod single_repartitioner;
 pub mod sort_repartitioner;
 
 pub mod buffered_data;
+pub mod coalesce;
+pub mod index;
+pub mod map_status;
+pub mod partition_files;
+pub mod range_index;
+pub mod reader;
 mod rss;
 pub mod rss_single_repartitioner;
 pub mod rss_sort_repartitioner;
+pub mod spill_trace;
+pub mod stage_metrics;
 
 #[async_trait]
 pub trait ShuffleRepartitioner: Send + Sync {
@@ -160,21 +175,105 @@ impl fmt::Display for Partitioning {
     }
 }
 
-fn evaluate_hashes(partitioning: &Partitioning, batch: &RecordBatch) -> ArrowResult<Vec<i32>> {
+fn evaluate_hash_keys(partitioning: &Partitioning, batch: &RecordBatch) -> Result<Vec<ArrayRef>> {
     match partitioning {
-        Partitioning::HashPartitioning(exprs, _) => {
-            let arrays = exprs
-                .iter()
-                .map(|expr| Ok(expr.evaluate(batch)?.into_array(batch.num_rows())?))
-                .collect::<Result<Vec<_>>>()?;
-
-            // compute hash array, use identical seed as spark hash partition
-            Ok(create_murmur3_hashes(arrays[0].len(), &arrays, 42))
-        }
+        Partitioning::HashPartitioning(exprs, _) => exprs
+            .iter()
+            .map(|expr| expr.evaluate(batch)?.into_array(batch.num_rows()))
+            .collect(),
         _ => unreachable!("unsupported partitioning: {:?}", partitioning),
     }
 }
 
+fn hash_keys(keys: &[ArrayRef]) -> Result<Vec<i32>> {
+    // compute hash array, use identical seed as spark hash partition
+    Ok(configured_hash_combiner()?.create_murmur3_hashes(
+        keys[0].len(),
+        keys,
+        42,
+        vectorized_hashing_enabled()?,
+    ))
+}
+
+fn vectorized_hashing_enabled() -> Result<bool> {
+    static ENABLED: OnceCell<bool> = OnceCell::new();
+    ENABLED
+        .get_or_try_init(|| {
+            if is_jni_bridge_inited() {
+                conf::SHUFFLE_VECTORIZED_HASHING_ENABLE.value()
+            } else {
+                Ok(false) // for testing
+            }
+        })
+        .copied()
+}
+
+fn configured_hash_combiner() -> Result<HashCombiner> {
+    static COMBINER: OnceCell<HashCombiner> = OnceCell::new();
+    COMBINER
+        .get_or_try_init(|| {
+            if is_jni_bridge_inited() {
+                HashCombiner::try_from_names(
+                    &conf::SHUFFLE_HASH_COMBINE_ORDER.value()?,
+                    &conf::SHUFFLE_HASH_COMBINE_METHOD.value()?,
+                )
+            } else {
+                Ok(HashCombiner::default()) // for testing
+            }
+        })
+        .copied()
+}
+
+/// Side to which rows equal to a range partitioning boundary are assigned.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RangeBoundaryTieBreak {
+    /// spark: rows equal to `bounds[i]` go to partition `i`, each boundary is
+    /// the inclusive upper bound of the lower partition
+    #[default]
+    Lower,
+    /// rows equal to `bounds[i]` go to partition `i + 1`, each boundary is the
+    /// inclusive lower bound of the upper partition
+    Upper,
+}
+
+impl RangeBoundaryTieBreak {
+    pub fn try_from_name(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "lower" => Ok(Self::Lower),
+            "upper" => Ok(Self::Upper),
+            _ => df_execution_err!("unsupported range boundary tie-breaking: {name}"),
+        }
+    }
+}
+
+fn configured_range_boundary_tie_break() -> Result<RangeBoundaryTieBreak> {
+    static TIE_BREAK: OnceCell<RangeBoundaryTieBreak> = OnceCell::new();
+    TIE_BREAK
+        .get_or_try_init(|| {
+            if is_jni_bridge_inited() {
+                RangeBoundaryTieBreak::try_from_name(
+                    &conf::SHUFFLE_RANGE_BOUNDARY_TIE_BREAK.value()?,
+                )
+            } else {
+                Ok(RangeBoundaryTieBreak::default()) // for testing
+            }
+        })
+        .copied()
+}
+
+fn output_exclusive_create_enabled() -> Result<bool> {
+    static ENABLED: OnceCell<bool> = OnceCell::new();
+    ENABLED
+        .get_or_try_init(|| {
+            if is_jni_bridge_inited() {
+                conf::SHUFFLE_OUTPUT_EXCLUSIVE_CREATE.value()
+            } else {
+                Ok(false) // for testing
+            }
+        })
+        .copied()
+}
+
 fn evaluate_partition_ids(mut hashes: Vec<i32>, num_partitions: usize) -> Vec<u32> {
     // evaluate part_id = pmod(hash, num_partitions)
     for h in &mut hashes {
@@ -205,6 +304,7 @@ fn evaluate_range_partition_ids(
     batch: &RecordBatch,
     sort_expr: &Vec<PhysicalSortExpr>,
     bound_rows: &Arc<Rows>,
+    tie_break: RangeBoundaryTieBreak,
 ) -> Result<Vec<u32>> {
     let num_rows = batch.num_rows();
 
@@ -231,23 +331,32 @@ fn evaluate_range_partition_ids(
     let key_rows = sort_row_converter.lock().convert_columns(&key_cols)?;
     let mut vec_u32 = Vec::with_capacity(num_rows);
     for key_row in key_rows.iter() {
-        let partition = get_partition(key_row, bound_rows, true);
+        let partition = get_partition(key_row, bound_rows, true, tie_break);
         vec_u32.push(partition);
     }
     Ok(vec_u32)
 }
 
-fn get_partition(key_row: Row, bound_rows: &Arc<Rows>, ascending: bool) -> u32 {
+fn get_partition(
+    key_row: Row,
+    bound_rows: &Arc<Rows>,
+    ascending: bool,
+    tie_break: RangeBoundaryTieBreak,
+) -> u32 {
     let mut partition = 0;
     let num_rows = bound_rows.num_rows();
     if num_rows <= 128 {
         // If we have less than 128 partitions naive search
-        while partition < num_rows && key_row > bound_rows.row(partition) {
+        let passes_bound = |bound: Row| match tie_break {
+            RangeBoundaryTieBreak::Lower => key_row > bound,
+            RangeBoundaryTieBreak::Upper => key_row >= bound,
+        };
+        while partition < num_rows && passes_bound(bound_rows.row(partition)) {
             partition += 1;
         }
     } else {
         // Determine which binary search method to use only once.
-        partition = binary_search(bound_rows, key_row, 0, num_rows as isize);
+        partition = binary_search(bound_rows, key_row, 0, num_rows as isize, tie_break);
         // binarySearch either returns the match location or -[insertion point]-1
         if partition > num_rows {
             partition = num_rows
@@ -259,7 +368,13 @@ fn get_partition(key_row: Row, bound_rows: &Arc<Rows>, ascending: bool) -> u32 {
     partition as u32
 }
 
-fn binary_search(rows: &Arc<Rows>, target: Row, from_index: isize, to_index: isize) -> usize {
+fn binary_search(
+    rows: &Arc<Rows>,
+    target: Row,
+    from_index: isize,
+    to_index: isize,
+    tie_break: RangeBoundaryTieBreak,
+) -> usize {
     let mut low: isize = from_index;
     let mut high: isize = to_index - 1;
 
@@ -271,6 +386,8 @@ fn binary_search(rows: &Arc<Rows>, target: Row, from_index: isize, to_index: isi
             low = mid + 1;
         } else if mid_val > target {
             high = mid - 1;
+        } else if tie_break == RangeBoundaryTieBreak::Upper {
+            low = mid + 1; // key found, continue to the last equal bound
         } else {
             return mid as usize; // key found
         }
@@ -278,13 +395,19 @@ fn binary_search(rows: &Arc<Rows>, target: Row, from_index: isize, to_index: isi
     return low as usize; // key not found.
 }
 
-pub fn open_shuffle_file<P: AsRef<Path>>(path: P) -> std::io::Result<File> {
+/// opens a shuffle output file for writing. an existing file is truncated,
+/// unless `exclusive_create` is set, in which case opening fails if the file
+/// already exists (O_EXCL), so that duplicate writers are detected.
+pub fn open_shuffle_file<P: AsRef<Path>>(path: P, exclusive_create: bool) -> std::io::Result<File> {
     let path_ref = path.as_ref();
-    let file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(path_ref)?;
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if exclusive_create {
+        options.create_new(true);
+    } else {
+        options.create(true).truncate(true);
+    }
+    let file = options.open(path_ref)?;
 
     // Set the shuffle file permissions to 0644 to keep it consistent with the
     // permissions of the built-in shuffler manager in Spark.
@@ -292,3 +415,34 @@ pub fn open_shuffle_file<P: AsRef<Path>>(path: P) -> std::io::Result<File> {
 
     Ok(file)
 }
+
+/// opens the data and index files of a shuffle output, see
+/// [`open_shuffle_file`]. with `exclusive_create`, the data file created here
+/// is removed if the index file cannot be created, so that a failed writer
+/// leaves no partial output behind.
+pub fn open_shuffle_files<P: AsRef<Path>>(
+    data_file: P,
+    index_file: P,
+    exclusive_create: bool,
+) -> std::io::Result<(File, File)> {
+    let data = open_shuffle_file(&data_file, exclusive_create)?;
+    let index = open_shuffle_file_or_remove(&index_file, &data_file, exclusive_create)?;
+    Ok((data, index))
+}
+
+/// opens a shuffle output file, with `exclusive_create` the already created
+/// `created_file` is removed if opening fails
+pub fn open_shuffle_file_or_remove<P: AsRef<Path>>(
+    path: P,
+    created_file: P,
+    exclusive_create: bool,
+) -> std::io::Result<File> {
+    open_shuffle_file(&path, exclusive_create).inspect_err(|_| {
+        if exclusive_create && let Err(e) = std::fs::remove_file(created_file.as_ref()) {
+            log::warn!(
+                "error removing shuffle file {}: {e}",
+                created_file.as_ref().display()
+            );
+        }
+    })
+}
@@ -0,0 +1,429 @@
+// Licensed to the Apache Software Foundation (ASF) under one or more
+// contributor license agreements.  See the NOTICE file distributed with
+// this work for additional information regarding copyright ownership.
+// The ASF licenses this file to You under the Apache License, Version 2.0
+// (the "License"); you may not use this file except in compliance with
+// the License.  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-partition-file output layout.
+//!
+//! The data file written by a repartitioner is split into one dedicated file
+//! per large partition, while partitions smaller than a minimum size are
+//! grouped into a shared small-partitions file to avoid many tiny files. A
+//! sidecar layout file records the location of every partition: number of
+//! partitions as u32, followed by the file kind (u8, 0 for the shared file and
+//! 1 for a dedicated file), offset and length (u64) of each partition, then the
+//! extension of partition file names (u32 length and utf-8 bytes), all in
+//! little-endian.
+//!
+//! The index file is kept and still holds the logical offsets, but the data
+//! file is removed, so the output is only readable by consumers aware of the
+//! layout file.
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use datafusion::common::Result;
+use datafusion_ext_commons::df_execution_err;
+
+use crate::{memmgr::spill::normalize_file_extension, shuffle::open_shuffle_file};
+
+/// returns path of the partition layout file of a shuffle data file
+pub fn partition_layout_file(output_data_file: &str) -> String {
+    format!("{output_data_file}.layout")
+}
+
+/// returns path of the file shared by small partitions
+pub fn small_partitions_file(output_data_file: &str, extension: &str) -> String {
+    format!("{output_data_file}.small{extension}")
+}
+
+/// returns path of the dedicated file of a large partition
+pub fn dedicated_partition_file(
+    output_data_file: &str,
+    partition_id: usize,
+    extension: &str,
+) -> String {
+    format!("{output_data_file}.part-{partition_id}{extension}")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionFileKind {
+    Small,
+    Dedicated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartitionLocation {
+    pub kind: PartitionFileKind,
+    pub offset: u64,
+    pub len: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionFileLayout {
+    locations: Vec<PartitionLocation>,
+    extension: String,
+}
+
+impl PartitionFileLayout {
+    /// partitions with at least `min_partition_bytes` bytes get dedicated
+    /// files, others are appended to the shared file in partition order
+    pub fn new(offsets: &[u64], min_partition_bytes: u64) -> Self {
+        let mut small_offset = 0;
+        let locations = offsets
+            .windows(2)
+            .map(|w| {
+                let len = w[1] - w[0];
+                if len > 0 && len >= min_partition_bytes {
+                    PartitionLocation {
+                        kind: PartitionFileKind::Dedicated,
+                        offset: 0,
+                        len,
+                    }
+                } else {
+                    small_offset += len;
+                    PartitionLocation {
+                        kind: PartitionFileKind::Small,
+                        offset: small_offset - len,
+                        len,
+                    }
+                }
+            })
+            .collect();
+        Self {
+            locations,
+            extension: String::new(),
+        }
+    }
+
+    /// appends `extension` (e.g. ".blaze-shuffle") to names of partition files
+    pub fn with_extension(mut self, extension: &str) -> Self {
+        self.extension = normalize_file_extension(extension);
+        self
+    }
+
+    pub fn try_read<R: Read>(mut r: R) -> Result<Self> {
+        let num_partitions = r.read_u32::<LittleEndian>()? as usize;
+        let mut locations = Vec::with_capacity(num_partitions);
+        for _ in 0..num_partitions {
+            let kind = match r.read_u8()? {
+                0 => PartitionFileKind::Small,
+                1 => PartitionFileKind::Dedicated,
+                kind => return df_execution_err!("invalid partition file kind: {kind}"),
+            };
+            let offset = r.read_u64::<LittleEndian>()?;
+            let len = r.read_u64::<LittleEndian>()?;
+            locations.push(PartitionLocation { kind, offset, len });
+        }
+        let mut extension = vec![0; r.read_u32::<LittleEndian>()? as usize];
+        r.read_exact(&mut extension)?;
+        let extension = match String::from_utf8(extension) {
+            Ok(extension) => extension,
+            Err(e) => return df_execution_err!("invalid partition file extension: {e}"),
+        };
+        Ok(Self {
+            locations,
+            extension,
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut w: W) -> Result<()> {
+        w.write_u32::<LittleEndian>(self.locations.len() as u32)?;
+        for location in &self.locations {
+            w.write_u8(match location.kind {
+                PartitionFileKind::Small => 0,
+                PartitionFileKind::Dedicated => 1,
+            })?;
+            w.write_u64::<LittleEndian>(location.offset)?;
+            w.write_u64::<LittleEndian>(location.len)?;
+        }
+        w.write_u32::<LittleEndian>(self.extension.len() as u32)?;
+        w.write_all(self.extension.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn num_partitions(&self) -> usize {
+        self.locations.len()
+    }
+
+    pub fn location(&self, partition_id: usize) -> PartitionLocation {
+        self.locations[partition_id]
+    }
+
+    pub fn extension(&self) -> &str {
+        &self.extension
+    }
+
+    /// returns path of the file holding the partition
+    pub fn partition_file(&self, output_data_file: &str, partition_id: usize) -> String {
+        match self.locations[partition_id].kind {
+            PartitionFileKind::Small => small_partitions_file(output_data_file, &self.extension),
+            PartitionFileKind::Dedicated => {
+                dedicated_partition_file(output_data_file, partition_id, &self.extension)
+            }
+        }
+    }
+
+    pub fn num_dedicated_files(&self) -> usize {
+        self.locations
+            .iter()
+            .filter(|location| location.kind == PartitionFileKind::Dedicated)
+            .count()
+    }
+}
+
+/// splits a shuffle data file into per-partition files and writes the layout
+/// file, the data file is removed afterwards. names of partition files carry
+/// the given extension, which is recorded in the layout file for readers
+pub fn split_partition_files(
+    output_data_file: &str,
+    offsets: &[u64],
+    min_partition_bytes: u64,
+    extension: &str,
+    exclusive_create: bool,
+) -> Result<PartitionFileLayout> {
+    let layout = PartitionFileLayout::new(offsets, min_partition_bytes).with_extension(extension);
+    let mut data = BufReader::new(File::open(output_data_file)?);
+    let mut small_output = BufWriter::new(open_shuffle_file(
+        small_partitions_file(output_data_file, layout.extension()),
+        exclusive_create,
+    )?);
+
+    for partition_id in 0..layout.num_partitions() {
+        let location = layout.location(partition_id);
+        if location.len == 0 {
+            continue;
+        }
+        data.seek(SeekFrom::Start(offsets[partition_id]))?;
+        let mut partition_data = (&mut data).take(location.len);
+        match location.kind {
+            PartitionFileKind::Small => {
+                std::io::copy(&mut partition_data, &mut small_output)?;
+            }
+            PartitionFileKind::Dedicated => {
+                let path = layout.partition_file(output_data_file, partition_id);
+                let mut output = BufWriter::new(open_shuffle_file(path, exclusive_create)?);
+                std::io::copy(&mut partition_data, &mut output)?;
+                output.flush()?;
+            }
+        }
+    }
+    small_output.flush()?;
+
+    let layout_file = partition_layout_file(output_data_file);
+    layout.write(open_shuffle_file(layout_file, exclusive_create)?)?;
+    std::fs::remove_file(output_data_file)?;
+
+    log::info!(
+        "split shuffle output into {} dedicated partition files and a small partitions file",
+        layout.num_dedicated_files(),
+    );
+    Ok(layout)
+}
+
+/// reads the partition layout of a shuffle data file, returns None if the
+/// output is not split into per-partition files
+pub fn read_partition_layout(output_data_file: &str) -> Result<Option<PartitionFileLayout>> {
+    let layout_file = partition_layout_file(output_data_file);
+    if !Path::new(&layout_file).exists() {
+        return Ok(None);
+    }
+    let layout = PartitionFileLayout::try_read(BufReader::new(File::open(layout_file)?))?;
+    Ok(Some(layout))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::Path, sync::Arc};
+
+    use arrow::{
+        array::{Int32Array, StringArray},
+        datatypes::{DataType, Field, Schema},
+        record_batch::RecordBatch,
+        row::{RowConverter, SortField},
+    };
+    use arrow_schema::SortOptions;
+    use datafusion::{
+        common::Result,
+        physical_expr::{PhysicalSortExpr, expressions::Column},
+        physical_plan::metrics::{ExecutionPlanMetricsSet, Time},
+        prelude::SessionContext,
+    };
+
+    use super::*;
+    use crate::{
+        common::execution_context::ExecutionContext,
+        memmgr::MemManager,
+        shuffle::{
+            Partitioning, ShuffleRepartitioner, reader::ShuffleReader,
+            sort_repartitioner::SortShuffleRepartitioner,
+        },
+    };
+
+    #[test]
+    fn test_partition_file_layout() -> Result<()> {
+        let layout = PartitionFileLayout::new(&[0, 10, 10, 1000, 1005, 3000], 100);
+        assert_eq!(layout.num_partitions(), 5);
+        assert_eq!(layout.num_dedicated_files(), 2);
+        let kinds = (0..5).map(|i| layout.location(i).kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                PartitionFileKind::Small,
+                PartitionFileKind::Small,
+                PartitionFileKind::Dedicated,
+                PartitionFileKind::Small,
+                PartitionFileKind::Dedicated,
+            ]
+        );
+        assert_eq!(layout.location(3).offset, 10);
+
+        let mut buf = vec![];
+        layout.write(&mut buf)?;
+        assert_eq!(PartitionFileLayout::try_read(&buf[..])?, layout);
+
+        // extension is normalized and persisted
+        let layout = layout.with_extension("blaze-shuffle");
+        assert_eq!(layout.extension(), ".blaze-shuffle");
+        assert_eq!(
+            layout.partition_file("a.data", 0),
+            "a.data.small.blaze-shuffle"
+        );
+        assert_eq!(
+            layout.partition_file("a.data", 2),
+            "a.data.part-2.blaze-shuffle"
+        );
+        let mut buf = vec![];
+        layout.write(&mut buf)?;
+        assert_eq!(PartitionFileLayout::try_read(&buf[..])?, layout);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_split_partition_files() -> Result<()> {
+        split_and_read_partition_files("").await?;
+        split_and_read_partition_files(".blaze-shuffle").await?;
+        Ok(())
+    }
+
+    async fn split_and_read_partition_files(extension: &str) -> Result<()> {
+        MemManager::init(100);
+        let dir = tempfile::tempdir()?;
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, false),
+            Field::new("b", DataType::Utf8, false),
+        ]));
+
+        // range partitioning: partitions 1 and 3 are large, others are tiny
+        let bounds = [10, 1000, 1010];
+        let bounds = RowConverter::new(vec![SortField::new(DataType::Int32)])?
+            .convert_columns(&[Arc::new(Int32Array::from(bounds.to_vec())) as _])?;
+        let partitioning = Partitioning::RangePartitioning(
+            vec![PhysicalSortExpr {
+                expr: Arc::new(Column::new("a", 0)),
+                options: SortOptions::default(),
+            }],
+            4,
+            Arc::new(bounds),
+        );
+        let data_file = dir
+            .path()
+            .join("shuffle.data")
+            .to_string_lossy()
+            .to_string();
+        let index_file = dir.path().join("shuffle.index");
+        let exec_ctx = ExecutionContext::new(
+            SessionContext::new().task_ctx(),
+            0,
+            schema.clone(),
+            &ExecutionPlanMetricsSet::new(),
+        );
+        let repartitioner = Arc::new(
+            SortShuffleRepartitioner::try_new(
+                exec_ctx,
+                data_file.clone(),
+                index_file.to_string_lossy().to_string(),
+                partitioning,
+                Time::new(),
+            )?
+            .with_partition_files(1024)
+            .with_output_file_extension(extension),
+        );
+        MemManager::register_consumer(repartitioner.clone(), true);
+
+        let values = (0..2000).collect::<Vec<i32>>();
+        let strings = values
+            .iter()
+            .map(|&v| format!("{:016x}", (v as u64).wrapping_mul(0x9e3779b97f4a7c15)))
+            .collect::<Vec<_>>();
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int32Array::from(values)),
+                Arc::new(StringArray::from(strings)),
+            ],
+        )?;
+        repartitioner.insert_batch(batch).await?;
+        repartitioner.shuffle_write().await?;
+
+        // tiny partitions are in the shared file, large ones in dedicated files
+        assert!(!Path::new(&data_file).exists());
+        let layout = read_partition_layout(&data_file)?.expect("missing layout file");
+        let kinds = (0..4).map(|i| layout.location(i).kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                PartitionFileKind::Small,
+                PartitionFileKind::Dedicated,
+                PartitionFileKind::Small,
+                PartitionFileKind::Dedicated,
+            ]
+        );
+        assert_eq!(layout.extension(), extension);
+        assert!(Path::new(&small_partitions_file(&data_file, extension)).exists());
+        assert!(Path::new(&dedicated_partition_file(&data_file, 1, extension)).exists());
+        assert!(Path::new(&dedicated_partition_file(&data_file, 3, extension)).exists());
+        assert!(!Path::new(&dedicated_partition_file(&data_file, 0, extension)).exists());
+
+        // all created files carry the configured extension
+        let file_names = std::fs::read_dir(dir.path())?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+            .collect::<Result<Vec<_>>>()?;
+        for file_name in &file_names {
+            if file_name.contains(".small") || file_name.contains(".part-") {
+                assert!(file_name.ends_with(extension), "{file_name}");
+            }
+        }
+        assert_eq!(
+            file_names
+                .iter()
+                .filter(|name| name.contains(".small") || name.contains(".part-"))
+                .count(),
+            3
+        );
+
+        // all partitions are readable
+        let reader = ShuffleReader::try_new(data_file, index_file, schema)?;
+        let num_rows = (0..4)
+            .map(|partition_id| {
+                let batches = reader.read_partition(partition_id).unwrap();
+                batches.iter().map(|batch| batch.num_rows()).sum::<usize>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(num_rows, vec![11, 990, 10, 989]);
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+// Licensed to the Apache Software Foundation (ASF) under one or more
+// contributor license agreements.  See the NOTICE file distributed with
+// this work for additional information regarding copyright ownership.
+// The ASF licenses this file to You under the Apache License, Version 2.0
+// (the "License"); you may not use this file except in compliance with
+// the License.  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sidecar index of range partitioning bounds, for mapping keys to their
+//! output partitions without scanning the data file.
+//!
+//! The index starts with a header (magic `BRBI`, format version, boundary
+//! tie-breaking as u8 since version 2, number of partitions and number of
+//! bounds as u32), followed by each bound encoded in arrow row format as a u32
+//! length and the row bytes. All integers are little endian. Version 1 indices
+//! have no tie-breaking byte and always use [`RangeBoundaryTieBreak::Lower`].
+
+use std::io::{Read, Write};
+
+use arrow::row::Rows;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use datafusion::common::Result;
+use datafusion_ext_commons::df_execution_err;
+
+use crate::shuffle::RangeBoundaryTieBreak;
+
+const RANGE_BOUNDS_INDEX_MAGIC: &[u8; 4] = b"BRBI";
+const RANGE_BOUNDS_INDEX_VERSION: u8 = 2;
+
+/// returns path of the range bounds index sidecar of a shuffle index file
+pub fn range_bounds_index_file(output_index_file: &str) -> String {
+    format!("{output_index_file}.bounds")
+}
+
+pub fn write_range_bounds_index<W: Write>(
+    mut w: W,
+    num_partitions: usize,
+    bounds: &Rows,
+    tie_break: RangeBoundaryTieBreak,
+) -> Result<()> {
+    w.write_all(RANGE_BOUNDS_INDEX_MAGIC)?;
+    w.write_u8(RANGE_BOUNDS_INDEX_VERSION)?;
+    w.write_u8(match tie_break {
+        RangeBoundaryTieBreak::Lower => 0,
+        RangeBoundaryTieBreak::Upper => 1,
+    })?;
+    w.write_u32::<LittleEndian>(num_partitions as u32)?;
+    w.write_u32::<LittleEndian>(bounds.num_rows() as u32)?;
+    for bound in bounds.iter() {
+        let bound = bound.as_ref();
+        w.write_u32::<LittleEndian>(bound.len() as u32)?;
+        w.write_all(bound)?;
+    }
+    Ok(())
+}
+
+/// range bounds loaded from a sidecar written by [`write_range_bounds_index`]
+pub struct RangeBoundsIndex {
+    num_partitions: usize,
+    bounds: Vec<Box<[u8]>>,
+    tie_break: RangeBoundaryTieBreak,
+}
+
+impl RangeBoundsIndex {
+    pub fn try_read<R: Read>(mut r: R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != RANGE_BOUNDS_INDEX_MAGIC {
+            return df_execution_err!("invalid range bounds index header");
+        }
+        let version = r.read_u8()?;
+        let tie_break = match version {
+            1 => RangeBoundaryTieBreak::Lower,
+            2 => match r.read_u8()? {
+                0 => RangeBoundaryTieBreak::Lower,
+                1 => RangeBoundaryTieBreak::Upper,
+                other => {
+                    return df_execution_err!("invalid range boundary tie-breaking: {other}");
+                }
+            },
+            _ => return df_execution_err!("unsupported range bounds index version: {version}"),
+        };
+        let num_partitions = r.read_u32::<LittleEndian>()? as usize;
+        let num_bounds = r.read_u32::<LittleEndian>()? as usize;
+
+        let mut bounds = Vec::with_capacity(num_bounds);
+        for _ in 0..num_bounds {
+            let len = r.read_u32::<LittleEndian>()? as usize;
+            let mut bound = vec![0u8; len];
+            r.read_exact(&mut bound)?;
+            bounds.push(bound.into_boxed_slice());
+        }
+        Ok(Self {
+            num_partitions,
+            bounds,
+            tie_break,
+        })
+    }
+
+    pub fn num_partitions(&self) -> usize {
+        self.num_partitions
+    }
+
+    pub fn tie_break(&self) -> RangeBoundaryTieBreak {
+        self.tie_break
+    }
+
+    /// returns the partition containing the key, which must be encoded in
+    /// arrow row format with the same sort fields as the range partitioning.
+    /// the result is identical to the partition id computed on the write side.
+    pub fn lookup(&self, key: &[u8]) -> u32 {
+        let partition = match self.tie_break {
+            RangeBoundaryTieBreak::Lower => self.bounds.partition_point(|b| b.as_ref() < key),
+            RangeBoundaryTieBreak::Upper => self.bounds.partition_point(|b| b.as_ref() <= key),
+        };
+        partition.min(self.num_partitions.saturating_sub(1)) as u32
+    }
+
+    pub fn lookup_rows(&self, keys: &Rows) -> Vec<u32> {
+        keys.iter().map(|key| self.lookup(key.as_ref())).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashSet, sync::Arc};
+
+    use arrow::{
+        array::{ArrayRef, Int32Array, as_primitive_array},
+        datatypes::{DataType, Field, Int32Type, Schema},
+        record_batch::RecordBatch,
+        row::{RowConverter, SortField},
+    };
+    use arrow_schema::SortOptions;
+    use datafusion::{
+        physical_expr::{PhysicalSortExpr, expressions::Column},
+        physical_plan::metrics::{ExecutionPlanMetricsSet, Time},
+        prelude::SessionContext,
+    };
+
+    use super::*;
+    use crate::{
+        common::execution_context::ExecutionContext,
+        memmgr::MemManager,
+        shuffle::{
+            Partitioning, ShuffleRepartitioner, evaluate_range_partition_ids,
+            reader::ShuffleReader, sort_repartitioner::SortShuffleRepartitioner,
+        },
+    };
+
+    #[tokio::test]
+    async fn test_range_bounds_index() -> Result<()> {
+        MemManager::init(100);
+        let dir = tempfile::tempdir()?;
+        let data_file = dir
+            .path()
+            .join("shuffle.data")
+            .to_string_lossy()
+            .to_string();
+        let index_file = dir
+            .path()
+            .join("shuffle.index")
+            .to_string_lossy()
+            .to_string();
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+
+        // range partitioning with bounds [100, 200, 300]
+        let row_converter = RowConverter::new(vec![SortField::new_with_options(
+            DataType::Int32,
+            SortOptions::default(),
+        )])?;
+        let bounds = row_converter
+            .convert_columns(&[Arc::new(Int32Array::from(vec![100, 200, 300])) as ArrayRef])?;
+        let partitioning = Partitioning::RangePartitioning(
+            vec![PhysicalSortExpr {
+                expr: Arc::new(Column::new("a", 0)),
+                options: SortOptions::default(),
+            }],
+            4,
+            Arc::new(bounds),
+        );
+
+        let exec_ctx = ExecutionContext::new(
+            SessionContext::new().task_ctx(),
+            0,
+            schema.clone(),
+            &ExecutionPlanMetricsSet::new(),
+        );
+        let repartitioner = Arc::new(
+            SortShuffleRepartitioner::try_new(
+                exec_ctx,
+                data_file.clone(),
+                index_file.clone(),
+                partitioning,
+                Time::new(),
+            )?
+            .with_range_bounds_index(true),
+        );
+        MemManager::register_consumer(repartitioner.clone(), true);
+        let values = (0..400).rev().collect::<Vec<i32>>();
+        repartitioner
+            .insert_batch(RecordBatch::try_new(
+                schema.clone(),
+                vec![Arc::new(Int32Array::from(values))],
+            )?)
+            .await?;
+        repartitioner.shuffle_write().await?;
+
+        // map sample keys to partitions with the sidecar index
+        let index =
+            RangeBoundsIndex::try_read(std::fs::File::open(range_bounds_index_file(&index_file))?)?;
+        assert_eq!(index.num_partitions(), 4);
+        let sample_keys = vec![-1, 0, 99, 100, 101, 200, 250, 300, 301, 399, 1000];
+        let sample_rows = row_converter
+            .convert_columns(&[Arc::new(Int32Array::from(sample_keys.clone())) as ArrayRef])?;
+        let partition_ids = index.lookup_rows(&sample_rows);
+        assert_eq!(partition_ids, vec![0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 3]);
+
+        // check the keys are really located in the mapped partitions
+        let reader = ShuffleReader::try_new(data_file, &index_file, schema)?;
+        for (key, partition_id) in sample_keys.into_iter().zip(partition_ids) {
+            if !(0..400).contains(&key) {
+                continue;
+            }
+            let partition_values = reader
+                .read_partition(partition_id as usize)?
+                .iter()
+                .flat_map(|batch| {
+                    as_primitive_array::<Int32Type>(batch.column(0))
+                        .values()
+                        .to_vec()
+                })
+                .collect::<HashSet<_>>();
+            assert!(partition_values.contains(&key));
+        }
+        Ok(())
+    }
+    #[test]
+    fn test_range_bounds_index_tie_break() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let sort_exprs = vec![PhysicalSortExpr {
+            expr: Arc::new(Column::new("a", 0)),
+            options: SortOptions::default(),
+        }];
+        let row_converter = RowConverter::new(vec![SortField::new(DataType::Int32)])?;
+        let bounds = Arc::new(
+            row_converter
+                .convert_columns(&[Arc::new(Int32Array::from(vec![100, 200, 300])) as ArrayRef])?,
+        );
+
+        // keys equal to bounds resolve to the same partitions as the write side
+        let keys: ArrayRef = Arc::new(Int32Array::from(vec![99, 100, 101, 200, 300, 301]));
+        let batch = RecordBatch::try_new(schema, vec![keys.clone()])?;
+        let key_rows = row_converter.convert_columns(&[keys])?;
+        for (tie_break, expected) in [
+            (RangeBoundaryTieBreak::Lower, vec![0, 0, 1, 1, 2, 3]),
+            (RangeBoundaryTieBreak::Upper, vec![0, 1, 1, 2, 3, 3]),
+        ] {
+            let mut buf = vec![];
+            write_range_bounds_index(&mut buf, 4, &bounds, tie_break)?;
+            let index = RangeBoundsIndex::try_read(&buf[..])?;
+            assert_eq!(index.tie_break(), tie_break);
+            assert_eq!(index.lookup_rows(&key_rows), expected);
+            assert_eq!(
+                evaluate_range_partition_ids(&batch, &sort_exprs, &bounds, tie_break)?,
+                expected
+            );
+        }
+
+        // version 1 indices have no tie-breaking byte
+        let mut buf = vec![];
+        write_range_bounds_index(&mut buf, 4, &bounds, RangeBoundaryTieBreak::Upper)?;
+        buf[4] = 1;
+        buf.remove(5);
+        let index = RangeBoundsIndex::try_read(&buf[..])?;
+        assert_eq!(index.tie_break(), RangeBoundaryTieBreak::Lower);
+        assert_eq!(index.lookup_rows(&key_rows), vec![0, 0, 1, 1, 2, 3]);
+        Ok(())
+    }
+}
@@ -0,0 +1,363 @@
+// Licensed to the Apache Software Foundation (ASF) under one or more
+// contributor license agreements.  See the NOTICE file distributed with
+// this work for additional information regarding copyright ownership.
+// The ASF licenses this file to You under the Apache License, Version 2.0
+// (the "License"); you may not use this file except in compliance with
+// the License.  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
+    path::Path,
+    sync::Arc,
+};
+
+use arrow::{
+    datatypes::SchemaRef,
+    record_batch::{RecordBatch, RecordBatchOptions},
+};
+use datafusion::common::Result;
+use datafusion_ext_commons::df_execution_err;
+use futures::{Stream, StreamExt};
+
+use crate::{
+    common::ipc_compression::IpcCompressionReader,
+    shuffle::{
+        ShuffleRepartitioner,
+        coalesce::{CoalescedPartitions, coalesced_partitions_file},
+        index::decode_index,
+        partition_files::{PartitionFileLayout, read_partition_layout},
+        sort_repartitioner::SortShuffleRepartitioner,
+    },
+};
+
+/// reads a shuffle output (data file + index file) written by a shuffle
+/// repartitioner. partitions are always addressed by their logical ids, also
+/// when the output is coalesced (see [`crate::shuffle::coalesce`]).
+#[derive(Clone)]
+pub struct ShuffleReader {
+    data_file: String,
+    offsets: Vec<u64>,
+    schema: SchemaRef,
+    partition_layout: Option<PartitionFileLayout>,
+    coalesced: Option<CoalescedPartitions>,
+}
+
+impl ShuffleReader {
+    pub fn try_new<P: AsRef<Path>>(
+        data_file: String,
+        index_file: P,
+        schema: SchemaRef,
+    ) -> Result<Self> {
+        let mapping_file = coalesced_partitions_file(&index_file.as_ref().to_string_lossy());
+        let offsets = read_index_offsets(index_file)?;
+        let coalesced = match Path::new(&mapping_file).exists() {
+            true => Some(CoalescedPartitions::try_read(&mapping_file)?),
+            false => None,
+        };
+        if let Some(coalesced) = &coalesced
+            && coalesced.physical_offsets() != offsets
+        {
+            return df_execution_err!(
+                "coalesced partitions mapping mismatches index: {coalesced:?}"
+            );
+        }
+        let partition_layout = read_partition_layout(&data_file)?;
+        let data_len = match &partition_layout {
+            Some(_) => offsets[offsets.len() - 1],
+            None => std::fs::metadata(&data_file)?.len(),
+        };
+        if offsets.windows(2).any(|w| w[0] > w[1]) || offsets[offsets.len() - 1] > data_len {
+            return df_execution_err!(
+                "invalid shuffle index: offsets={offsets:?}, data file size={data_len}"
+            );
+        }
+        if let Some(layout) = &partition_layout {
+            let sizes_matched = layout.num_partitions() == offsets.len() - 1
+                && (0..layout.num_partitions())
+                    .all(|i| layout.location(i).len == offsets[i + 1] - offsets[i]);
+            if !sizes_matched {
+                return df_execution_err!("shuffle partition layout mismatches index: {layout:?}");
+            }
+        }
+        Ok(Self {
+            data_file,
+            offsets,
+            schema,
+            partition_layout,
+            coalesced,
+        })
+    }
+
+    pub fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    pub fn num_partitions(&self) -> usize {
+        match &self.coalesced {
+            Some(coalesced) => coalesced.num_logical_partitions(),
+            None => self.offsets.len() - 1,
+        }
+    }
+
+    pub fn partition_size(&self, partition_id: usize) -> u64 {
+        let range = self.partition_range(partition_id);
+        range.end - range.start
+    }
+
+    // byte range of the logical partition in the data file
+    fn partition_range(&self, partition_id: usize) -> Range<u64> {
+        match &self.coalesced {
+            Some(coalesced) => coalesced.logical_partition_range(partition_id),
+            None => self.offsets[partition_id]..self.offsets[partition_id + 1],
+        }
+    }
+
+    pub fn read_partition(&self, partition_id: usize) -> Result<Vec<RecordBatch>> {
+        if self.partition_size(partition_id) == 0 {
+            return Ok(vec![]);
+        }
+        let range = self.partition_range(partition_id);
+        let physical_partition_id = match &self.coalesced {
+            Some(coalesced) => coalesced.physical_partition(partition_id),
+            None => partition_id,
+        };
+        let (path, offset) = match &self.partition_layout {
+            Some(layout) => (
+                layout.partition_file(&self.data_file, physical_partition_id),
+                layout.location(physical_partition_id).offset + range.start
+                    - self.offsets[physical_partition_id],
+            ),
+            None => (self.data_file.clone(), range.start),
+        };
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = IpcCompressionReader::new(file.take(self.partition_size(partition_id)));
+
+        let mut batches = vec![];
+        while let Some((num_rows, cols)) = reader.read_batch(&self.schema)? {
+            batches.push(RecordBatch::try_new_with_options(
+                self.schema.clone(),
+                cols,
+                &RecordBatchOptions::new().with_row_count(Some(num_rows)),
+            )?);
+        }
+        Ok(batches)
+    }
+
+    /// reads the given partitions in parallel, each with an independent file
+    /// handle. partitions are yielded in the given order, and at most
+    /// `concurrency` partitions are decoded and buffered at a time, so memory
+    /// stays bounded as long as the consumer keeps pulling.
+    pub fn read_partitions_concurrently(
+        &self,
+        partition_ids: Vec<usize>,
+        concurrency: usize,
+    ) -> impl Stream<Item = Result<(usize, Vec<RecordBatch>)>> + Send + 'static {
+        let reader = self.clone();
+        futures::stream::iter(partition_ids)
+            .map(move |partition_id| {
+                let reader = reader.clone();
+                async move {
+                    let batches =
+                        tokio::task::spawn_blocking(move || reader.read_partition(partition_id))
+                            .await
+                            .expect("tokio spawn_blocking error")?;
+                    Ok((partition_id, batches))
+                }
+            })
+            .buffered(concurrency.max(1))
+    }
+}
+
+/// reads partition offsets from a shuffle index file, in either the plain or
+/// the checksummed layout
+pub fn read_index_offsets<P: AsRef<Path>>(index_file: P) -> Result<Vec<u64>> {
+    decode_index(&std::fs::read(index_file)?)
+}
+
+/// re-shuffles an existing shuffle output into the given repartitioner, which
+/// is typically created with a different partitioning. all rows are preserved
+/// and the new output is written by the repartitioner's shuffle_write().
+///
+/// the repartitioner must be registered to the memory manager.
+pub async fn repartition_shuffle_output(
+    reader: &ShuffleReader,
+    repartitioner: Arc<SortShuffleRepartitioner>,
+) -> Result<()> {
+    for partition_id in 0..reader.num_partitions() {
+        let reader = reader.clone();
+        let batches = tokio::task::spawn_blocking(move || reader.read_partition(partition_id))
+            .await
+            .expect("tokio spawn_blocking error")?;
+        for batch in batches {
+            repartitioner.insert_batch(batch).await?;
+        }
+    }
+    repartitioner.shuffle_write().await
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arrow::{
+        array::{Int32Array, StringArray, as_primitive_array, as_string_array},
+        datatypes::{DataType, Field, Int32Type, Schema},
+        record_batch::RecordBatch,
+    };
+    use datafusion::{
+        common::Result,
+        physical_expr::expressions::Column,
+        physical_plan::metrics::{ExecutionPlanMetricsSet, Time},
+        prelude::SessionContext,
+    };
+    use futures::TryStreamExt;
+
+    use super::*;
+    use crate::{
+        common::execution_context::ExecutionContext,
+        memmgr::MemManager,
+        shuffle::{Partitioning, sort_repartitioner::SortShuffleRepartitioner},
+    };
+
+    fn new_repartitioner(
+        dir: &Path,
+        name: &str,
+        schema: SchemaRef,
+        num_partitions: usize,
+    ) -> Result<Arc<SortShuffleRepartitioner>> {
+        let exec_ctx = ExecutionContext::new(
+            SessionContext::new().task_ctx(),
+            0,
+            schema,
+            &ExecutionPlanMetricsSet::new(),
+        );
+        let repartitioner = Arc::new(SortShuffleRepartitioner::try_new(
+            exec_ctx,
+            dir.join(format!("{name}.data"))
+                .to_string_lossy()
+                .to_string(),
+            dir.join(format!("{name}.index"))
+                .to_string_lossy()
+                .to_string(),
+            Partitioning::HashPartitioning(vec![Arc::new(Column::new("a", 0))], num_partitions),
+            Time::new(),
+        )?);
+        MemManager::register_consumer(repartitioner.clone(), true);
+        Ok(repartitioner)
+    }
+
+    fn read_all_rows(reader: &ShuffleReader) -> Result<Vec<(i32, String)>> {
+        let mut rows = vec![];
+        for partition_id in 0..reader.num_partitions() {
+            for batch in reader.read_partition(partition_id)? {
+                let a = as_primitive_array::<Int32Type>(batch.column(0));
+                let b = as_string_array(batch.column(1));
+                rows.extend((0..batch.num_rows()).map(|i| (a.value(i), b.value(i).to_string())));
+            }
+        }
+        rows.sort_unstable();
+        Ok(rows)
+    }
+
+    // writes an 8-partition shuffle output of 1000 rows
+    async fn write_input(dir: &Path, schema: SchemaRef) -> Result<ShuffleReader> {
+        let repartitioner = new_repartitioner(dir, "input", schema.clone(), 8)?;
+        for i in 0..10 {
+            let values = (i * 100..i * 100 + 100).collect::<Vec<i32>>();
+            let strings = values.iter().map(|v| format!("v{v}")).collect::<Vec<_>>();
+            let batch = RecordBatch::try_new(
+                schema.clone(),
+                vec![
+                    Arc::new(Int32Array::from(values)),
+                    Arc::new(StringArray::from(strings)),
+                ],
+            )?;
+            repartitioner.insert_batch(batch).await?;
+        }
+        repartitioner.shuffle_write().await?;
+        let input = ShuffleReader::try_new(
+            dir.join("input.data").to_string_lossy().to_string(),
+            dir.join("input.index"),
+            schema,
+        )?;
+        assert_eq!(input.num_partitions(), 8);
+        Ok(input)
+    }
+
+    fn test_schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, false),
+            Field::new("b", DataType::Utf8, false),
+        ]))
+    }
+
+    #[tokio::test]
+    async fn test_repartition_shuffle_output() -> Result<()> {
+        MemManager::init(100);
+        let dir = tempfile::tempdir()?;
+        let schema = test_schema();
+        let input = write_input(dir.path(), schema.clone()).await?;
+
+        // re-partition it into 3 partitions
+        let repartitioner = new_repartitioner(dir.path(), "output", schema.clone(), 3)?;
+        repartition_shuffle_output(&input, repartitioner).await?;
+        let output = ShuffleReader::try_new(
+            dir.path().join("output.data").to_string_lossy().to_string(),
+            dir.path().join("output.index"),
+            schema.clone(),
+        )?;
+        assert_eq!(output.num_partitions(), 3);
+
+        let input_rows = read_all_rows(&input)?;
+        assert_eq!(input_rows.len(), 1000);
+        assert_eq!(read_all_rows(&output)?, input_rows);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_read_partitions_concurrently() -> Result<()> {
+        MemManager::init(100);
+        let dir = tempfile::tempdir()?;
+        let input = write_input(dir.path(), test_schema()).await?;
+
+        let partition_ids = vec![6, 0, 3, 7, 1];
+        let results = input
+            .read_partitions_concurrently(partition_ids.clone(), 3)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        // partitions are yielded in the requested order with identical rows
+        assert_eq!(
+            results.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            partition_ids
+        );
+        for (partition_id, batches) in &results {
+            let sequential = input.read_partition(*partition_id)?;
+            assert_eq!(batches, &sequential);
+        }
+        let num_rows = results
+            .iter()
+            .flat_map(|(_, batches)| batches)
+            .map(|batch| batch.num_rows())
+            .sum::<usize>();
+        let expected_num_rows = partition_ids
+            .iter()
+            .flat_map(|&partition_id| input.read_partition(partition_id).unwrap())
+            .map(|batch| batch.num_rows())
+            .sum::<usize>();
+        assert!(num_rows > 0);
+        assert_eq!(num_rows, expected_num_rows);
+        Ok(())
+    }
+}
@@ -34,21 +34,21 @@ pub struct RssSortShuffleRepartitioner {
 }
 
 impl RssSortShuffleRepartitioner {
-    pub fn new(
+    pub fn try_new(
         partition_id: usize,
         rss_partition_writer: GlobalRef,
         partitioning: Partitioning,
         output_io_time: Time,
-    ) -> Self {
-        Self {
+    ) -> Result<Self> {
+        Ok(Self {
             mem_consumer_info: None,
-            data: Mutex::new(BufferedData::new(
+            data: Mutex::new(BufferedData::try_new(
                 partitioning,
                 partition_id,
                 output_io_time,
-            )),
+            )?),
             rss: rss_partition_writer,
-        }
+        })
     }
 }
 
@@ -89,17 +89,25 @@ impl Drop for RssSortShuffleRepartitioner {
 #[async_trait]
 impl ShuffleRepartitioner for RssSortShuffleRepartitioner {
     async fn insert_batch(&self, input: RecordBatch) -> Result<()> {
-        // update memory usage before adding to buffered data
-        let mem_used = self.data.lock().await.mem_used() + input.get_batch_mem_size() * 2;
-        self.update_mem_used(mem_used).await?;
-
-        // add batch to buffered data
-        let mem_used = {
-            let mut data = self.data.lock().await;
-            data.add_batch(input)?;
-            data.mem_used()
-        };
-        self.update_mem_used(mem_used).await?;
+        // bound memory growing of concurrent insertions between reporting the
+        // growth and re-reporting the actual usage. the permit is released
+        // before force spilling
+        let grow_size = input.get_batch_mem_size() * 2;
+        {
+            let _grow_permit = MemManager::get().acquire_grow_permit(grow_size).await;
+
+            // update memory usage before adding to buffered data
+            let mem_used = self.data.lock().await.mem_used() + grow_size;
+            self.update_mem_used(mem_used).await?;
+
+            // add batch to buffered data
+            let mem_used = {
+                let mut data = self.data.lock().await;
+                data.add_batch(input)?;
+                data.mem_used()
+            };
+            self.update_mem_used(mem_used).await?;
+        }
 
         // we are likely to spill more frequently because the cost of spilling a shuffle
         // repartition is lower than other consumers.
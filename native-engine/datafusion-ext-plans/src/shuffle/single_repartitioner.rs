@@ -29,7 +29,10 @@ use crate::{
         ipc_compression::IpcCompressionWriter,
         timer_helper::{TimedWriter, TimerHelper},
     },
-    shuffle::{ShuffleRepartitioner, open_shuffle_file},
+    shuffle::{
+        ShuffleRepartitioner, open_shuffle_file, open_shuffle_file_or_remove, open_shuffle_files,
+        output_exclusive_create_enabled,
+    },
 };
 
 pub struct SingleShuffleRepartitioner {
@@ -37,16 +40,22 @@ pub struct SingleShuffleRepartitioner {
     output_index_file: String,
     output_data: Arc<Mutex<Option<IpcCompressionWriter<TimedWriter<File>>>>>,
     output_io_time: Time,
+    exclusive_create: bool,
 }
 
 impl SingleShuffleRepartitioner {
-    pub fn new(output_data_file: String, output_index_file: String, output_io_time: Time) -> Self {
-        Self {
+    pub fn try_new(
+        output_data_file: String,
+        output_index_file: String,
+        output_io_time: Time,
+    ) -> Result<Self> {
+        Ok(Self {
             output_data_file,
             output_index_file,
             output_data: Arc::new(Mutex::default()),
             output_io_time,
-        }
+            exclusive_create: output_exclusive_create_enabled()?,
+        })
     }
 
     fn get_output_writer<'a>(
@@ -54,10 +63,9 @@ impl SingleShuffleRepartitioner {
         output_data: &'a mut Option<IpcCompressionWriter<TimedWriter<File>>>,
     ) -> Result<&'a mut IpcCompressionWriter<TimedWriter<File>>> {
         if output_data.is_none() {
-            *output_data = Some(IpcCompressionWriter::new(
-                self.output_io_time
-                    .wrap_writer(open_shuffle_file(&self.output_data_file)?),
-            ));
+            *output_data = Some(IpcCompressionWriter::new(self.output_io_time.wrap_writer(
+                open_shuffle_file(&self.output_data_file, self.exclusive_create)?,
+            )));
         }
         Ok(output_data.as_mut().unwrap())
     }
@@ -77,21 +85,23 @@ impl ShuffleRepartitioner for SingleShuffleRepartitioner {
 
         // write index file
         if let Some(output_writer) = output_data.as_mut() {
-            let mut output_index = self
-                .output_io_time
-                .wrap_writer(open_shuffle_file(&self.output_index_file)?);
+            let mut output_index = self.output_io_time.wrap_writer(open_shuffle_file_or_remove(
+                &self.output_index_file,
+                &self.output_data_file,
+                self.exclusive_create,
+            )?);
             output_writer.finish_current_buf()?;
             let offset = output_writer.inner_mut().0.stream_position()?;
             output_index.write_all(&[0u8; 8])?;
             output_index.write_all(&(offset as i64).to_le_bytes()[..])?;
         } else {
             // write empty data file and index file
-            let _output_data = self
-                .output_io_time
-                .wrap_writer(open_shuffle_file(&self.output_data_file)?);
-            let mut output_index = self
-                .output_io_time
-                .wrap_writer(open_shuffle_file(&self.output_index_file)?);
+            let (_output_data, output_index) = open_shuffle_files(
+                &self.output_data_file,
+                &self.output_index_file,
+                self.exclusive_create,
+            )?;
+            let mut output_index = self.output_io_time.wrap_writer(output_index);
             output_index.write_all(&[0u8; 16])?;
         }
         Ok(())
@@ -14,23 +14,42 @@
 // limitations under the License.
 
 use std::{
-    io::{Read, Write},
-    sync::{Arc, Weak},
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, Write},
+    path::Path,
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicUsize, Ordering::Relaxed},
+    },
+    time::Instant,
 };
 
-use arrow::record_batch::RecordBatch;
+use arrow::{datatypes::SchemaRef, record_batch::RecordBatch, row::Rows};
 use async_trait::async_trait;
+use auron_jni_bridge::{
+    conf,
+    conf::{BooleanConf, DoubleConf, IntConf, LongConf, StringConf},
+    is_jni_bridge_inited,
+};
 use bytesize::ByteSize;
 use datafusion::{
     common::{DataFusionError, Result},
     physical_plan::metrics::Time,
 };
-use datafusion_ext_commons::{arrow::array_size::BatchSize, df_execution_err};
+use datafusion_ext_commons::{
+    arrow::array_size::{ArraySize, BatchSize},
+    df_execution_err,
+};
 use futures::lock::Mutex;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex as SyncMutex;
 
 use crate::{
     common::{
         execution_context::ExecutionContext,
+        interrupt_retry::InterruptRetry,
+        ipc_compression::transcode_to_zstd_seekable,
         offsetted::{Offsetted, OffsettedMergeIterator},
         timer_helper::TimerHelper,
     },
@@ -38,7 +57,19 @@ use crate::{
         MemConsumer, MemConsumerInfo, MemManager,
         spill::{OwnedSpillBufReader, Spill, try_new_spill},
     },
-    shuffle::{Partitioning, ShuffleRepartitioner, buffered_data::BufferedData, open_shuffle_file},
+    shuffle::{
+        Partitioning, ShuffleRepartitioner,
+        buffered_data::{BufferedData, NullKeyFallback},
+        coalesce::AdaptiveCoalesce,
+        configured_range_boundary_tie_break,
+        index::write_index,
+        open_shuffle_file, open_shuffle_files, output_exclusive_create_enabled,
+        partition_files::split_partition_files,
+        range_index::{range_bounds_index_file, write_range_bounds_index},
+        reader::read_index_offsets,
+        spill_trace::{SpillTarget, SpillTrace, SpillTraceRecord},
+        stage_metrics::{ShuffleStageMetrics, StageSpillContribution},
+    },
 };
 
 pub struct SortShuffleRepartitioner {
@@ -50,32 +81,487 @@ pub struct SortShuffleRepartitioner {
     spills: Mutex<Vec<Offsetted<u64, Box<dyn Spill>>>>,
     num_output_partitions: usize,
     output_io_time: Time,
+    max_in_mem_spill_size: usize,
+    spill_trace: Option<Arc<SpillTrace>>,
+    exclusive_create: bool,
+    column_sizes_enabled: bool,
+    column_mem_sizes: SyncMutex<Vec<usize>>,
+    column_serialized_sizes: OnceCell<HashMap<String, u64>>,
+    range_bounds: Option<Arc<Rows>>,
+    range_bounds_index: bool,
+    adaptive_coalesce: Option<AdaptiveCoalesce>,
+    file_spills_first: bool,
+    merge_progress: Arc<MergeProgress>,
+    index_checksum_block_size: usize,
+    max_interrupt_retries: usize,
+    in_mem_spill_ratio: Option<f64>,
+    min_partition_file_bytes: Option<u64>,
+    output_file_extension: String,
+    zstd_seekable_frame_size: Option<usize>,
+    stage_spill_contribution: Option<StageSpillContribution>,
+    grow_in_flight: AtomicUsize,
+    peak_grow_in_flight: AtomicUsize,
+}
+
+/// Live progress of merging spills in `shuffle_write`, updated by the
+/// blocking merge task and readable by monitors from other threads.
+#[derive(Debug, Default)]
+pub struct MergeProgress {
+    num_partitions: usize,
+    current_partition: AtomicUsize,
+    remaining_cursors: AtomicUsize,
+}
+
+impl MergeProgress {
+    pub fn new(num_partitions: usize) -> Self {
+        Self {
+            num_partitions,
+            ..Default::default()
+        }
+    }
+
+    /// partition currently being written, equals to `num_partitions` after
+    /// merging is finished
+    pub fn current_partition(&self) -> usize {
+        self.current_partition.load(Relaxed)
+    }
+
+    /// number of spill cursors which still have partitions to merge
+    pub fn remaining_cursors(&self) -> usize {
+        self.remaining_cursors.load(Relaxed)
+    }
+
+    pub fn percent(&self) -> f64 {
+        if self.num_partitions == 0 {
+            return 100.0;
+        }
+        self.current_partition() as f64 * 100.0 / self.num_partitions as f64
+    }
+
+    fn update(&self, current_partition: usize, remaining_cursors: usize) {
+        self.current_partition.store(current_partition, Relaxed);
+        self.remaining_cursors.store(remaining_cursors, Relaxed);
+    }
+}
+
+/// configurations used by [`SortShuffleRepartitioner::try_new`], read only
+/// once
+struct SortShuffleConf {
+    unsafe_row_output: bool,
+    column_sizes_enabled: bool,
+    null_key_fallback_ratio: f64,
+    max_in_mem_spill_size: usize,
+    range_bounds_index: bool,
+    adaptive_coalesce: Option<AdaptiveCoalesce>,
+    file_spills_first: bool,
+    index_checksum_block_size: usize,
+    max_interrupt_retries: usize,
+    in_mem_spill_ratio: Option<f64>,
+    output_file_extension: String,
+    zstd_seekable_frame_size: Option<usize>,
+    spill_trace_dir: Option<String>,
+}
+
+fn sort_shuffle_conf() -> Result<&'static SortShuffleConf> {
+    static CONF: OnceCell<SortShuffleConf> = OnceCell::new();
+    CONF.get_or_try_init(|| {
+        if !is_jni_bridge_inited() {
+            // for testing
+            return Ok(SortShuffleConf {
+                unsafe_row_output: false,
+                column_sizes_enabled: false,
+                null_key_fallback_ratio: 0.0,
+                max_in_mem_spill_size: usize::MAX,
+                range_bounds_index: false,
+                adaptive_coalesce: None,
+                file_spills_first: false,
+                index_checksum_block_size: 0,
+                max_interrupt_retries: 100,
+                in_mem_spill_ratio: None,
+                output_file_extension: String::new(),
+                zstd_seekable_frame_size: None,
+                spill_trace_dir: None,
+            });
+        }
+        let max_in_mem_spill_size = conf::SHUFFLE_MAX_IN_MEM_SPILL_SIZE.value()?;
+        let adaptive_coalesce_max_size = conf::SHUFFLE_ADAPTIVE_COALESCE_MAX_SIZE.value()?;
+        let in_mem_spill_ratio = conf::SHUFFLE_IN_MEM_SPILL_RATIO.value()?;
+        let zstd_seekable_frame_size = conf::SHUFFLE_ZSTD_SEEKABLE_FRAME_SIZE.value()?;
+        let spill_trace_dir = conf::SHUFFLE_SPILL_TRACE_DIR.value()?;
+        Ok::<_, DataFusionError>(SortShuffleConf {
+            unsafe_row_output: conf::SHUFFLE_UNSAFE_ROW_OUTPUT.value()?,
+            column_sizes_enabled: conf::SHUFFLE_COLUMN_SIZES_ENABLE.value()?,
+            null_key_fallback_ratio: conf::SHUFFLE_NULL_KEY_FALLBACK_RATIO.value()?,
+            max_in_mem_spill_size: match max_in_mem_spill_size {
+                size if size > 0 => size as usize,
+                _ => usize::MAX,
+            },
+            range_bounds_index: conf::SHUFFLE_RANGE_BOUNDS_INDEX_ENABLE.value()?,
+            adaptive_coalesce: match adaptive_coalesce_max_size {
+                max_total_size if max_total_size > 0 => Some(AdaptiveCoalesce {
+                    max_total_size: max_total_size as u64,
+                    target_num_partitions: conf::SHUFFLE_ADAPTIVE_COALESCE_TARGET_PARTITIONS
+                        .value()?
+                        .max(1) as usize,
+                }),
+                _ => None,
+            },
+            file_spills_first: conf::SHUFFLE_MERGE_FILE_SPILLS_FIRST.value()?,
+            index_checksum_block_size: conf::SHUFFLE_INDEX_CHECKSUM_BLOCK_SIZE.value()?.max(0)
+                as usize,
+            max_interrupt_retries: conf::SHUFFLE_MAX_INTERRUPT_RETRIES.value()?.max(0) as usize,
+            in_mem_spill_ratio: (in_mem_spill_ratio >= 0.0).then(|| in_mem_spill_ratio.min(1.0)),
+            output_file_extension: conf::SHUFFLE_OUTPUT_FILE_EXTENSION.value()?,
+            zstd_seekable_frame_size: (zstd_seekable_frame_size > 0
+                && conf::SPARK_IO_COMPRESSION_CODEC.value()? == "zstd")
+                .then_some(zstd_seekable_frame_size as usize),
+            spill_trace_dir: (!spill_trace_dir.is_empty()).then_some(spill_trace_dir),
+        })
+    })
 }
 
 impl SortShuffleRepartitioner {
-    pub fn new(
+    pub fn try_new(
         exec_ctx: Arc<ExecutionContext>,
         output_data_file: String,
         output_index_file: String,
         partitioning: Partitioning,
         output_io_time: Time,
-    ) -> Self {
+    ) -> Result<Self> {
         let partition_id = exec_ctx.partition_id();
         let num_output_partitions = partitioning.partition_count();
-        Self {
+        let range_bounds = match &partitioning {
+            Partitioning::RangePartitioning(_, _, bounds) => Some(bounds.clone()),
+            _ => None,
+        };
+        let conf = sort_shuffle_conf()?;
+        let mut data = BufferedData::try_new(partitioning, partition_id, output_io_time.clone())?;
+        if conf.unsafe_row_output {
+            data = data.with_unsafe_row_output(true);
+        }
+        let null_key_fallback_ratio = conf.null_key_fallback_ratio;
+        if null_key_fallback_ratio > 0.0 {
+            data = data.with_null_key_fallback(NullKeyFallback {
+                min_null_ratio: null_key_fallback_ratio,
+                fallback_rows: exec_ctx.register_counter_metric("null_key_fallback_rows"),
+            });
+        }
+        let spill_trace = conf
+            .spill_trace_dir
+            .as_ref()
+            .and_then(|dir| new_spill_trace_file(dir, &output_data_file));
+        Ok(Self {
             exec_ctx,
             mem_consumer_info: None,
             output_data_file,
             output_index_file,
-            data: Mutex::new(BufferedData::new(
-                partitioning,
-                partition_id,
-                output_io_time.clone(),
-            )),
+            data: Mutex::new(data),
             spills: Mutex::default(),
             num_output_partitions,
             output_io_time,
+            max_in_mem_spill_size: conf.max_in_mem_spill_size,
+            spill_trace,
+            exclusive_create: output_exclusive_create_enabled()?,
+            column_sizes_enabled: conf.column_sizes_enabled,
+            column_mem_sizes: SyncMutex::default(),
+            column_serialized_sizes: OnceCell::new(),
+            range_bounds,
+            range_bounds_index: conf.range_bounds_index,
+            adaptive_coalesce: conf.adaptive_coalesce,
+            file_spills_first: conf.file_spills_first,
+            merge_progress: Arc::new(MergeProgress::new(num_output_partitions)),
+            index_checksum_block_size: conf.index_checksum_block_size,
+            max_interrupt_retries: conf.max_interrupt_retries,
+            in_mem_spill_ratio: conf.in_mem_spill_ratio,
+            min_partition_file_bytes: None,
+            output_file_extension: conf.output_file_extension.clone(),
+            zstd_seekable_frame_size: conf.zstd_seekable_frame_size,
+            stage_spill_contribution: None,
+            grow_in_flight: AtomicUsize::new(0),
+            peak_grow_in_flight: AtomicUsize::new(0),
+        })
+    }
+
+    /// splits in-memory spills so that each one holds at most the specified
+    /// size of uncompressed data
+    pub fn with_max_in_mem_spill_size(mut self, max_in_mem_spill_size: usize) -> Self {
+        self.max_in_mem_spill_size = max_in_mem_spill_size;
+        self
+    }
+
+    /// records every spill decision into the given trace
+    pub fn with_spill_trace(mut self, spill_trace: Arc<SpillTrace>) -> Self {
+        self.spill_trace = Some(spill_trace);
+        self
+    }
+
+    /// fails shuffle_write() if the output data/index files already exist,
+    /// instead of truncating them
+    pub fn with_exclusive_create(mut self, exclusive_create: bool) -> Self {
+        self.exclusive_create = exclusive_create;
+        self
+    }
+
+    /// tracks in-memory sizes of each column on every inserted batch, so that
+    /// column_serialized_sizes() is available after shuffle_write()
+    pub fn with_column_serialized_sizes(mut self, enabled: bool) -> Self {
+        self.column_sizes_enabled = enabled;
+        self
+    }
+
+    /// returns the estimated serialized bytes contributed by each column of
+    /// the output data file, available after shuffle_write() if enabled with
+    /// with_column_serialized_sizes().
+    /// NOTE: these are estimates, not measured sizes. columns are compressed
+    /// together, so each column is given its share of the total in-memory
+    /// size, multiplied by the data file size.
+    pub fn column_serialized_sizes(&self) -> Option<&HashMap<String, u64>> {
+        self.column_serialized_sizes.get()
+    }
+
+    /// writes a sidecar index of the range bounds next to the output index
+    /// file (see range_index::range_bounds_index_file), only takes effect with
+    /// range partitioning
+    pub fn with_range_bounds_index(mut self, range_bounds_index: bool) -> Self {
+        self.range_bounds_index = range_bounds_index;
+        self
+    }
+
+    /// coalesces partitions into fewer physical ones when the total output
+    /// size is small, see coalesce::AdaptiveCoalesce
+    pub fn with_adaptive_coalesce(mut self, adaptive_coalesce: AdaptiveCoalesce) -> Self {
+        self.adaptive_coalesce = Some(adaptive_coalesce);
+        self
+    }
+
+    /// within each partition, copies bytes of file spills before those of
+    /// in-memory spills during the final merge, so that disk reads are not
+    /// interleaved with memory reads. frames of a partition are independent
+    /// and readers make no assumption on their order, so only the byte order
+    /// inside a partition is changed.
+    pub fn with_file_spills_first(mut self, file_spills_first: bool) -> Self {
+        self.file_spills_first = file_spills_first;
+        self
+    }
+
+    /// writes the index in checksummed blocks of the given number of offsets,
+    /// see [`crate::shuffle::index`]. 0 writes spark's plain index.
+    pub fn with_index_checksum_block_size(mut self, index_checksum_block_size: usize) -> Self {
+        self.index_checksum_block_size = index_checksum_block_size;
+        self
+    }
+
+    /// retries output I/O calls interrupted by signals (EINTR) at most the
+    /// given times per call. 0 to surface interruptions as errors
+    pub fn with_max_interrupt_retries(mut self, max_interrupt_retries: usize) -> Self {
+        self.max_interrupt_retries = max_interrupt_retries;
+        self
+    }
+
+    /// keeps at most the given ratio of spilled data in memory. when memory
+    /// usage is low, `spill()` and `shuffle_write` write buffered data into
+    /// in-mem spills and move in-mem spills exceeding the ratio to disk. under
+    /// memory pressure, `spill()` moves all in-mem spills to disk. without a
+    /// ratio, `spill()` always goes to disk and `shuffle_write` uses in-mem
+    /// spills when memory usage is low.
+    pub fn with_in_mem_spill_ratio(mut self, in_mem_spill_ratio: f64) -> Self {
+        self.in_mem_spill_ratio = Some(in_mem_spill_ratio.clamp(0.0, 1.0));
+        self
+    }
+
+    /// writes output partitions as uncompressed spark `UnsafeRowSerializer`
+    /// streams, see [`BufferedData::with_unsafe_row_output`]
+    pub fn with_unsafe_row_output(mut self, unsafe_row_output: bool) -> Self {
+        let data = self.data.get_mut();
+        *data = data.drain().with_unsafe_row_output(unsafe_row_output);
+        self
+    }
+
+    /// splits the output into per-partition files after writing, partitions
+    /// smaller than `min_partition_bytes` are grouped into a shared file, see
+    /// [`crate::shuffle::partition_files`].
+    /// NOTE: the data file is removed, so the output is only readable by
+    /// [`crate::shuffle::reader::ShuffleReader`]. spark's shuffle readers do
+    /// not know the layout, so this is not exposed as a spark configuration.
+    pub fn with_partition_files(mut self, min_partition_bytes: u64) -> Self {
+        self.min_partition_file_bytes = Some(min_partition_bytes);
+        self
+    }
+
+    /// appends `extension` (e.g. ".blaze-shuffle") to names of per-partition
+    /// output files, see [`Self::with_partition_files`]
+    pub fn with_output_file_extension(mut self, extension: &str) -> Self {
+        self.output_file_extension = extension.to_string();
+        self
+    }
+
+    /// writes each output partition as a single zstd seekable stream with
+    /// frames of at most `max_frame_size` uncompressed bytes, so that readers
+    /// can decompress from any offset of the partition. partitions are
+    /// transcoded after writing, which costs another pass over the data file.
+    /// does not apply to unsafe row output, which is uncompressed.
+    pub fn with_zstd_seekable_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.zstd_seekable_frame_size = Some(max_frame_size);
+        self
+    }
+
+    /// registers into stage-level metrics shared with other repartitioners of
+    /// the same stage, spills of this repartitioner are accumulated into it
+    pub fn with_stage_metrics(mut self, stage_metrics: &Arc<ShuffleStageMetrics>) -> Self {
+        self.stage_spill_contribution = Some(stage_metrics.register());
+        self
+    }
+
+    pub fn stage_spill_contribution(&self) -> Option<&StageSpillContribution> {
+        self.stage_spill_contribution.as_ref()
+    }
+
+    /// peak of memory growth reported by concurrent insertions before being
+    /// re-reported with the actual usage, bounded by grow permits
+    pub fn peak_grow_in_flight(&self) -> usize {
+        self.peak_grow_in_flight.load(Relaxed)
+    }
+
+    /// progress of merging spills, updated while `shuffle_write` is running
+    pub fn merge_progress(&self) -> Arc<MergeProgress> {
+        self.merge_progress.clone()
+    }
+
+    fn update_column_mem_sizes(&self, batch: &RecordBatch) {
+        if !self.column_sizes_enabled {
+            return;
+        }
+        let mut column_mem_sizes = self.column_mem_sizes.lock();
+        column_mem_sizes.resize(batch.num_columns(), 0);
+        for (size, column) in column_mem_sizes.iter_mut().zip(batch.columns()) {
+            *size += column.get_array_mem_size();
+        }
+    }
+
+    // computes column sizes, writes the range bounds index and splits partition
+    // files if enabled, all of which work on the written data/index files
+    async fn finish_output(&self) -> Result<()> {
+        let data_file = self.output_data_file.clone();
+        let index_file = self.output_index_file.clone();
+        let exclusive_create = self.exclusive_create;
+        let num_output_partitions = self.num_output_partitions;
+        let range_bounds = self
+            .range_bounds
+            .clone()
+            .filter(|_| self.range_bounds_index);
+        let tie_break = configured_range_boundary_tie_break()?;
+        let min_partition_file_bytes = self.min_partition_file_bytes;
+        let output_file_extension = self.output_file_extension.clone();
+        let column_mem_sizes = self
+            .column_sizes_enabled
+            .then(|| std::mem::take(&mut *self.column_mem_sizes.lock()));
+        let schema = self.exec_ctx.output_schema();
+
+        let column_serialized_sizes = tokio::task::spawn_blocking(move || {
+            let column_serialized_sizes = column_mem_sizes
+                .map(|column_mem_sizes| {
+                    compute_column_serialized_sizes(&data_file, &schema, &column_mem_sizes)
+                })
+                .transpose()?;
+            if let Some(range_bounds) = range_bounds {
+                let path = range_bounds_index_file(&index_file);
+                let mut output = open_shuffle_file(&path, exclusive_create)?;
+                write_range_bounds_index(
+                    &mut output,
+                    num_output_partitions,
+                    &range_bounds,
+                    tie_break,
+                )?;
+            }
+            if let Some(min_partition_bytes) = min_partition_file_bytes {
+                let offsets = read_index_offsets(&index_file)?;
+                split_partition_files(
+                    &data_file,
+                    &offsets,
+                    min_partition_bytes,
+                    &output_file_extension,
+                    exclusive_create,
+                )?;
+            }
+            Ok::<_, DataFusionError>(column_serialized_sizes)
+        })
+        .await
+        .or_else(|e| df_execution_err!("shuffle write error: {e:?}"))??;
+
+        if let Some(column_serialized_sizes) = column_serialized_sizes {
+            let _ = self.column_serialized_sizes.set(column_serialized_sizes);
         }
+        Ok(())
+    }
+
+    fn record_spill_trace(
+        &self,
+        mem_used: usize,
+        spills: &[Offsetted<u64, Box<dyn Spill>>],
+        buffered_size: usize,
+        target: SpillTarget,
+        freed: usize,
+    ) {
+        if let Some(spill_trace) = &self.spill_trace {
+            spill_trace.record(SpillTraceRecord::new(
+                mem_used,
+                in_mem_spills_size(spills),
+                buffered_size,
+                target,
+                freed,
+            ));
+        }
+    }
+
+    // memory usage is low enough to keep spills in memory
+    fn is_mem_used_low(&self) -> bool {
+        self.mem_used_percent() < 0.5
+    }
+
+    async fn in_mem_spills_mem_used(&self) -> usize {
+        in_mem_spills_size(&self.spills.lock().await)
+    }
+
+    // moves in-mem spills exceeding the given ratio of all spilled data to disk,
+    // returns the moved size
+    async fn evict_in_mem_spills(
+        &self,
+        spills: &mut Vec<Offsetted<u64, Box<dyn Spill>>>,
+        ratio: f64,
+    ) -> Result<usize> {
+        let (mut in_mem_spills, disk_spills): (Vec<_>, Vec<_>) = std::mem::take(spills)
+            .into_iter()
+            .partition(|spill| is_in_mem_spill(spill));
+        let disk_spilled_size = disk_spills.iter().map(spill_size).sum::<usize>();
+        let in_mem_sizes = in_mem_spills.iter().map(spill_size).collect::<Vec<_>>();
+        let num_kept = num_in_mem_spills_to_keep(&in_mem_sizes, disk_spilled_size, ratio);
+        let moved_size = in_mem_sizes[num_kept..].iter().sum::<usize>();
+        let moved_spills = in_mem_spills.split_off(num_kept);
+        *spills = disk_spills;
+        spills.extend(in_mem_spills);
+        if moved_spills.is_empty() {
+            return Ok(0);
+        }
+
+        let spill_metrics = self.exec_ctx.spill_metrics().clone();
+        let moved_spills = tokio::task::spawn_blocking(move || {
+            moved_spills
+                .into_iter()
+                .map(|in_mem_spill| {
+                    let offsets = in_mem_spill.offsets().to_vec();
+                    let mut spill = try_new_spill(&spill_metrics)?;
+                    let mut writer = spill.get_buf_writer();
+                    std::io::copy(&mut in_mem_spill.data().get_buf_reader(), &mut writer)?;
+                    writer.flush()?;
+                    drop(writer);
+                    Ok::<_, DataFusionError>(Offsetted::new(offsets, spill))
+                })
+                .collect::<Result<Vec<_>>>()
+        })
+        .await
+        .expect("tokio spawn_blocking error")?;
+        spills.extend(moved_spills);
+        Ok(moved_size)
     }
 }
 
@@ -96,18 +582,73 @@ impl MemConsumer for SortShuffleRepartitioner {
     }
 
     async fn spill(&self) -> Result<()> {
+        let mem_used = MemManager::get().total_used();
         let data = self.data.lock().await.drain();
+        let buffered_size = data.mem_used();
+
+        // spill to memory only if a target ratio allows and memory usage is low
+        let spill_to_mem =
+            self.in_mem_spill_ratio.is_some_and(|ratio| ratio > 0.0) && self.is_mem_used_low();
+        let max_in_mem_spill_size = self.max_in_mem_spill_size;
         let spill_metrics = self.exec_ctx.spill_metrics().clone();
-        let spill = tokio::task::spawn_blocking(move || {
+        let spill_start_time = Instant::now();
+        let new_spills = tokio::task::spawn_blocking(move || {
+            if spill_to_mem {
+                let in_mem_spills = data.write_in_mem_spills(max_in_mem_spill_size)?;
+                return Ok(in_mem_spills
+                    .into_iter()
+                    .map(|spill| spill.map_data(|s| Box::new(s) as Box<dyn Spill>))
+                    .collect::<Vec<_>>());
+            }
             let mut spill = try_new_spill(&spill_metrics)?;
             let offsets = data.write(spill.get_buf_writer())?;
-            Ok::<_, DataFusionError>(Offsetted::new(offsets, spill))
+            Ok::<_, DataFusionError>(vec![Offsetted::new(offsets, spill)])
         })
         .await
         .expect("tokio spawn_blocking error")?;
+        let spilled_size = new_spills.iter().map(spill_size).sum::<usize>();
+        if let Some(contribution) = &self.stage_spill_contribution {
+            contribution.record_spill(spilled_size as u64, spill_start_time.elapsed());
+        }
 
-        self.spills.lock().await.push(spill);
-        self.update_mem_used(0).await?;
+        let mut spills = self.spills.lock().await;
+        spills.extend(new_spills);
+        if spill_to_mem {
+            self.record_spill_trace(
+                mem_used,
+                &spills,
+                buffered_size,
+                SpillTarget::Memory,
+                buffered_size.saturating_sub(spilled_size),
+            );
+        } else {
+            self.record_spill_trace(
+                mem_used,
+                &spills,
+                buffered_size,
+                SpillTarget::Disk,
+                buffered_size,
+            );
+        }
+
+        // keeps in-mem spills under the target ratio, or moves all of them to disk
+        // under memory pressure
+        if let Some(ratio) = self.in_mem_spill_ratio {
+            let ratio = if spill_to_mem { ratio } else { 0.0 };
+            let moved_size = self.evict_in_mem_spills(&mut spills, ratio).await?;
+            if moved_size > 0 {
+                self.record_spill_trace(
+                    mem_used,
+                    &spills,
+                    moved_size,
+                    SpillTarget::Disk,
+                    moved_size,
+                );
+            }
+        }
+        let in_mem_size = in_mem_spills_size(&spills);
+        drop(spills);
+        self.update_mem_used(in_mem_size).await?;
         Ok(())
     }
 }
@@ -121,17 +662,30 @@ impl Drop for SortShuffleRepartitioner {
 #[async_trait]
 impl ShuffleRepartitioner for SortShuffleRepartitioner {
     async fn insert_batch(&self, input: RecordBatch) -> Result<()> {
-        // update memory usage before adding to buffered data
-        let mem_used = self.data.lock().await.mem_used() + input.get_batch_mem_size() * 2;
-        self.update_mem_used(mem_used).await?;
-
-        // add batch to buffered data
+        // bound memory growing of concurrent insertions between reporting the
+        // growth and re-reporting the actual usage. the permit is released
+        // before force spilling
+        let grow_size = input.get_batch_mem_size() * 2;
         let mem_used = {
-            let mut data = self.data.lock().await;
-            data.add_batch(input)?;
-            data.mem_used()
+            let _grow_permit = MemManager::get().acquire_grow_permit(grow_size).await;
+            let _grow_in_flight =
+                InFlightGrow::new(&self.grow_in_flight, &self.peak_grow_in_flight, grow_size);
+
+            // update memory usage before adding to buffered data
+            let mem_used =
+                self.data.lock().await.mem_used() + self.in_mem_spills_mem_used().await + grow_size;
+            self.update_mem_used(mem_used).await?;
+            self.update_column_mem_sizes(&input);
+
+            // add batch to buffered data
+            let mem_used = {
+                let mut data = self.data.lock().await;
+                data.add_batch(input)?;
+                data.mem_used()
+            } + self.in_mem_spills_mem_used().await;
+            self.update_mem_used(mem_used).await?;
+            mem_used
         };
-        self.update_mem_used(mem_used).await?;
 
         // we are likely to spill more frequently because the cost of spilling a shuffle
         // repartition is lower than other consumers.
@@ -162,6 +716,15 @@ impl ShuffleRepartitioner for SortShuffleRepartitioner {
 
         let data_file = self.output_data_file.clone();
         let index_file = self.output_index_file.clone();
+        let exclusive_create = self.exclusive_create;
+        let adaptive_coalesce = self.adaptive_coalesce;
+        let file_spills_first = self.file_spills_first;
+        let merge_progress = self.merge_progress.clone();
+        let index_checksum_block_size = self.index_checksum_block_size;
+        let max_interrupt_retries = self.max_interrupt_retries;
+        let zstd_seekable_frame_size = self
+            .zstd_seekable_frame_size
+            .filter(|_| !data.is_unsafe_row_output());
 
         // no spills - directly write current batches into final file
         if spills.is_empty() {
@@ -170,36 +733,76 @@ impl ShuffleRepartitioner for SortShuffleRepartitioner {
                 let output_io_time_cloned = output_io_time.clone();
                 let _output_io_timer = output_io_time_cloned.timer();
 
-                let mut output_data = open_shuffle_file(&data_file)?;
-                let mut output_index = open_shuffle_file(&index_file)?;
+                let (output_data, output_index) =
+                    open_shuffle_files(&data_file, &index_file, exclusive_create)?;
+                let mut output_data = InterruptRetry::new(output_data, max_interrupt_retries);
+                let mut output_index = InterruptRetry::new(output_index, max_interrupt_retries);
 
                 // write data file
                 // exclude io timer because it is already included buffered_data.write()
-                let offsets = output_io_time.exclude_timer(|| data.write(&mut output_data))?;
+                let mut offsets = output_io_time.exclude_timer(|| data.write(&mut output_data))?;
+                drop(output_data);
+                if let Some(max_frame_size) = zstd_seekable_frame_size {
+                    offsets =
+                        rewrite_zstd_seekable_partitions(&data_file, &offsets, max_frame_size)?;
+                }
+                if let Some(adaptive_coalesce) = adaptive_coalesce {
+                    offsets = adaptive_coalesce.apply(&offsets, &index_file, exclusive_create)?;
+                }
 
                 // write index file
-                let mut offsets_data = vec![];
-                for offset in offsets {
-                    offsets_data.extend_from_slice(&(offset as i64).to_le_bytes()[..]);
-                }
-                output_index.write_all(&offsets_data)?;
+                write_index(&mut output_index, &offsets, index_checksum_block_size)?;
 
                 Ok::<(), DataFusionError>(())
             })
             .await
             .or_else(|e| df_execution_err!("shuffle write error: {e:?}"))??;
+            self.merge_progress.update(self.num_output_partitions, 0);
+            self.finish_output().await?;
             self.update_mem_used(0).await?;
             return Ok(());
         }
 
         // write rest data into a spill
         if !data.is_empty() {
-            if self.mem_used_percent() < 0.5 {
-                let mut spill = Box::new(vec![]);
-                let writer = spill.get_buf_writer();
-                let offsets = data.write(writer)?;
-                self.update_mem_used(spill.len()).await?;
-                spills.push(Offsetted::new(offsets, spill));
+            let mem_used = MemManager::get().total_used();
+            let buffered_size = data.mem_used();
+            let spill_to_mem =
+                self.in_mem_spill_ratio.is_none_or(|ratio| ratio > 0.0) && self.is_mem_used_low();
+            if spill_to_mem {
+                let in_mem_spills = data.write_in_mem_spills(self.max_in_mem_spill_size)?;
+                let in_mem_size = in_mem_spills
+                    .iter()
+                    .map(|spill| spill.data().len())
+                    .sum::<usize>();
+                let freed = buffered_size.saturating_sub(in_mem_size);
+                spills.extend(
+                    in_mem_spills
+                        .into_iter()
+                        .map(|spill| spill.map_data(|s| Box::new(s) as Box<dyn Spill>)),
+                );
+                self.record_spill_trace(
+                    mem_used,
+                    &spills,
+                    buffered_size,
+                    SpillTarget::Memory,
+                    freed,
+                );
+
+                // keeps in-mem spills under the target ratio, moves the rest to disk
+                if let Some(ratio) = self.in_mem_spill_ratio {
+                    let moved_size = self.evict_in_mem_spills(&mut spills, ratio).await?;
+                    if moved_size > 0 {
+                        self.record_spill_trace(
+                            mem_used,
+                            &spills,
+                            moved_size,
+                            SpillTarget::Disk,
+                            moved_size,
+                        );
+                    }
+                }
+                self.update_mem_used(in_mem_spills_size(&spills)).await?;
             } else {
                 let spill_metrics = self.exec_ctx.spill_metrics().clone();
                 let spill = tokio::task::spawn_blocking(move || {
@@ -209,8 +812,15 @@ impl ShuffleRepartitioner for SortShuffleRepartitioner {
                 })
                 .await
                 .expect("tokio spawn_blocking error")?;
-                self.update_mem_used(0).await?;
                 spills.push(spill);
+                self.update_mem_used(in_mem_spills_size(&spills)).await?;
+                self.record_spill_trace(
+                    mem_used,
+                    &spills,
+                    buffered_size,
+                    SpillTarget::Disk,
+                    buffered_size,
+                );
             }
         }
 
@@ -219,36 +829,978 @@ impl ShuffleRepartitioner for SortShuffleRepartitioner {
         let output_io_time = self.output_io_time.clone();
         tokio::task::spawn_blocking(move || {
             let _output_io_timer = output_io_time.timer();
-            let mut output_data = open_shuffle_file(&data_file)?;
-            let mut output_index = open_shuffle_file(&index_file)?;
+            let (mut output_data, output_index) =
+                open_shuffle_files(&data_file, &index_file, exclusive_create)?;
+            let mut output_index = InterruptRetry::new(output_index, max_interrupt_retries);
 
-            let mut merge_iter = OffsettedMergeIterator::new(
+            let mut offsets = merge_spills(
                 num_output_partitions,
-                spills
-                    .into_iter()
-                    .map(|spill| spill.map_data(|s| OwnedSpillBufReader::from(s)))
-                    .collect(),
-            );
-
-            while let Some((_partition_id, reader, range)) = merge_iter.next() {
-                let mut reader = reader.buf_reader().take(range.end - range.start);
-                std::io::copy(&mut reader, &mut output_data)?;
+                spills,
+                &mut output_data,
+                file_spills_first,
+                &merge_progress,
+                max_interrupt_retries,
+            )?;
+            drop(output_data);
+            if let Some(max_frame_size) = zstd_seekable_frame_size {
+                offsets = rewrite_zstd_seekable_partitions(&data_file, &offsets, max_frame_size)?;
+            }
+            if let Some(adaptive_coalesce) = adaptive_coalesce {
+                offsets = adaptive_coalesce.apply(&offsets, &index_file, exclusive_create)?;
             }
-            let offsets = merge_iter.merged_offsets();
 
             // write index file
-            let mut offsets_data = vec![];
-            for &offset in offsets {
-                offsets_data.extend_from_slice(&(offset as i64).to_le_bytes()[..]);
-            }
-            output_index.write_all(&offsets_data)?;
+            write_index(&mut output_index, &offsets, index_checksum_block_size)?;
 
             Ok::<(), DataFusionError>(())
         })
         .await
         .or_else(|e| df_execution_err!("shuffle write error: {e:?}"))??;
+        self.finish_output().await?;
 
         self.update_mem_used(0).await?;
         Ok(())
     }
 }
+
+// memory growth reported by an insertion but not yet re-reported with the
+// actual usage, removed from the in-flight counter on drop
+struct InFlightGrow<'a> {
+    in_flight: &'a AtomicUsize,
+    size: usize,
+}
+
+impl<'a> InFlightGrow<'a> {
+    fn new(in_flight: &'a AtomicUsize, peak_in_flight: &AtomicUsize, size: usize) -> Self {
+        let cur_in_flight = in_flight.fetch_add(size, Relaxed) + size;
+        peak_in_flight.fetch_max(cur_in_flight, Relaxed);
+        Self { in_flight, size }
+    }
+}
+
+impl Drop for InFlightGrow<'_> {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(self.size, Relaxed);
+    }
+}
+
+fn is_in_mem_spill(spill: &Offsetted<u64, Box<dyn Spill>>) -> bool {
+    spill.data().as_any().is::<Vec<u8>>()
+}
+
+fn spill_size(spill: &Offsetted<u64, Box<dyn Spill>>) -> usize {
+    spill.offsets().last().cloned().unwrap_or(0) as usize
+}
+
+fn in_mem_spills_size(spills: &[Offsetted<u64, Box<dyn Spill>>]) -> usize {
+    spills
+        .iter()
+        .filter(|spill| is_in_mem_spill(spill))
+        .map(spill_size)
+        .sum()
+}
+
+// returns the number of leading in-mem spills that can stay in memory, so that
+// in-mem size does not exceed the given ratio of all spilled data
+fn num_in_mem_spills_to_keep(
+    in_mem_sizes: &[usize],
+    disk_spilled_size: usize,
+    ratio: f64,
+) -> usize {
+    let total_size = disk_spilled_size + in_mem_sizes.iter().sum::<usize>();
+    let max_in_mem_size = (total_size as f64 * ratio) as usize;
+    let mut kept_size = 0;
+    in_mem_sizes
+        .iter()
+        .take_while(|&&size| {
+            kept_size += size;
+            kept_size <= max_in_mem_size
+        })
+        .count()
+}
+
+// merges partitions of all spills into the output, returns merged offsets
+fn merge_spills(
+    num_partitions: usize,
+    spills: Vec<Offsetted<u64, Box<dyn Spill>>>,
+    output: &mut impl Write,
+    file_spills_first: bool,
+    merge_progress: &MergeProgress,
+    max_interrupt_retries: usize,
+) -> Result<Vec<u64>> {
+    let mut output = InterruptRetry::new(output, max_interrupt_retries);
+    let mut merge_iter = OffsettedMergeIterator::new(
+        num_partitions,
+        spills
+            .into_iter()
+            .map(|spill| spill.map_data(|s| OwnedSpillBufReader::from(s)))
+            .collect(),
+    );
+
+    while let Some((partition_id, chunk_iter)) = merge_iter.next_partition_chunk() {
+        let mut chunks = chunk_iter.collect::<Vec<_>>();
+        if file_spills_first {
+            chunks.sort_by_key(|(reader, _)| reader.spill().as_any().is::<Vec<u8>>());
+        }
+        merge_progress.update(partition_id, merge_iter.num_active_cursors());
+        for (reader, range) in chunks {
+            let mut reader = InterruptRetry::new(
+                reader.buf_reader().take(range.end - range.start),
+                max_interrupt_retries,
+            );
+            std::io::copy(&mut reader, &mut output)?;
+        }
+    }
+    output.flush()?;
+    merge_progress.update(num_partitions, 0);
+    Ok(merge_iter.merged_offsets().to_vec())
+}
+
+// estimates serialized bytes of each column by sharing the data file size
+// according to in-memory sizes of columns
+fn compute_column_serialized_sizes(
+    data_file: &str,
+    schema: &SchemaRef,
+    column_mem_sizes: &[usize],
+) -> Result<HashMap<String, u64>> {
+    let data_size = std::fs::metadata(data_file)?.len();
+    let total_mem_size = column_mem_sizes.iter().sum::<usize>().max(1) as u128;
+    Ok(schema
+        .fields()
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            let mem_size = column_mem_sizes.get(i).cloned().unwrap_or(0) as u128;
+            let size = (data_size as u128 * mem_size / total_mem_size) as u64;
+            (field.name().clone(), size)
+        })
+        .collect())
+}
+
+// creates a spill trace file named after the output data file in the given
+// directory. tracing is only diagnostic, so failures disable it with a warning
+fn new_spill_trace_file(dir: &str, output_data_file: &str) -> Option<Arc<SpillTrace>> {
+    let file_name = Path::new(output_data_file).file_name()?.to_string_lossy();
+    let path = Path::new(dir).join(format!("{file_name}.spill-trace"));
+    match SpillTrace::try_new_file(&path) {
+        Ok(spill_trace) => Some(Arc::new(spill_trace)),
+        Err(e) => {
+            log::warn!("error creating spill trace {path:?}, tracing disabled: {e}");
+            None
+        }
+    }
+}
+
+// rewrites each partition of the data file as a standalone zstd seekable
+// stream, returns offsets of the rewritten partitions
+fn rewrite_zstd_seekable_partitions(
+    data_file: &str,
+    offsets: &[u64],
+    max_frame_size: usize,
+) -> Result<Vec<u64>> {
+    let tmp_file = format!("{data_file}.seekable.tmp");
+    let mut input = BufReader::new(File::open(data_file)?);
+    let mut output = BufWriter::new(File::create(&tmp_file)?);
+    let mut new_offsets = vec![0];
+    for range in offsets.windows(2) {
+        if range[1] > range[0] {
+            let partition_input = (&mut input).take(range[1] - range[0]);
+            transcode_to_zstd_seekable(partition_input, &mut output, max_frame_size)?;
+        }
+        new_offsets.push(output.stream_position()?);
+    }
+    output.flush()?;
+    drop(output);
+    std::fs::rename(&tmp_file, data_file)?;
+    Ok(new_offsets)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::Path, sync::Arc};
+
+    use arrow::{
+        array::{Int32Array, StringArray, as_primitive_array},
+        datatypes::{DataType, Field, Int32Type, Schema},
+        record_batch::RecordBatch,
+    };
+    use datafusion::{
+        common::Result,
+        physical_expr::expressions::Column,
+        physical_plan::metrics::{ExecutionPlanMetricsSet, Time},
+        prelude::SessionContext,
+    };
+    use datafusion_ext_commons::spark_hash::create_murmur3_hashes;
+    use itertools::Itertools;
+
+    use super::*;
+    use crate::{
+        common::ipc_compression::IpcCompressionReader,
+        memmgr::{MemManager, metrics::SpillMetrics},
+        shuffle::{
+            reader::ShuffleReader,
+            spill_trace::{SpillTarget, SpillTrace, SpillTraceRecord, decode_spill_trace},
+        },
+    };
+
+    fn build_batch(values: Vec<i32>) -> RecordBatch {
+        let schema = Schema::new(vec![Field::new("a", DataType::Int32, false)]);
+        RecordBatch::try_new(Arc::new(schema), vec![Arc::new(Int32Array::from(values))]).unwrap()
+    }
+
+    fn new_repartitioner(dir: &Path, num_partitions: usize) -> Result<SortShuffleRepartitioner> {
+        let schema = build_batch(vec![]).schema();
+        let exec_ctx = ExecutionContext::new(
+            SessionContext::new().task_ctx(),
+            0,
+            schema,
+            &ExecutionPlanMetricsSet::new(),
+        );
+        SortShuffleRepartitioner::try_new(
+            exec_ctx,
+            dir.join("shuffle.data").to_string_lossy().to_string(),
+            dir.join("shuffle.index").to_string_lossy().to_string(),
+            Partitioning::HashPartitioning(vec![Arc::new(Column::new("a", 0))], num_partitions),
+            Time::new(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_spill_trace() -> Result<()> {
+        MemManager::init(100);
+        let dir = tempfile::tempdir()?;
+        let spill_trace = Arc::new(SpillTrace::new_ring_buffer(16));
+        let repartitioner =
+            Arc::new(new_repartitioner(dir.path(), 4)?.with_spill_trace(spill_trace.clone()));
+        MemManager::register_consumer(repartitioner.clone(), true);
+
+        // every insertion exceeds the tiny memory budget and spills to disk
+        for i in 0..3 {
+            repartitioner
+                .insert_batch(build_batch((i * 100..i * 100 + 100).collect()))
+                .await?;
+        }
+
+        // leave some data in memory so that shuffle_write() makes one more decision
+        repartitioner
+            .data
+            .lock()
+            .await
+            .add_batch(build_batch((0..100).collect()))?;
+        repartitioner.shuffle_write().await?;
+
+        let records = decode_spill_trace(&spill_trace.snapshot())?;
+        assert_eq!(records.len(), 4);
+        for record in &records[0..3] {
+            assert_eq!(record.target, SpillTarget::Disk);
+            assert_eq!(record.in_mem_size, 0);
+            assert!(record.buffered_size > 0);
+            assert_eq!(record.freed, record.buffered_size);
+        }
+
+        // the final decision depends on memory pressure, check it is consistent with
+        // the branch taken
+        let last = &records[3];
+        match last.target {
+            SpillTarget::Memory => {
+                assert!(last.in_mem_size > 0);
+                assert_eq!(
+                    last.freed,
+                    last.buffered_size.saturating_sub(last.in_mem_size)
+                );
+            }
+            SpillTarget::Disk => {
+                assert_eq!(last.in_mem_size, 0);
+                assert_eq!(last.freed, last.buffered_size);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_spill_trace_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let trace_dir = dir.path().to_string_lossy();
+        let spill_trace = new_spill_trace_file(&trace_dir, "/output/shuffle_1_2_0.data")
+            .expect("error creating spill trace");
+        spill_trace.record(SpillTraceRecord::new(100, 0, 50, SpillTarget::Disk, 50));
+        drop(spill_trace);
+
+        // traces are named after the output data file
+        let trace = std::fs::read(dir.path().join("shuffle_1_2_0.data.spill-trace"))?;
+        let records = decode_spill_trace(&trace)?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].buffered_size, 50);
+
+        // an unwritable directory disables tracing
+        let missing_dir = dir.path().join("missing").to_string_lossy().to_string();
+        assert!(new_spill_trace_file(&missing_dir, "shuffle_1_2_0.data").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_exclusive_create() -> Result<()> {
+        MemManager::init(100);
+
+        async fn write(dir: &Path, exclusive_create: bool) -> Result<()> {
+            let repartitioner =
+                Arc::new(new_repartitioner(dir, 4)?.with_exclusive_create(exclusive_create));
+            MemManager::register_consumer(repartitioner.clone(), true);
+            repartitioner
+                .data
+                .lock()
+                .await
+                .add_batch(build_batch((0..100).collect()))?;
+            repartitioner.shuffle_write().await
+        }
+
+        // succeeds when the target files are absent
+        let dir = tempfile::tempdir()?;
+        write(dir.path(), true).await?;
+        let index = std::fs::read(dir.path().join("shuffle.index"))?;
+        assert_eq!(index.len(), 8 * 5);
+
+        // fails when the target files exist
+        assert!(write(dir.path(), true).await.is_err());
+        assert_eq!(std::fs::read(dir.path().join("shuffle.index"))?, index);
+
+        // default mode truncates existing files
+        write(dir.path(), false).await?;
+        assert_eq!(std::fs::read(dir.path().join("shuffle.index"))?, index);
+
+        // no data file is left behind when only the index file exists
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("shuffle.index"), b"")?;
+        assert!(write(dir.path(), true).await.is_err());
+        assert!(!dir.path().join("shuffle.data").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_column_serialized_sizes() -> Result<()> {
+        MemManager::init(100);
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, false),
+            Field::new("payload", DataType::Utf8, false),
+        ]));
+        let dir = tempfile::tempdir()?;
+        let new_repartitioner = |column_sizes_enabled: bool| -> Result<_> {
+            let exec_ctx = ExecutionContext::new(
+                SessionContext::new().task_ctx(),
+                0,
+                schema.clone(),
+                &ExecutionPlanMetricsSet::new(),
+            );
+            let file = |name: &str| {
+                dir.path()
+                    .join(format!("{column_sizes_enabled}.{name}"))
+                    .to_string_lossy()
+                    .to_string()
+            };
+            let repartitioner = Arc::new(
+                SortShuffleRepartitioner::try_new(
+                    exec_ctx,
+                    file("data"),
+                    file("index"),
+                    Partitioning::HashPartitioning(vec![Arc::new(Column::new("a", 0))], 4),
+                    Time::new(),
+                )?
+                .with_column_serialized_sizes(column_sizes_enabled),
+            );
+            MemManager::register_consumer(repartitioner.clone(), true);
+            Ok(repartitioner)
+        };
+        let repartitioner = new_repartitioner(true)?;
+        let disabled_repartitioner = new_repartitioner(false)?;
+        assert!(repartitioner.column_serialized_sizes().is_none());
+
+        for i in 0..3 {
+            let keys = (i * 100..i * 100 + 100).collect::<Vec<i32>>();
+            let payloads = keys
+                .iter()
+                .map(|k| format!("{k:0>8}").repeat(32))
+                .collect::<Vec<_>>();
+            let batch = RecordBatch::try_new(
+                schema.clone(),
+                vec![
+                    Arc::new(Int32Array::from(keys)),
+                    Arc::new(StringArray::from(payloads)),
+                ],
+            )?;
+            repartitioner.insert_batch(batch.clone()).await?;
+            disabled_repartitioner.insert_batch(batch).await?;
+        }
+        repartitioner.shuffle_write().await?;
+        disabled_repartitioner.shuffle_write().await?;
+        assert!(disabled_repartitioner.column_serialized_sizes().is_none());
+        assert!(disabled_repartitioner.column_mem_sizes.lock().is_empty());
+
+        let sizes = repartitioner.column_serialized_sizes().unwrap();
+        let data_size = std::fs::metadata(dir.path().join("true.data"))?.len();
+        assert_eq!(sizes.len(), 2);
+        assert!(sizes["payload"] > sizes["a"] * 10);
+        assert!(sizes.values().sum::<u64>() <= data_size);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_file_spills_first() -> Result<()> {
+        let schema = build_batch(vec![]).schema();
+        let partitioning = Partitioning::HashPartitioning(vec![Arc::new(Column::new("a", 0))], 4);
+        let spill_metrics = SpillMetrics::new(&ExecutionPlanMetricsSet::new(), 0);
+
+        // in-mem and file spills are interleaved
+        let build_spills = || -> Result<Vec<Offsetted<u64, Box<dyn Spill>>>> {
+            let mut spills = vec![];
+            for i in 0..4 {
+                let mut data = BufferedData::try_new(partitioning.clone(), 0, Time::new())?;
+                data.add_batch(build_batch((i * 100..i * 100 + 100).collect()))?;
+                if i % 2 == 0 {
+                    let mut in_mem_spills = data.write_in_mem_spills(usize::MAX)?;
+                    assert_eq!(in_mem_spills.len(), 1);
+                    let spill = in_mem_spills.pop().unwrap();
+                    spills.push(spill.map_data(|s| Box::new(s) as Box<dyn Spill>));
+                } else {
+                    let mut spill = try_new_spill(&spill_metrics)?;
+                    let offsets = data.write(spill.get_buf_writer())?;
+                    spills.push(Offsetted::new(offsets, spill));
+                }
+            }
+            Ok(spills)
+        };
+        let read_values = |data: &[u8]| -> Result<Vec<i32>> {
+            let mut values = vec![];
+            let mut reader = IpcCompressionReader::new(data);
+            while let Some((_, cols)) = reader.read_batch(&schema)? {
+                values.extend(as_primitive_array::<Int32Type>(&cols[0]).values());
+            }
+            values.sort();
+            Ok(values)
+        };
+
+        // partition bytes of each spill, grouped by spill type
+        let mut file_chunks = vec![vec![]; 4];
+        let mut in_mem_chunks = vec![vec![]; 4];
+        for spill in build_spills()? {
+            let mut bytes = vec![];
+            spill.data().get_buf_reader().read_to_end(&mut bytes)?;
+            let is_in_mem = spill.data().as_any().is::<Vec<u8>>();
+            for partition_id in 0..4 {
+                let range = spill.offset(partition_id);
+                let chunk = bytes[range.start as usize..range.end as usize].to_vec();
+                if is_in_mem {
+                    in_mem_chunks[partition_id].push(chunk);
+                } else {
+                    file_chunks[partition_id].push(chunk);
+                }
+            }
+        }
+
+        let mut default_output = vec![];
+        let default_offsets = merge_spills(
+            4,
+            build_spills()?,
+            &mut default_output,
+            false,
+            &MergeProgress::new(4),
+            0,
+        )?;
+        let mut output = vec![];
+        let offsets = merge_spills(
+            4,
+            build_spills()?,
+            &mut output,
+            true,
+            &MergeProgress::new(4),
+            0,
+        )?;
+        assert_eq!(offsets, default_offsets);
+
+        let mut num_rows = 0;
+        for partition_id in 0..4 {
+            let range = offsets[partition_id] as usize..offsets[partition_id + 1] as usize;
+            let partition_data = &output[range.clone()];
+
+            // all file spills come before in-mem spills
+            let file_size = file_chunks[partition_id]
+                .iter()
+                .map(|c| c.len())
+                .sum::<usize>();
+            let is_concat_of = |data: &[u8], chunks: &[Vec<u8>]| {
+                chunks
+                    .iter()
+                    .permutations(chunks.len())
+                    .any(|chunks| chunks.into_iter().flatten().copied().collect::<Vec<_>>() == data)
+            };
+            assert!(is_concat_of(
+                &partition_data[..file_size],
+                &file_chunks[partition_id]
+            ));
+            assert!(is_concat_of(
+                &partition_data[file_size..],
+                &in_mem_chunks[partition_id]
+            ));
+
+            // rows are identical to the default ordering
+            let values = read_values(partition_data)?;
+            assert_eq!(values, read_values(&default_output[range])?);
+            num_rows += values.len();
+        }
+        assert_eq!(num_rows, 400);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merge_progress() -> Result<()> {
+        MemManager::init(100);
+        let dir = tempfile::tempdir()?;
+        let num_partitions = 1000;
+        let repartitioner = Arc::new(new_repartitioner(dir.path(), num_partitions)?);
+        MemManager::register_consumer(repartitioner.clone(), true);
+        for i in 0..20 {
+            repartitioner
+                .insert_batch(build_batch((i * 5000..i * 5000 + 5000).collect()))
+                .await?;
+        }
+
+        // polls progress while merging
+        let merge_progress = repartitioner.merge_progress();
+        assert_eq!(merge_progress.current_partition(), 0);
+        let finished = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let monitor = std::thread::spawn({
+            let merge_progress = merge_progress.clone();
+            let finished = finished.clone();
+            move || {
+                let mut observed = vec![];
+                while !finished.load(Relaxed) {
+                    observed.push(merge_progress.current_partition());
+                    std::thread::yield_now();
+                }
+                observed.push(merge_progress.current_partition());
+                observed
+            }
+        });
+        repartitioner.shuffle_write().await?;
+        finished.store(true, Relaxed);
+
+        let observed = monitor.join().unwrap();
+        assert!(observed.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(*observed.last().unwrap(), num_partitions);
+        assert!(observed.iter().all(|&p| p <= num_partitions));
+        assert_eq!(merge_progress.remaining_cursors(), 0);
+        assert_eq!(merge_progress.percent(), 100.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_interrupt_retry() -> Result<()> {
+        let partitioning = Partitioning::HashPartitioning(vec![Arc::new(Column::new("a", 0))], 4);
+        let spill_metrics = SpillMetrics::new(&ExecutionPlanMetricsSet::new(), 0);
+        let build_spills = || -> Result<Vec<Offsetted<u64, Box<dyn Spill>>>> {
+            let mut spills = vec![];
+            for i in 0..3 {
+                let mut data = BufferedData::try_new(partitioning.clone(), 0, Time::new())?;
+                data.add_batch(build_batch((i * 100..i * 100 + 100).collect()))?;
+                let mut spill = try_new_spill(&spill_metrics)?;
+                let offsets = data.write(spill.get_buf_writer())?;
+                spills.push(Offsetted::new(offsets, spill));
+            }
+            Ok(spills)
+        };
+
+        // returns EINTR once on the first write and the first flush
+        #[derive(Default)]
+        struct InterruptOnceWriter {
+            data: Vec<u8>,
+            write_interrupted: bool,
+            flush_interrupted: bool,
+        }
+        impl Write for InterruptOnceWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if !std::mem::replace(&mut self.write_interrupted, true) {
+                    return Err(std::io::ErrorKind::Interrupted.into());
+                }
+                self.data.write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                if !std::mem::replace(&mut self.flush_interrupted, true) {
+                    return Err(std::io::ErrorKind::Interrupted.into());
+                }
+                Ok(())
+            }
+        }
+
+        let mut expected = vec![];
+        let expected_offsets = merge_spills(
+            4,
+            build_spills()?,
+            &mut expected,
+            false,
+            &MergeProgress::new(4),
+            0,
+        )?;
+
+        let mut output = InterruptOnceWriter::default();
+        let offsets = merge_spills(
+            4,
+            build_spills()?,
+            &mut output,
+            false,
+            &MergeProgress::new(4),
+            3,
+        )?;
+        assert!(output.write_interrupted && output.flush_interrupted);
+        assert_eq!(offsets, expected_offsets);
+        assert_eq!(output.data, expected);
+
+        // interruption surfaces as an error without retries
+        let mut output = InterruptOnceWriter::default();
+        output.write_interrupted = true;
+        let err = merge_spills(
+            4,
+            build_spills()?,
+            &mut output,
+            false,
+            &MergeProgress::new(4),
+            0,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("interrupted"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_num_in_mem_spills_to_keep() {
+        let sizes = [100, 100, 100];
+        assert_eq!(num_in_mem_spills_to_keep(&sizes, 700, 0.0), 0);
+        assert_eq!(num_in_mem_spills_to_keep(&sizes, 700, 0.3), 3);
+        assert_eq!(num_in_mem_spills_to_keep(&sizes, 700, 0.25), 2);
+        assert_eq!(num_in_mem_spills_to_keep(&sizes, 700, 0.1), 1);
+        assert_eq!(num_in_mem_spills_to_keep(&sizes, 0, 1.0), 3);
+        assert_eq!(num_in_mem_spills_to_keep(&sizes, 0, 0.5), 1);
+    }
+
+    #[tokio::test]
+    async fn test_in_mem_spill_ratio() -> Result<()> {
+        MemManager::init(100);
+
+        async fn write(dir: &Path, ratio: f64) -> Result<Vec<SpillTraceRecord>> {
+            let spill_trace = Arc::new(SpillTrace::new_ring_buffer(16));
+            let repartitioner = Arc::new(
+                new_repartitioner(dir, 4)?
+                    .with_spill_trace(spill_trace.clone())
+                    .with_max_in_mem_spill_size(64)
+                    .with_in_mem_spill_ratio(ratio),
+            );
+            MemManager::register_consumer(repartitioner.clone(), true);
+
+            // forced disk spills
+            for i in 0..2 {
+                repartitioner
+                    .insert_batch(build_batch((i * 100..i * 100 + 100).collect()))
+                    .await?;
+            }
+            repartitioner
+                .data
+                .lock()
+                .await
+                .add_batch(build_batch((200..300).collect()))?;
+            repartitioner.shuffle_write().await?;
+
+            let reader = ShuffleReader::try_new(
+                dir.join("shuffle.data").to_string_lossy().to_string(),
+                dir.join("shuffle.index"),
+                build_batch(vec![]).schema(),
+            )?;
+            let num_rows = (0..4)
+                .flat_map(|partition_id| reader.read_partition(partition_id).unwrap())
+                .map(|batch| batch.num_rows())
+                .sum::<usize>();
+            assert_eq!(num_rows, 300);
+            decode_spill_trace(&spill_trace.snapshot())
+        }
+
+        // 0%: everything goes to disk
+        let dir = tempfile::tempdir()?;
+        let records = write(dir.path(), 0.0).await?;
+        assert_eq!(records.len(), 3);
+        assert!(
+            records
+                .iter()
+                .all(|record| record.target == SpillTarget::Disk)
+        );
+        assert!(records.iter().all(|record| record.in_mem_size == 0));
+
+        // 100%: the remaining data stays in memory, only forced spills go to disk
+        let dir = tempfile::tempdir()?;
+        let records = write(dir.path(), 1.0).await?;
+        assert_eq!(records.len(), 3);
+        assert!(
+            records[0..2]
+                .iter()
+                .all(|record| record.target == SpillTarget::Disk)
+        );
+        assert_eq!(records[2].target, SpillTarget::Memory);
+        assert!(records[2].in_mem_size > 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_in_mem_spill_ratio_on_spill() -> Result<()> {
+        MemManager::init(100);
+
+        // returns spill trace records, number of in-mem spills and whether the
+        // in-mem spills are accounted as used memory
+        async fn spill(dir: &Path, ratio: f64) -> Result<(Vec<SpillTraceRecord>, usize, bool)> {
+            let spill_trace = Arc::new(SpillTrace::new_ring_buffer(16));
+            let repartitioner = Arc::new(
+                new_repartitioner(dir, 4)?
+                    .with_spill_trace(spill_trace.clone())
+                    .with_in_mem_spill_ratio(ratio),
+            );
+            MemManager::register_consumer(repartitioner.clone(), true);
+
+            // forced disk spill under memory pressure
+            repartitioner
+                .insert_batch(build_batch((0..100).collect()))
+                .await?;
+
+            // spill without memory pressure goes to memory, then follows the ratio
+            repartitioner
+                .data
+                .lock()
+                .await
+                .add_batch(build_batch((100..200).collect()))?;
+            repartitioner.spill().await?;
+            let num_in_mem_spills = repartitioner
+                .spills
+                .lock()
+                .await
+                .iter()
+                .filter(|spill| is_in_mem_spill(spill))
+                .count();
+            let mem_accounted = repartitioner.mem_used_percent() > 0.0;
+            repartitioner.shuffle_write().await?;
+
+            let reader = ShuffleReader::try_new(
+                dir.join("shuffle.data").to_string_lossy().to_string(),
+                dir.join("shuffle.index"),
+                build_batch(vec![]).schema(),
+            )?;
+            let num_rows = (0..4)
+                .flat_map(|partition_id| reader.read_partition(partition_id).unwrap())
+                .map(|batch| batch.num_rows())
+                .sum::<usize>();
+            assert_eq!(num_rows, 200);
+            let records = decode_spill_trace(&spill_trace.snapshot())?;
+            Ok((records, num_in_mem_spills, mem_accounted))
+        }
+
+        // 100%: the spilled data stays in memory
+        let dir = tempfile::tempdir()?;
+        let (records, num_in_mem_spills, mem_accounted) = spill(dir.path(), 1.0).await?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].target, SpillTarget::Disk);
+        assert_eq!(records[1].target, SpillTarget::Memory);
+        assert!(records[1].in_mem_size > 0);
+        assert_eq!(num_in_mem_spills, 1);
+        assert!(mem_accounted);
+
+        // 1%: the in-mem spill exceeds the ratio and is moved to disk
+        let dir = tempfile::tempdir()?;
+        let (records, num_in_mem_spills, mem_accounted) = spill(dir.path(), 0.01).await?;
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].target, SpillTarget::Disk);
+        assert_eq!(records[1].target, SpillTarget::Memory);
+        assert_eq!(records[2].target, SpillTarget::Disk);
+        assert_eq!(records[2].freed, records[1].in_mem_size);
+        assert_eq!(records[2].in_mem_size, 0);
+        assert_eq!(num_in_mem_spills, 0);
+        assert!(!mem_accounted);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_insert_batch() -> Result<()> {
+        MemManager::init(100);
+        let dir = tempfile::tempdir()?;
+        let repartitioner = Arc::new(new_repartitioner(dir.path(), 4)?);
+        MemManager::register_consumer(repartitioner.clone(), true);
+
+        // every insertion takes all grow permits and spills, concurrent insertions
+        // must neither deadlock nor lose rows
+        let grow_size = build_batch((0..100).collect()).get_batch_mem_size() * 2;
+        let handles = (0..16)
+            .map(|i| {
+                let repartitioner = repartitioner.clone();
+                tokio::spawn(async move {
+                    repartitioner
+                        .insert_batch(build_batch((i * 100..i * 100 + 100).collect()))
+                        .await
+                })
+            })
+            .collect::<Vec<_>>();
+        let inserted = tokio::time::timeout(std::time::Duration::from_secs(30), async {
+            for handle in handles {
+                handle.await.expect("insert task panicked")?;
+            }
+            Ok::<_, DataFusionError>(())
+        })
+        .await
+        .expect("concurrent insertions timed out");
+        inserted?;
+
+        // over-committed memory is bounded by the total memory, or by a single
+        // insertion larger than it
+        let peak_grow_in_flight = repartitioner.peak_grow_in_flight();
+        assert!(peak_grow_in_flight >= grow_size);
+        assert!(
+            peak_grow_in_flight <= grow_size.max(100),
+            "peak over-commit {peak_grow_in_flight} exceeds bound"
+        );
+        repartitioner.shuffle_write().await?;
+
+        let reader = ShuffleReader::try_new(
+            dir.path()
+                .join("shuffle.data")
+                .to_string_lossy()
+                .to_string(),
+            dir.path().join("shuffle.index"),
+            build_batch(vec![]).schema(),
+        )?;
+        let values = (0..4)
+            .flat_map(|partition_id| reader.read_partition(partition_id).unwrap())
+            .flat_map(|batch| {
+                as_primitive_array::<Int32Type>(batch.column(0))
+                    .values()
+                    .to_vec()
+            })
+            .sorted()
+            .collect::<Vec<_>>();
+        assert_eq!(values, (0..1600).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unsafe_row_output() -> Result<()> {
+        MemManager::init(100);
+        let dir = tempfile::tempdir()?;
+        let repartitioner =
+            Arc::new(new_repartitioner(dir.path(), 4)?.with_unsafe_row_output(true));
+        MemManager::register_consumer(repartitioner.clone(), true);
+
+        // forced disk spills and remaining data are merged
+        for i in 0..2 {
+            repartitioner
+                .insert_batch(build_batch((i * 100..i * 100 + 100).collect()))
+                .await?;
+        }
+        repartitioner
+            .data
+            .lock()
+            .await
+            .add_batch(build_batch((200..300).collect()))?;
+        repartitioner.shuffle_write().await?;
+
+        let data = std::fs::read(dir.path().join("shuffle.data"))?;
+        let offsets = read_index_offsets(dir.path().join("shuffle.index"))?;
+        let mut values = vec![];
+        for partition_id in 0..4 {
+            let mut stream =
+                &data[offsets[partition_id] as usize..offsets[partition_id + 1] as usize];
+            while !stream.is_empty() {
+                // one int field: 8-byte null bitset + 8-byte slot
+                let size = i32::from_be_bytes(stream[..4].try_into().unwrap());
+                assert_eq!(size, 16);
+                let row = &stream[4..20];
+                assert_eq!(&row[..8], &[0; 8]);
+                let value = i64::from_le_bytes(row[8..16].try_into().unwrap()) as i32;
+                let hash =
+                    create_murmur3_hashes(1, &[Arc::new(Int32Array::from(vec![value]))], 42)[0];
+                assert_eq!(hash.rem_euclid(4) as usize, partition_id);
+                values.push(value);
+                stream = &stream[20..];
+            }
+        }
+        values.sort();
+        assert_eq!(values, (0..300).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_zstd_seekable_partitions() -> Result<()> {
+        MemManager::init(100);
+        let dir = tempfile::tempdir()?;
+        let repartitioner =
+            Arc::new(new_repartitioner(dir.path(), 4)?.with_zstd_seekable_frame_size(256));
+        MemManager::register_consumer(repartitioner.clone(), true);
+
+        // spills every insertion, partitions are merged from several spills
+        for i in 0..4 {
+            repartitioner
+                .insert_batch(build_batch((i * 1000..i * 1000 + 1000).collect()))
+                .await?;
+        }
+        repartitioner.shuffle_write().await?;
+
+        let data = std::fs::read(dir.path().join("shuffle.data"))?;
+        let offsets = read_index_offsets(dir.path().join("shuffle.index"))?;
+        let partition = &data[offsets[1] as usize..offsets[2] as usize];
+        let decompressed = zstd::stream::decode_all(partition)?;
+
+        // locate frames with the seek table at the end of the partition, following the
+        // zstd seekable format spec
+        let footer = &partition[partition.len() - 9..];
+        assert_eq!(&footer[5..9], &0x8F92EAB1u32.to_le_bytes());
+        let num_frames = u32::from_le_bytes(footer[0..4].try_into().unwrap()) as usize;
+        let seek_table_start = partition.len() - 9 - num_frames * 8;
+        let frames = partition[seek_table_start..partition.len() - 9]
+            .chunks(8)
+            .map(|entry| {
+                let compressed_size = u32::from_le_bytes(entry[0..4].try_into().unwrap());
+                let decompressed_size = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+                (compressed_size as usize, decompressed_size as usize)
+            })
+            .collect::<Vec<_>>();
+        assert!(num_frames > 2);
+
+        // decode only the frame holding the middle of the partition
+        let middle = decompressed.len() / 2;
+        let (mut compressed_offset, mut decompressed_offset) = (0, 0);
+        for &(compressed_size, decompressed_size) in &frames {
+            if decompressed_offset + decompressed_size > middle {
+                let frame = &partition[compressed_offset..][..compressed_size];
+                assert_eq!(
+                    zstd::stream::decode_all(frame)?,
+                    &decompressed[decompressed_offset..][..decompressed_size]
+                );
+                break;
+            }
+            compressed_offset += compressed_size;
+            decompressed_offset += decompressed_size;
+        }
+        assert!(decompressed_offset > 0);
+
+        // the shuffle reader reads seekable partitions
+        let reader = ShuffleReader::try_new(
+            dir.path()
+                .join("shuffle.data")
+                .to_string_lossy()
+                .to_string(),
+            dir.path().join("shuffle.index"),
+            build_batch(vec![]).schema(),
+        )?;
+        let values = (0..4)
+            .flat_map(|partition_id| reader.read_partition(partition_id).unwrap())
+            .flat_map(|batch| {
+                as_primitive_array::<Int32Type>(batch.column(0))
+                    .values()
+                    .to_vec()
+            })
+            .sorted()
+            .collect::<Vec<_>>();
+        assert_eq!(values, (0..4000).collect::<Vec<_>>());
+        Ok(())
+    }
+}
@@ -0,0 +1,288 @@
+// Licensed to the Apache Software Foundation (ASF) under one or more
+// contributor license agreements.  See the NOTICE file distributed with
+// this work for additional information regarding copyright ownership.
+// The ASF licenses this file to You under the Apache License, Version 2.0
+// (the "License"); you may not use this file except in compliance with
+// the License.  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compact binary trace of spill decisions made by shuffle repartitioners.
+//!
+//! The trace starts with a 5-byte header (magic `BSPT` + format version),
+//! followed by fixed-size little-endian records:
+//!
+//! | field          | type |
+//! |----------------|------|
+//! | timestamp (us) | u64  |
+//! | mem_used       | u64  |
+//! | in_mem_size    | u64  |
+//! | buffered_size  | u64  |
+//! | target         | u8   |
+//! | freed          | u64  |
+//!
+//! Use [`decode_spill_trace`] to turn a trace back into records, or run the
+//! `decode_spill_trace` example to print records of a trace file.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use datafusion::common::Result;
+use datafusion_ext_commons::df_execution_err;
+use parking_lot::Mutex;
+
+const SPILL_TRACE_MAGIC: &[u8; 4] = b"BSPT";
+const SPILL_TRACE_VERSION: u8 = 1;
+const SPILL_TRACE_RECORD_SIZE: usize = 41;
+const SPILL_TRACE_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpillTarget {
+    Memory = 0,
+    Disk = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpillTraceRecord {
+    pub timestamp_us: u64,
+    pub mem_used: u64,
+    pub in_mem_size: u64,
+    pub buffered_size: u64,
+    pub target: SpillTarget,
+    pub freed: u64,
+}
+
+impl SpillTraceRecord {
+    pub fn new(
+        mem_used: usize,
+        in_mem_size: usize,
+        buffered_size: usize,
+        target: SpillTarget,
+        freed: usize,
+    ) -> Self {
+        let timestamp_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+        Self {
+            timestamp_us,
+            mem_used: mem_used as u64,
+            in_mem_size: in_mem_size as u64,
+            buffered_size: buffered_size as u64,
+            target,
+            freed: freed as u64,
+        }
+    }
+
+    fn encode(&self) -> [u8; SPILL_TRACE_RECORD_SIZE] {
+        let mut buf = [0u8; SPILL_TRACE_RECORD_SIZE];
+        buf[0..8].copy_from_slice(&self.timestamp_us.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.mem_used.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.in_mem_size.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.buffered_size.to_le_bytes());
+        buf[32] = self.target as u8;
+        buf[33..41].copy_from_slice(&self.freed.to_le_bytes());
+        buf
+    }
+
+    fn decode(mut r: &[u8]) -> Result<Self> {
+        let timestamp_us = r.read_u64::<LittleEndian>()?;
+        let mem_used = r.read_u64::<LittleEndian>()?;
+        let in_mem_size = r.read_u64::<LittleEndian>()?;
+        let buffered_size = r.read_u64::<LittleEndian>()?;
+        let target = match r.read_u8()? {
+            0 => SpillTarget::Memory,
+            1 => SpillTarget::Disk,
+            t => return df_execution_err!("invalid spill trace target: {t}"),
+        };
+        let freed = r.read_u64::<LittleEndian>()?;
+        Ok(Self {
+            timestamp_us,
+            mem_used,
+            in_mem_size,
+            buffered_size,
+            target,
+            freed,
+        })
+    }
+}
+
+impl fmt::Display for SpillTraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ts={}us target={:?} mem_used={} in_mem={} buffered={} freed={}",
+            self.timestamp_us,
+            self.target,
+            self.mem_used,
+            self.in_mem_size,
+            self.buffered_size,
+            self.freed,
+        )
+    }
+}
+
+enum SpillTraceSink {
+    RingBuffer {
+        capacity: usize,
+        records: VecDeque<[u8; SPILL_TRACE_RECORD_SIZE]>,
+    },
+    File {
+        writer: BufWriter<File>,
+        last_flush_time: Instant,
+    },
+}
+
+/// An opt-in sink for spill decisions, either kept in a bounded in-memory ring
+/// buffer (oldest records are dropped) or appended to a file. file traces are
+/// flushed at most once per second while recording, and on drop.
+pub struct SpillTrace {
+    sink: Mutex<SpillTraceSink>,
+}
+
+impl SpillTrace {
+    pub fn new_ring_buffer(capacity: usize) -> Self {
+        Self {
+            sink: Mutex::new(SpillTraceSink::RingBuffer {
+                capacity: capacity.max(1),
+                records: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn try_new_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(SPILL_TRACE_MAGIC)?;
+        writer.write_u8(SPILL_TRACE_VERSION)?;
+        Ok(Self {
+            sink: Mutex::new(SpillTraceSink::File {
+                writer,
+                last_flush_time: Instant::now(),
+            }),
+        })
+    }
+
+    pub fn record(&self, record: SpillTraceRecord) {
+        let encoded = record.encode();
+        match &mut *self.sink.lock() {
+            SpillTraceSink::RingBuffer { capacity, records } => {
+                if records.len() == *capacity {
+                    records.pop_front();
+                }
+                records.push_back(encoded);
+            }
+            SpillTraceSink::File {
+                writer,
+                last_flush_time,
+            } => {
+                let result = writer.write_all(&encoded).and_then(|_| {
+                    if last_flush_time.elapsed() >= SPILL_TRACE_FLUSH_INTERVAL {
+                        *last_flush_time = Instant::now();
+                        writer.flush()?;
+                    }
+                    Ok(())
+                });
+                if let Err(e) = result {
+                    log::warn!("error writing spill trace: {e}");
+                }
+            }
+        }
+    }
+
+    /// returns the encoded trace held in the ring buffer, or an empty buffer
+    /// for file traces
+    pub fn snapshot(&self) -> Vec<u8> {
+        match &*self.sink.lock() {
+            SpillTraceSink::RingBuffer { records, .. } => {
+                let mut buf = Vec::with_capacity(5 + records.len() * SPILL_TRACE_RECORD_SIZE);
+                buf.extend_from_slice(SPILL_TRACE_MAGIC);
+                buf.push(SPILL_TRACE_VERSION);
+                for record in records {
+                    buf.extend_from_slice(record);
+                }
+                buf
+            }
+            SpillTraceSink::File { .. } => vec![],
+        }
+    }
+}
+
+impl Drop for SpillTrace {
+    fn drop(&mut self) {
+        if let SpillTraceSink::File { writer, .. } = &mut *self.sink.lock()
+            && let Err(e) = writer.flush()
+        {
+            log::warn!("error flushing spill trace: {e}");
+        }
+    }
+}
+
+/// decodes a trace produced by [`SpillTrace`]
+pub fn decode_spill_trace(buf: &[u8]) -> Result<Vec<SpillTraceRecord>> {
+    if buf.len() < 5 || &buf[0..4] != SPILL_TRACE_MAGIC {
+        return df_execution_err!("invalid spill trace header");
+    }
+    if buf[4] != SPILL_TRACE_VERSION {
+        return df_execution_err!("unsupported spill trace version: {}", buf[4]);
+    }
+    let body = &buf[5..];
+    if body.len() % SPILL_TRACE_RECORD_SIZE != 0 {
+        return df_execution_err!("truncated spill trace: {} bytes", body.len());
+    }
+    body.chunks(SPILL_TRACE_RECORD_SIZE)
+        .map(SpillTraceRecord::decode)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_spill_trace_ring_buffer() -> Result<()> {
+        let trace = SpillTrace::new_ring_buffer(2);
+        trace.record(SpillTraceRecord::new(1, 2, 3, SpillTarget::Disk, 4));
+        trace.record(SpillTraceRecord::new(5, 6, 7, SpillTarget::Memory, 8));
+        trace.record(SpillTraceRecord::new(9, 10, 11, SpillTarget::Disk, 12));
+
+        let records = decode_spill_trace(&trace.snapshot())?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].mem_used, 5);
+        assert_eq!(records[0].target, SpillTarget::Memory);
+        assert_eq!(records[1].buffered_size, 11);
+        assert_eq!(records[1].freed, 12);
+        Ok(())
+    }
+
+    #[test]
+    fn test_spill_trace_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("spill.trace");
+        let trace = SpillTrace::try_new_file(&path)?;
+        trace.record(SpillTraceRecord::new(1, 0, 3, SpillTarget::Disk, 3));
+        trace.record(SpillTraceRecord::new(2, 0, 4, SpillTarget::Memory, 1));
+
+        // records are buffered until the trace is flushed
+        assert_eq!(std::fs::read(&path)?.len(), 0);
+        drop(trace);
+
+        let records = decode_spill_trace(&std::fs::read(&path)?)?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].target, SpillTarget::Disk);
+        assert_eq!(records[1].target, SpillTarget::Memory);
+        Ok(())
+    }
+}
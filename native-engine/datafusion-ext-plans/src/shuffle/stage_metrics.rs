@@ -0,0 +1,236 @@
+// Licensed to the Apache Software Foundation (ASF) under one or more
+// contributor license agreements.  See the NOTICE file distributed with
+// this work for additional information regarding copyright ownership.
+// The ASF licenses this file to You under the Apache License, Version 2.0
+// (the "License"); you may not use this file except in compliance with
+// the License.  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed},
+    },
+    time::Duration,
+};
+
+/// Spill metrics aggregated over a group of repartitioners, typically all
+/// map tasks of a stage. Repartitioners register into a shared handle at
+/// construction and their contributions are subtracted when they are dropped,
+/// so the handle always reflects the currently alive group.
+#[derive(Debug, Default)]
+pub struct ShuffleStageMetrics {
+    num_repartitioners: AtomicUsize,
+    spill_count: AtomicUsize,
+    spill_bytes: AtomicU64,
+    spill_time_ns: AtomicU64,
+}
+
+impl ShuffleStageMetrics {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// registers a repartitioner into this stage
+    pub fn register(self: &Arc<Self>) -> StageSpillContribution {
+        self.num_repartitioners.fetch_add(1, Relaxed);
+        StageSpillContribution {
+            stage: self.clone(),
+            spill_count: AtomicUsize::new(0),
+            spill_bytes: AtomicU64::new(0),
+            spill_time_ns: AtomicU64::new(0),
+        }
+    }
+
+    pub fn num_repartitioners(&self) -> usize {
+        self.num_repartitioners.load(Relaxed)
+    }
+
+    pub fn spill_count(&self) -> usize {
+        self.spill_count.load(Relaxed)
+    }
+
+    /// total size of spilled (serialized) data
+    pub fn spill_bytes(&self) -> u64 {
+        self.spill_bytes.load(Relaxed)
+    }
+
+    /// total time spent on writing spills
+    pub fn spill_time(&self) -> Duration {
+        Duration::from_nanos(self.spill_time_ns.load(Relaxed))
+    }
+}
+
+/// Spill metrics of a single repartitioner in a [`ShuffleStageMetrics`].
+#[derive(Debug)]
+pub struct StageSpillContribution {
+    stage: Arc<ShuffleStageMetrics>,
+    spill_count: AtomicUsize,
+    spill_bytes: AtomicU64,
+    spill_time_ns: AtomicU64,
+}
+
+impl StageSpillContribution {
+    pub fn record_spill(&self, spill_bytes: u64, spill_time: Duration) {
+        let spill_time_ns = spill_time.as_nanos() as u64;
+        self.spill_count.fetch_add(1, Relaxed);
+        self.spill_bytes.fetch_add(spill_bytes, Relaxed);
+        self.spill_time_ns.fetch_add(spill_time_ns, Relaxed);
+        self.stage.spill_count.fetch_add(1, Relaxed);
+        self.stage.spill_bytes.fetch_add(spill_bytes, Relaxed);
+        self.stage.spill_time_ns.fetch_add(spill_time_ns, Relaxed);
+    }
+
+    pub fn stage(&self) -> &Arc<ShuffleStageMetrics> {
+        &self.stage
+    }
+
+    pub fn spill_count(&self) -> usize {
+        self.spill_count.load(Relaxed)
+    }
+
+    pub fn spill_bytes(&self) -> u64 {
+        self.spill_bytes.load(Relaxed)
+    }
+
+    pub fn spill_time(&self) -> Duration {
+        Duration::from_nanos(self.spill_time_ns.load(Relaxed))
+    }
+}
+
+impl Drop for StageSpillContribution {
+    fn drop(&mut self) {
+        self.stage
+            .spill_count
+            .fetch_sub(self.spill_count.load(Relaxed), Relaxed);
+        self.stage
+            .spill_bytes
+            .fetch_sub(self.spill_bytes.load(Relaxed), Relaxed);
+        self.stage
+            .spill_time_ns
+            .fetch_sub(self.spill_time_ns.load(Relaxed), Relaxed);
+        self.stage.num_repartitioners.fetch_sub(1, Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arrow::{
+        array::Int32Array,
+        datatypes::{DataType, Field, Schema},
+        record_batch::RecordBatch,
+    };
+    use datafusion::{
+        common::Result,
+        physical_expr::expressions::Column,
+        physical_plan::metrics::{ExecutionPlanMetricsSet, Time},
+        prelude::SessionContext,
+    };
+
+    use super::*;
+    use crate::{
+        common::execution_context::ExecutionContext,
+        memmgr::MemManager,
+        shuffle::{
+            Partitioning, ShuffleRepartitioner, sort_repartitioner::SortShuffleRepartitioner,
+        },
+    };
+
+    #[tokio::test]
+    async fn test_stage_metrics() -> Result<()> {
+        MemManager::init(100);
+        let dir = tempfile::tempdir()?;
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let stage_metrics = ShuffleStageMetrics::new();
+
+        // three map tasks of a stage, every inserted batch is spilled
+        let mut repartitioners = vec![];
+        for task_id in 0..3 {
+            let exec_ctx = ExecutionContext::new(
+                SessionContext::new().task_ctx(),
+                task_id,
+                schema.clone(),
+                &ExecutionPlanMetricsSet::new(),
+            );
+            let file = |ext: &str| {
+                dir.path()
+                    .join(format!("shuffle_{task_id}.{ext}"))
+                    .to_string_lossy()
+                    .to_string()
+            };
+            let repartitioner = Arc::new(
+                SortShuffleRepartitioner::try_new(
+                    exec_ctx,
+                    file("data"),
+                    file("index"),
+                    Partitioning::HashPartitioning(vec![Arc::new(Column::new("a", 0))], 4),
+                    Time::new(),
+                )?
+                .with_stage_metrics(&stage_metrics),
+            );
+            MemManager::register_consumer(repartitioner.clone(), true);
+
+            for i in 0..=task_id as i32 {
+                let values = (i * 1000..(i + 1) * 1000).collect::<Vec<_>>();
+                let batch =
+                    RecordBatch::try_new(schema.clone(), vec![Arc::new(Int32Array::from(values))])?;
+                repartitioner.insert_batch(batch).await?;
+            }
+            repartitioners.push(repartitioner);
+        }
+        assert_eq!(stage_metrics.num_repartitioners(), 3);
+
+        let contributions = repartitioners
+            .iter()
+            .map(|r| r.stage_spill_contribution().expect("stage metrics not set"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            contributions
+                .iter()
+                .map(|c| c.spill_count())
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert!(contributions.iter().all(|c| c.spill_bytes() > 0));
+        assert_eq!(stage_metrics.spill_count(), 6);
+        assert_eq!(
+            stage_metrics.spill_bytes(),
+            contributions.iter().map(|c| c.spill_bytes()).sum::<u64>()
+        );
+        assert_eq!(
+            stage_metrics.spill_time(),
+            contributions
+                .iter()
+                .map(|c| c.spill_time())
+                .sum::<Duration>()
+        );
+
+        // dropped repartitioners are removed from the stage
+        let last_spill_bytes = contributions[2].spill_bytes();
+        let total_spill_bytes = stage_metrics.spill_bytes();
+        drop(contributions);
+        drop(repartitioners.pop());
+        assert_eq!(stage_metrics.num_repartitioners(), 2);
+        assert_eq!(stage_metrics.spill_count(), 3);
+        assert_eq!(
+            stage_metrics.spill_bytes(),
+            total_spill_bytes - last_spill_bytes
+        );
+
+        drop(repartitioners);
+        assert_eq!(stage_metrics.num_repartitioners(), 0);
+        assert_eq!(stage_metrics.spill_count(), 0);
+        assert_eq!(stage_metrics.spill_bytes(), 0);
+        assert_eq!(stage_metrics.spill_time(), Duration::ZERO);
+        Ok(())
+    }
+}
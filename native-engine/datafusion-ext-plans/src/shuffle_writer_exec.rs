@@ -120,19 +120,19 @@ impl ExecutionPlan for ShuffleWriterExec {
         let mut input = self.input.clone();
 
         let repartitioner: Arc<dyn ShuffleRepartitioner> = match &self.partitioning {
-            p if p.partition_count() == 1 => Arc::new(SingleShuffleRepartitioner::new(
+            p if p.partition_count() == 1 => Arc::new(SingleShuffleRepartitioner::try_new(
                 self.output_data_file.clone(),
                 self.output_index_file.clone(),
                 output_time,
-            )),
+            )?),
             Partitioning::HashPartitioning(..) | Partitioning::RangePartitioning(..) => {
-                let partitioner = Arc::new(SortShuffleRepartitioner::new(
+                let partitioner = Arc::new(SortShuffleRepartitioner::try_new(
                     exec_ctx.clone(),
                     self.output_data_file.clone(),
                     self.output_index_file.clone(),
                     self.partitioning.clone(),
                     output_time,
-                ));
+                )?);
                 MemManager::register_consumer(partitioner.clone(), true);
                 partitioner
             }
@@ -152,13 +152,13 @@ impl ExecutionPlan for ShuffleWriterExec {
                     None,
                     false, // do not record output metric
                 );
-                let partitioner = Arc::new(SortShuffleRepartitioner::new(
+                let partitioner = Arc::new(SortShuffleRepartitioner::try_new(
                     exec_ctx.clone(),
                     self.output_data_file.clone(),
                     self.output_index_file.clone(),
                     self.partitioning.clone(),
                     output_time,
-                ));
+                )?);
                 MemManager::register_consumer(partitioner.clone(), true);
                 partitioner
             }
@@ -0,0 +1,62 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one or more
+ * contributor license agreements.  See the NOTICE file distributed with
+ * this work for additional information regarding copyright ownership.
+ * The ASF licenses this file to You under the Apache License, Version 2.0
+ * (the "License"); you may not use this file except in compliance with
+ * the License.  You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+package org.apache.spark.sql.auron
+
+import java.nio.ByteBuffer
+import java.nio.charset.StandardCharsets
+import java.nio.file.Files
+
+import org.apache.hadoop.conf.Configuration
+import org.apache.hadoop.fs.FSDataOutputStreamBuilder
+import org.apache.hadoop.fs.Path
+import org.apache.hadoop.fs.RawLocalFileSystem
+import org.apache.spark.SparkConf
+
+class AuronFileSystemSuite extends org.apache.spark.sql.QueryTest with BaseAuronSQLSuite {
+
+  override protected def sparkConf: SparkConf = {
+    super.sparkConf
+      .set(AuronConf.FS_CREATE_CONTENT_TYPE.key, "application/x-blaze-shuffle")
+  }
+
+  test("created files carry the configured content-type") {
+    withTempDir { dir =>
+      val fs = new CreateFileRecordingFileSystem
+      fs.initialize(dir.toURI, new Configuration())
+      val path = new Path(dir.toURI.toString, "part-0.parquet")
+
+      val output = JniBridge.createFileAsDataOutputWrapper(fs, path.toUri.toString)
+      output.writeFully(ByteBuffer.wrap("data".getBytes(StandardCharsets.UTF_8)))
+      output.close()
+
+      assert(
+        fs.lastCreateFile.getOptions.get(JniBridge.CONTENT_TYPE_CREATE_OPTION) ==
+          "application/x-blaze-shuffle")
+      val written = Files.readAllBytes(dir.toPath.resolve("part-0.parquet"))
+      assert(new String(written, StandardCharsets.UTF_8) == "data")
+    }
+  }
+}
+
+class CreateFileRecordingFileSystem extends RawLocalFileSystem {
+  var lastCreateFile: FSDataOutputStreamBuilder[_, _] = _
+
+  override def createFile(path: Path): FSDataOutputStreamBuilder[_, _] = {
+    lastCreateFile = super.createFile(path)
+    lastCreateFile
+  }
+}
@@ -0,0 +1,93 @@
+/*
+ * Licensed to the Apache Software Foundation (ASF) under one or more
+ * contributor license agreements.  See the NOTICE file distributed with
+ * this work for additional information regarding copyright ownership.
+ * The ASF licenses this file to You under the Apache License, Version 2.0
+ * (the "License"); you may not use this file except in compliance with
+ * the License.  You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+package org.apache.spark.sql.auron
+
+import java.io.ByteArrayOutputStream
+
+import org.apache.spark.SparkFunSuite
+import org.apache.spark.sql.catalyst.InternalRow
+import org.apache.spark.sql.catalyst.expressions.UnsafeProjection
+import org.apache.spark.sql.execution.UnsafeRowSerializer
+import org.apache.spark.sql.types._
+import org.apache.spark.unsafe.types.UTF8String
+
+class AuronUnsafeRowSuite extends SparkFunSuite {
+
+  // must be kept in sync with SPARK_UNSAFE_ROWS_HEX in
+  // native-engine/datafusion-ext-commons/src/io/unsafe_row.rs
+  private val nativeUnsafeRowsHex = Seq(
+    "0000007000000000000000000100000000000000feffffffffffffff05000000",
+    "480000000100000000000000000000000000f83f393000000000000001000000",
+    "500000000a0000006000000068656c6c6f000000ff0000000000000000000000",
+    "000000003031323334353637383900000000000000000058c100000000000000",
+    "0000000000000000000000000001000000000000480000000000000000000000",
+    "000000000000d0bfffffffffffffffff00000000480000000000000000000000",
+    "00000000000000000000000000000000000000602c00000000000000f9ffffff",
+    "000000000000000000000000000000000000000000000000000000007dc39425",
+    "ad49b25400000000000000000a00000048000000020000005800000003e7ffff",
+    "fffffffffc180000000000006162000000000000").mkString
+
+  test("native UnsafeRow layout matches UnsafeProjection") {
+    val schema = StructType(
+      Seq(
+        StructField("c0", IntegerType),
+        StructField("c1", LongType),
+        StructField("c2", StringType),
+        StructField("c3", BooleanType),
+        StructField("c4", DoubleType),
+        StructField("c5", DecimalType(10, 2)),
+        StructField("c6", DecimalType(38, 2)),
+        StructField("c7", BinaryType)))
+    val rows = Seq(
+      InternalRow(
+        1,
+        -2L,
+        UTF8String.fromString("hello"),
+        true,
+        1.5,
+        Decimal(12345L, 10, 2),
+        Decimal(-1L, 38, 2),
+        "0123456789".getBytes),
+      InternalRow(
+        null,
+        1L << 40,
+        UTF8String.fromString(""),
+        false,
+        -0.25,
+        Decimal(-1L, 10, 2),
+        null,
+        null),
+      InternalRow(
+        -7,
+        0L,
+        null,
+        null,
+        1e100,
+        null,
+        Decimal(BigDecimal(BigInt("18446744073709551615000"), 2), 38, 2),
+        "ab".getBytes))
+
+    val projection = UnsafeProjection.create(schema)
+    val output = new ByteArrayOutputStream()
+    val stream = new UnsafeRowSerializer(schema.length).newInstance().serializeStream(output)
+    rows.foreach(row => stream.writeValue(projection(row)))
+    stream.close()
+
+    val hex = output.toByteArray.map(b => f"${b & 0xff}%02x").mkString
+    assert(hex == nativeUnsafeRowsHex)
+  }
+}
@@ -97,9 +97,126 @@ public enum AuronConf {
     // shuffle compression target buffer size, default is 4MB
     SHUFFLE_COMPRESSION_TARGET_BUF_SIZE("spark.auron.shuffle.compression.targetBufSize", 4194304),
 
+    // max size of a single in-memory shuffle spill, larger spills are split into multiple ones
+    // to avoid huge contiguous allocations. 0 for unlimited
+    SHUFFLE_MAX_IN_MEM_SPILL_SIZE("spark.auron.shuffle.maxInMemSpillSize", 0),
+
+    // use vectorized murmur3 hashing for fixed-width keys when evaluating shuffle partition ids,
+    // the partition ids are identical to the row-by-row implementation. disabled by default until
+    // the speedup is confirmed with the hashing benchmark on the target hardware
+    SHUFFLE_VECTORIZED_HASHING_ENABLE("spark.auron.shuffle.vectorizedHashing.enable", false),
+
+    // log one in every N key-to-partition assignments at debug level for troubleshooting
+    // shuffle routing. 0 to disable
+    SHUFFLE_PARTITION_LOG_SAMPLE_INTERVAL("spark.auron.shuffle.partitionLogSampleInterval", 0),
+
+    // fail shuffle writing if the output data/index files already exist instead of truncating
+    // them, useful for detecting duplicate writers of the same map output
+    SHUFFLE_OUTPUT_EXCLUSIVE_CREATE("spark.auron.shuffle.output.exclusiveCreate", false),
+
+    // when the ratio of rows with all-null hash partitioning keys in a batch reaches this value,
+    // distribute those rows in a round-robin manner to avoid extreme skew. NOTE: this deviates
+    // from spark, which always routes null keys to the same partition. 0 to disable
+    SHUFFLE_NULL_KEY_FALLBACK_RATIO("spark.auron.shuffle.nullKeyFallback.ratio", 0.0),
+
+    // write a sidecar index of range bounds next to the shuffle index file for range partitioned
+    // shuffles, so that consumers can map keys to partitions without scanning
+    SHUFFLE_RANGE_BOUNDS_INDEX_ENABLE("spark.auron.shuffle.rangeBoundsIndex.enable", false),
+
+    // format of serialized batches in shuffle/broadcast data, supported values:
+    // native (default), arrow_ipc_v4 and arrow_ipc_v5 (arrow IPC record batch messages with the
+    // corresponding IPC metadata version, still wrapped in auron's compressed block framing)
+    SHUFFLE_BATCH_FORMAT("spark.auron.shuffle.batchFormat", "native"),
+
+    // partitions not smaller than this size report their exact sizes in map status, others
+    // report the average size. should be consistent with spark.shuffle.accurateBlockThreshold
+    SHUFFLE_ACCURATE_BLOCK_THRESHOLD("spark.auron.shuffle.accurateBlockThreshold", 100L * 1024 * 1024),
+
+    // report averaged sizes in map status only when the number of partitions exceeds this value.
+    // should be consistent with spark.shuffle.minNumPartitionsToHighlyCompress
+    SHUFFLE_MIN_PARTITIONS_TO_HIGHLY_COMPRESS("spark.auron.shuffle.minNumPartitionsToHighlyCompress", 2000),
+
+    // coalesce consecutive shuffle input batches with fewer rows than this value into one buffered
+    // batch, reducing per-batch overhead with tiny upstream batches. 0 to disable
+    SHUFFLE_TINY_BATCH_COALESCE_ROWS("spark.auron.shuffle.tinyBatchCoalesceRows", 0),
+
+    // coalesce shuffle output partitions into fewer physical partitions at map side when the total
+    // output size is below this value, writing a mapping of logical to physical partitions next to
+    // the index file. only for consumers aware of the mapping. 0 to disable
+    SHUFFLE_ADAPTIVE_COALESCE_MAX_SIZE("spark.auron.shuffle.adaptiveCoalesce.maxSize", 0L),
+
+    // target number of physical partitions of map-side adaptive coalescing
+    SHUFFLE_ADAPTIVE_COALESCE_TARGET_PARTITIONS("spark.auron.shuffle.adaptiveCoalesce.targetPartitions", 1),
+
+    // split each large shuffle partition into at least this number of compressed frames, so that
+    // readers can decode them in parallel. 1 to disable
+    SHUFFLE_MIN_PARTITION_FRAMES("spark.auron.shuffle.minPartitionFrames", 1),
+
+    // partitions with fewer rows than minPartitionFrames * minFrameRows are not split
+    SHUFFLE_MIN_FRAME_ROWS("spark.auron.shuffle.minFrameRows", 10000),
+
+    // order in which per-column hashes of multi-column shuffle keys are combined:
+    // left_to_right (spark), right_to_left
+    SHUFFLE_HASH_COMBINE_ORDER("spark.auron.shuffle.hashCombine.order", "left_to_right"),
+
+    // method used to combine per-column hashes of multi-column shuffle keys:
+    // seed_chaining (spark), multiply31
+    SHUFFLE_HASH_COMBINE_METHOD("spark.auron.shuffle.hashCombine.method", "seed_chaining"),
+
+    // within each partition, write bytes of file spills before in-memory spills in the final merge,
+    // reduces disk seeks on HDD spill devices
+    SHUFFLE_MERGE_FILE_SPILLS_FIRST("spark.auron.shuffle.merge.fileSpillsFirst", false),
+
+    // write shuffle index in checksummed (crc32c) blocks of this number of offsets, only readable by
+    // native shuffle readers. 0 to write spark's plain index
+    SHUFFLE_INDEX_CHECKSUM_BLOCK_SIZE("spark.auron.shuffle.index.checksumBlockSize", 0),
+
+    // max retries of shuffle output io calls interrupted by signals (EINTR). 0 to disable retrying
+    SHUFFLE_MAX_INTERRUPT_RETRIES("spark.auron.shuffle.maxInterruptRetries", 100),
+
+    // max ratio of spilled shuffle data kept in memory, in-memory spills exceeding the ratio are moved
+    // to disk. negative to keep the default heuristic (in-memory spills when memory usage is low)
+    SHUFFLE_IN_MEM_SPILL_RATIO("spark.auron.shuffle.inMemSpillRatio", -1.0),
+
+    // write shuffle output as uncompressed UnsafeRowSerializer streams instead of arrow frames, so that
+    // pure JVM reducers can read it directly. requires spark.shuffle.compress=false on reducers
+    SHUFFLE_UNSAFE_ROW_OUTPUT("spark.auron.shuffle.unsafeRowOutput", false),
+
+    // side to which rows equal to a range partitioning boundary are assigned:
+    // lower (spark), upper
+    SHUFFLE_RANGE_BOUNDARY_TIE_BREAK("spark.auron.shuffle.range.boundaryTieBreak", "lower"),
+
+    // when spark.io.compression.codec=zstd, write each shuffle output partition as a single zstd seekable
+    // stream with frames of this number of uncompressed bytes, so that readers can decompress arbitrary
+    // offsets. still readable by ordinary zstd decoders. costs another pass over the output data file.
+    // non-positive to disable
+    SHUFFLE_ZSTD_SEEKABLE_FRAME_SIZE("spark.auron.shuffle.zstd.seekableFrameSize", -1),
+
+    // track per-column in-memory sizes during shuffle writing, to estimate the serialized size of each
+    // column in the output data file
+    SHUFFLE_COLUMN_SIZES_ENABLE("spark.auron.shuffle.columnSizes.enable", false),
+
+    // verify offsets of in-memory shuffle spills against their data, for debugging serialization issues
+    SHUFFLE_VERIFY_IN_MEM_SPILL_OFFSETS("spark.auron.shuffle.verifyInMemSpillOffsets", false),
+
+    // extension of per-partition shuffle output file names, e.g. ".blaze-shuffle", for tooling and lifecycle policies
+    SHUFFLE_OUTPUT_FILE_EXTENSION("spark.auron.shuffle.outputFileExtension", ""),
+
+    // directory of binary traces of spill decisions made by sort shuffle writers, one file per map task named
+    // after its shuffle data file. for post-mortem tuning of spilling. empty to disable
+    SHUFFLE_SPILL_TRACE_DIR("spark.auron.shuffle.spillTrace.dir", ""),
+
     // spark spill compression codec
     SPILL_COMPRESSION_CODEC("spark.auron.spill.compression.codec", "lz4"),
 
+    // extension of spill file names, e.g. ".blaze-spill", for tooling and lifecycle policies
+    SPILL_FILE_EXTENSION("spark.auron.spill.fileExtension", ""),
+
+    // content-type of objects created by native writers through hadoop filesystems, e.g.
+    // "application/x-blaze-shuffle". set with the optional create option "fs.s3a.create.header.Content-Type",
+    // which is ignored by filesystems not supporting it. empty to disable
+    FS_CREATE_CONTENT_TYPE("spark.auron.fs.create.contentType", ""),
+
     // enable hash join falling back to sort merge join when hash table is too big
     SMJ_FALLBACK_ENABLE("spark.auron.smjfallback.enable", false),
 
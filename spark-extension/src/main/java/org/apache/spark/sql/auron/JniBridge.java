@@ -21,6 +21,7 @@ import java.lang.management.ManagementFactory;
 import java.net.URI;
 import java.util.List;
 import java.util.concurrent.ConcurrentHashMap;
+import org.apache.hadoop.fs.FSDataOutputStreamBuilder;
 import org.apache.hadoop.fs.FileSystem;
 import org.apache.hadoop.fs.Path;
 import org.apache.spark.SparkEnv;
@@ -37,6 +38,8 @@ import org.apache.spark.sql.auron.memory.OnHeapSpillManager$;
 public class JniBridge {
     public static final ConcurrentHashMap<String, Object> resourcesMap = new ConcurrentHashMap<>();
 
+    public static final String CONTENT_TYPE_CREATE_OPTION = "fs.s3a.create.header.Content-Type";
+
     public static native long callNative(long initNativeMemory, String logLevel, AuronCallNativeWrapper wrapper);
 
     public static native boolean nextBatch(long ptr);
@@ -93,7 +96,17 @@ public class JniBridge {
     }
 
     public static FSDataOutputWrapper createFileAsDataOutputWrapper(FileSystem fs, String path) throws Exception {
-        return FSDataOutputWrapper$.MODULE$.wrap(fs.create(new Path(new URI(path))));
+        Path hadoopPath = new Path(new URI(path));
+        String contentType = AuronConf.FS_CREATE_CONTENT_TYPE.stringConf();
+        if (contentType.isEmpty()) {
+            return FSDataOutputWrapper$.MODULE$.wrap(fs.create(hadoopPath));
+        }
+
+        // same semantics as fs.create(), with the content-type passed as an optional option
+        FSDataOutputStreamBuilder<?, ?> builder =
+                fs.createFile(hadoopPath).overwrite(true).recursive();
+        builder.opt(CONTENT_TYPE_CREATE_OPTION, contentType);
+        return FSDataOutputWrapper$.MODULE$.wrap(builder.build());
     }
 
     private static final List<BufferPoolMXBean> directMXBeans =